@@ -50,4 +50,23 @@ class SmartForwardNative {
      * @return 0 成功，1 需要重启，其他值失败
      */
     external fun updateConfig(configJson: String): Int
+    
+    /**
+     * 切换目标运维状态（通过独立运行的 smart-forward 服务进程的控制接口）
+     *
+     * 只作用于配置了 control 的服务进程，startProxy 启动的进程内代理没有目标状态，不受影响。
+     * 调用会阻塞，连接和读写各有5秒超时，请勿在主线程调用。
+     * @param controlAddr 控制接口地址，例如 "127.0.0.1:7070" 或 "unix:/path/ctl.sock"
+     * @param target 目标地址，与配置中的 targets 一致
+     * @param state "enabled"、"drain" 或 "disabled"
+     * @return 0 成功，-2 无效状态，-3 无法连接控制接口，-4 命令被拒绝，其他值失败
+     */
+    external fun setTargetState(controlAddr: String, target: String, state: String): Int
+    
+    /**
+     * 获取各目标的运维状态和健康状态（同样来自独立服务进程的控制接口，阻塞调用）
+     * @param controlAddr 控制接口地址
+     * @return 每行一个目标："地址 状态 healthy|unhealthy"，连接失败时为空字符串
+     */
+    external fun getTargetStates(controlAddr: String): String
 }
@@ -0,0 +1,534 @@
+// 负载均衡 - 按规则配置的策略为每个连接（UDP为每个会话）选择目标
+use crate::common::{gcd, TargetInfo};
+use crate::config::ForwardRule;
+use dashmap::DashMap;
+use log::info;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// 差值低于该值时视为测量误差，不切换最低延迟目标
+const MIN_LATENCY_GAIN: Duration = Duration::from_millis(1);
+
+// 一致性哈希环上每单位权重的虚拟节点数，以及归一化后的权重上限
+const VNODES_PER_WEIGHT: u32 = 100;
+const MAX_RING_WEIGHT: u64 = 100;
+
+// 加权选择时权重的放大倍数，慢启动从满权重的1%开始爬升
+const WEIGHT_SCALE: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Failover,           // 按配置顺序使用第一个健康目标（默认）
+    RoundRobin,         // 轮询
+    WeightedRoundRobin, // 平滑加权轮询
+    LeastConnections,   // 活跃连接数最少（按权重折算）
+    Random,             // 随机
+    LowestLatency,      // 健康检查延迟最低
+    SourceHash,         // 按客户端IP哈希，同一客户端固定到同一目标
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 7] = [
+        Strategy::Failover,
+        Strategy::RoundRobin,
+        Strategy::WeightedRoundRobin,
+        Strategy::LeastConnections,
+        Strategy::Random,
+        Strategy::LowestLatency,
+        Strategy::SourceHash,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strategy::Failover => "failover",
+            Strategy::RoundRobin => "round_robin",
+            Strategy::WeightedRoundRobin => "weighted_round_robin",
+            Strategy::LeastConnections => "least_connections",
+            Strategy::Random => "random",
+            Strategy::LowestLatency => "lowest_latency",
+            Strategy::SourceHash => "source_hash",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKey {
+    Ip,     // 客户端IP（默认）
+    IpPort, // 客户端IP+端口
+    Sni,    // TLS SNI 或 HTTP Host，取不到时退回客户端IP
+}
+
+impl HashKey {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ip" => Some(HashKey::Ip),
+            "ip_port" => Some(HashKey::IpPort),
+            "sni" => Some(HashKey::Sni),
+            _ => None,
+        }
+    }
+}
+
+fn hash_of<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// 一致性哈希环：目标的虚拟节点位置只取决于目标自身，目标增减时只有它负责的客户端会迁移
+#[derive(Default)]
+struct HashRing {
+    members: Vec<(String, u32)>,
+    points: Vec<(u64, usize)>, // (哈希位置, 候选目标下标)
+}
+
+impl HashRing {
+    fn update(&mut self, candidates: &[TargetInfo]) {
+        let members: Vec<_> = candidates
+            .iter()
+            .map(|t| (t.original.clone(), t.weight.max(1)))
+            .collect();
+        if members == self.members {
+            return;
+        }
+
+        self.points = members
+            .iter()
+            .zip(ring_weights(&members))
+            .enumerate()
+            .flat_map(|(index, ((name, _), weight))| {
+                (0..weight.saturating_mul(VNODES_PER_WEIGHT))
+                    .map(move |vnode| (hash_of((name, vnode)), index))
+            })
+            .collect();
+        self.points.sort_unstable();
+        self.members = members;
+    }
+
+    fn lookup(&self, hash: u64) -> usize {
+        let pos = self.points.partition_point(|(point, _)| *point < hash);
+        self.points
+            .get(pos)
+            .or_else(|| self.points.first())
+            .map(|(_, index)| *index)
+            .unwrap_or(0)
+    }
+}
+
+// 哈希环上的权重：先除以最大公约数，再按比例缩放到不超过 MAX_RING_WEIGHT，
+// 避免SRV记录的大权重（最大65535）生成过多虚拟节点
+fn ring_weights(members: &[(String, u32)]) -> Vec<u32> {
+    let divisor = members
+        .iter()
+        .fold(0, |acc, (_, weight)| gcd(acc, *weight as u64))
+        .max(1);
+    let max = members
+        .iter()
+        .map(|(_, weight)| *weight as u64 / divisor)
+        .max()
+        .unwrap_or(1);
+    members
+        .iter()
+        .map(|(_, weight)| {
+            let weight = *weight as u64 / divisor;
+            let weight = if max > MAX_RING_WEIGHT {
+                weight * MAX_RING_WEIGHT / max
+            } else {
+                weight
+            };
+            weight.max(1) as u32
+        })
+        .collect()
+}
+
+/// 连接结束（guard 释放）时自动减少目标的活跃连接数
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Balancer {
+    name: String,
+    strategy: Strategy,
+    latency_margin: u32, // 百分比：新目标需比当前目标快这么多才切换
+    hash_key: HashKey,
+    preferred: Mutex<Option<String>>, // 最低延迟策略当前使用的目标
+    next: AtomicUsize,
+    ring: Mutex<HashRing>,
+    current_weights: Mutex<HashMap<String, i64>>, // 平滑加权轮询的当前权重
+    active: DashMap<String, Arc<AtomicUsize>>,    // 目标 -> 活跃连接数
+    slow_start: Duration,                         // 目标恢复后权重线性爬升的时长
+}
+
+impl Balancer {
+    pub fn new(strategy: Strategy) -> Self {
+        Self {
+            name: String::new(),
+            strategy,
+            latency_margin: 20,
+            hash_key: HashKey::Ip,
+            preferred: Mutex::new(None),
+            next: AtomicUsize::new(0),
+            ring: Mutex::new(HashRing::default()),
+            current_weights: Mutex::new(HashMap::new()),
+            active: DashMap::new(),
+            slow_start: Duration::ZERO,
+        }
+    }
+
+    pub fn from_rule(rule: &ForwardRule) -> Self {
+        Self {
+            name: rule.name.clone(),
+            latency_margin: rule.get_latency_margin(),
+            hash_key: rule.get_hash_key(),
+            slow_start: Duration::from_secs(rule.get_slow_start()),
+            ..Self::new(rule.get_strategy())
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    fn counter(&self, target: &str) -> Arc<AtomicUsize> {
+        self.active
+            .entry(target.to_string())
+            .or_insert_with(|| Arc::new(AtomicUsize::new(0)))
+            .clone()
+    }
+
+    pub fn active_connections(&self, target: &str) -> usize {
+        self.active
+            .get(target)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// 慢启动进度（0~1），目标不在慢启动期间时返回 None
+    pub fn slow_start_progress(&self, target: &TargetInfo) -> Option<f64> {
+        let elapsed = target.recovered_at?.elapsed();
+        if self.slow_start.is_zero() || elapsed >= self.slow_start {
+            return None;
+        }
+        Some(elapsed.as_secs_f64() / self.slow_start.as_secs_f64())
+    }
+
+    // 放大后的有效权重，慢启动期间按恢复时长线性爬升
+    fn effective_weight(&self, target: &TargetInfo) -> u64 {
+        let full = target.weight.max(1) as u64 * WEIGHT_SCALE;
+        match self.slow_start_progress(target) {
+            Some(progress) => ((full as f64 * progress) as u64).max(1),
+            None => full,
+        }
+    }
+
+    /// 记录一个到目标的活跃连接
+    pub fn acquire(&self, target: &str) -> ConnectionGuard {
+        let counter = self.counter(target);
+        counter.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { counter }
+    }
+
+    /// 从候选目标（按配置顺序）中选择一个；failover 的粘性由调用方处理，这里取第一个
+    /// host 为连接的 SNI/Host，仅 source_hash 按 sni 哈希时使用
+    pub fn pick<'a>(
+        &self,
+        candidates: &'a [TargetInfo],
+        client: Option<SocketAddr>,
+        host: Option<&str>,
+    ) -> Option<&'a TargetInfo> {
+        if candidates.len() <= 1 {
+            return candidates.first();
+        }
+
+        let index = match self.strategy {
+            Strategy::Failover => 0,
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % candidates.len(),
+            Strategy::WeightedRoundRobin => self.pick_weighted(candidates),
+            Strategy::LeastConnections => self.pick_least_connections(candidates),
+            Strategy::Random => rand::thread_rng().gen_range(0..candidates.len()),
+            Strategy::LowestLatency => self.pick_lowest_latency(candidates),
+            Strategy::SourceHash => match self.affinity_hash(client, host) {
+                Some(hash) => {
+                    let mut ring = self.ring.lock().unwrap();
+                    ring.update(candidates);
+                    ring.lookup(hash)
+                }
+                None => 0,
+            },
+        };
+        candidates.get(index)
+    }
+
+    fn affinity_hash(&self, client: Option<SocketAddr>, host: Option<&str>) -> Option<u64> {
+        match (self.hash_key, host) {
+            (HashKey::Sni, Some(host)) => Some(hash_of(host.to_ascii_lowercase())),
+            (HashKey::IpPort, _) => client.map(|c| hash_of((c.ip().to_canonical(), c.port()))),
+            _ => client.map(|c| hash_of(c.ip().to_canonical())),
+        }
+    }
+
+    // 平滑加权轮询（nginx算法）：每轮各目标加上自身权重，选当前权重最大者并减去总权重
+    fn pick_weighted(&self, candidates: &[TargetInfo]) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        current.retain(|name, _| candidates.iter().any(|t| &t.original == name));
+
+        let weights: Vec<_> = candidates
+            .iter()
+            .map(|t| self.effective_weight(t) as i64)
+            .collect();
+        let total: i64 = weights.iter().sum();
+        let mut best = 0;
+        let mut best_weight = i64::MIN;
+        for (index, target) in candidates.iter().enumerate() {
+            let weight = current.entry(target.original.clone()).or_insert(0);
+            *weight += weights[index];
+            if *weight > best_weight {
+                best = index;
+                best_weight = *weight;
+            }
+        }
+        if let Some(weight) = current.get_mut(&candidates[best].original) {
+            *weight -= total;
+        }
+        best
+    }
+
+    // 比较 活跃连接数/权重，相同时按配置顺序
+    // 慢启动中的目标按多一个连接计算，避免空闲时以0连接抢走全部新连接
+    fn pick_least_connections(&self, candidates: &[TargetInfo]) -> usize {
+        let load = |target: &TargetInfo| {
+            let pending = self.slow_start_progress(target).is_some() as u64;
+            (
+                self.active_connections(&target.original) as u64 + pending,
+                self.effective_weight(target),
+            )
+        };
+        let mut best = 0;
+        for index in 1..candidates.len() {
+            let (active, weight) = load(&candidates[index]);
+            let (best_active, best_weight) = load(&candidates[best]);
+            if active * best_weight < best_active * weight {
+                best = index;
+            }
+        }
+        best
+    }
+
+    // 保持当前目标，除非最快的目标比它快出 latency_margin 以上，避免在相近目标间来回切换
+    fn pick_lowest_latency(&self, candidates: &[TargetInfo]) -> usize {
+        // 没有延迟数据的目标排在最后
+        let latency = |t: &TargetInfo| t.latency().unwrap_or(Duration::MAX);
+        let fastest = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, t)| latency(t))
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+
+        let mut preferred = self.preferred.lock().unwrap();
+        let current = preferred
+            .as_ref()
+            .and_then(|name| candidates.iter().position(|t| &t.original == name));
+
+        let chosen = match current {
+            Some(current) if current != fastest => {
+                let current_latency = latency(&candidates[current]);
+                let fastest_latency = latency(&candidates[fastest]);
+                let threshold = current_latency.mul_f64(1.0 - self.latency_margin as f64 / 100.0);
+                if fastest_latency < threshold
+                    && current_latency.saturating_sub(fastest_latency) >= MIN_LATENCY_GAIN
+                {
+                    info!(
+                        "规则 {} 最低延迟目标切换: {} ({:?}) -> {} ({:?})",
+                        self.name,
+                        candidates[current].original,
+                        current_latency,
+                        candidates[fastest].original,
+                        fastest_latency
+                    );
+                    fastest
+                } else {
+                    current
+                }
+            }
+            Some(current) => current,
+            None => fastest,
+        };
+        *preferred = Some(candidates[chosen].original.clone());
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TargetAddr;
+
+    fn target(name: &str, weight: u32) -> TargetInfo {
+        let mut target = TargetInfo::new(
+            name,
+            vec![TargetAddr::Inet(format!("{}:80", name).parse().unwrap())],
+        );
+        target.weight = weight;
+        target
+    }
+
+    #[test]
+    fn test_weighted_and_least_connections() {
+        let targets = vec![target("10.0.0.1", 3), target("10.0.0.2", 1)];
+
+        let balancer = Balancer::new(Strategy::WeightedRoundRobin);
+        let picks: Vec<_> = (0..4)
+            .map(|_| {
+                balancer
+                    .pick(&targets, None, None)
+                    .unwrap()
+                    .original
+                    .clone()
+            })
+            .collect();
+        assert_eq!(picks.iter().filter(|p| *p == "10.0.0.1").count(), 3);
+        assert_eq!(picks[2], "10.0.0.2"); // 平滑分布：a a b a，不会连续三次选中同一目标
+
+        let balancer = Balancer::new(Strategy::LeastConnections);
+        let _a = balancer.acquire("10.0.0.1");
+        let _b = balancer.acquire("10.0.0.1");
+        let guard = balancer.acquire("10.0.0.2");
+        // 10.0.0.1：2/3，10.0.0.2：1/1
+        assert_eq!(
+            balancer.pick(&targets, None, None).unwrap().original,
+            "10.0.0.1"
+        );
+        drop(guard);
+        assert_eq!(balancer.active_connections("10.0.0.2"), 0);
+        assert_eq!(
+            balancer.pick(&targets, None, None).unwrap().original,
+            "10.0.0.2"
+        );
+    }
+
+    #[test]
+    fn test_slow_start_ramps_weight() {
+        let mut targets = vec![target("10.0.0.1", 1), target("10.0.0.2", 1)];
+        targets[1].recovered_at = Some(std::time::Instant::now() - Duration::from_secs(10));
+
+        // 慢启动100秒、已恢复10秒：有效权重约为满权重的10%
+        let balancer = Balancer {
+            slow_start: Duration::from_secs(100),
+            ..Balancer::new(Strategy::WeightedRoundRobin)
+        };
+        let recovering = (0..110)
+            .filter(|_| balancer.pick(&targets, None, None).unwrap().original == "10.0.0.2")
+            .count();
+        assert!((9..=12).contains(&recovering));
+
+        // 两个目标都空闲时，慢启动中的目标不会以0连接抢走新连接
+        let balancer = Balancer {
+            slow_start: Duration::from_secs(100),
+            ..Balancer::new(Strategy::LeastConnections)
+        };
+        assert_eq!(
+            balancer.pick(&targets, None, None).unwrap().original,
+            "10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_source_hash_moves_only_affected_clients() {
+        let all: Vec<_> = (1..=4)
+            .map(|i| target(&format!("10.0.0.{}", i), 1))
+            .collect();
+        let balancer = Balancer::new(Strategy::SourceHash);
+        let clients: Vec<SocketAddr> = (0..200)
+            .map(|i| format!("198.51.100.{}:{}", i, 40000 + i).parse().unwrap())
+            .collect();
+        let assign = |targets: &[TargetInfo]| -> Vec<String> {
+            clients
+                .iter()
+                .map(|c| {
+                    balancer
+                        .pick(targets, Some(*c), None)
+                        .unwrap()
+                        .original
+                        .clone()
+                })
+                .collect()
+        };
+
+        let before = assign(&all);
+        let remaining: Vec<_> = all
+            .iter()
+            .filter(|t| t.original != "10.0.0.2")
+            .cloned()
+            .collect();
+        let after = assign(&remaining);
+
+        // 只有原本落在 10.0.0.2 的客户端迁移，恢复后回到原目标
+        assert!(before.iter().any(|t| t == "10.0.0.2"));
+        for (old, new) in before.iter().zip(&after) {
+            if old != "10.0.0.2" {
+                assert_eq!(old, new);
+            }
+        }
+        assert_eq!(assign(&all), before);
+    }
+
+    #[test]
+    fn test_lowest_latency_switch_margin() {
+        let mut targets = vec![target("10.0.0.1", 1), target("10.0.0.2", 1)];
+        targets[0].rtt = Some(Duration::from_millis(50));
+        targets[1].rtt = Some(Duration::from_millis(45));
+
+        let balancer = Balancer::new(Strategy::LowestLatency);
+        assert_eq!(
+            balancer.pick(&targets, None, None).unwrap().original,
+            "10.0.0.2"
+        );
+
+        // 10.0.0.1 只快了约10%，低于20%的切换阈值，保持当前目标
+        targets[0].rtt = Some(Duration::from_millis(40));
+        assert_eq!(
+            balancer.pick(&targets, None, None).unwrap().original,
+            "10.0.0.2"
+        );
+
+        targets[0].rtt = Some(Duration::from_millis(30));
+        assert_eq!(
+            balancer.pick(&targets, None, None).unwrap().original,
+            "10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_hash_ring_normalizes_large_weights() {
+        let mut ring = HashRing::default();
+        ring.update(&[target("10.0.0.1", 60000), target("10.0.0.2", 30000)]);
+        // 按最大公约数约简为 2:1
+        assert_eq!(ring.points.len(), 3 * VNODES_PER_WEIGHT as usize);
+
+        // SRV最大权重与最小权重相差悬殊时，缩放到上限且保留至少一份权重
+        ring.update(&[target("10.0.0.1", 65535), target("10.0.0.2", 1)]);
+        assert_eq!(
+            ring.points.len(),
+            (MAX_RING_WEIGHT as usize + 1) * VNODES_PER_WEIGHT as usize
+        );
+        assert!(ring.points.iter().any(|(_, index)| *index == 1));
+    }
+}
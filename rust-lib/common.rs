@@ -1,27 +1,677 @@
-use crate::config::Config;
-use crate::utils::resolve_target;
+use crate::balancer::{Balancer, ConnectionGuard, HashKey, Strategy};
+use crate::config::{Config, HealthCheckConfig, NoHealthyAction, PassiveHealthConfig, TargetSpec};
+use crate::probe::{probe, target_host, ProbeType};
+use crate::utils::{
+    format_addrs, is_expanded_addr, is_unix_addr, resolve_expanded, resolve_target, ExpandedTarget,
+    TargetAddr,
+};
 use anyhow::Result;
 use dashmap::DashMap;
 use log::{error, info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
+
+// 单个解析地址的健康状态
+#[derive(Debug, Clone)]
+pub struct AddressInfo {
+    pub addr: TargetAddr,
+    pub healthy: bool,
+    pub last_check: Instant,
+    pub fail_count: u32,
+    pub success_count: u32,        // 连续成功次数，达到 rise 后恢复
+    pub latency: Option<Duration>, // 最近一次健康检查的连接耗时
+}
 
 #[derive(Debug, Clone)]
 pub struct TargetInfo {
     pub original: String,
-    pub resolved: SocketAddr,
-    pub healthy: bool,
+    pub resolved: Vec<AddressInfo>,
+    pub healthy: bool, // 任一地址健康即视为目标健康
     pub last_check: Instant,
     pub fail_count: u32,
+    pub weight: u32,                     // 加权策略使用的权重
+    pub max_connections: Option<usize>,  // 活跃连接数上限
+    pub rtt: Option<Duration>,           // 健康检查RTT的指数加权移动平均
+    pub rtt_history: VecDeque<Duration>, // 最近的RTT样本
+    pub history: VecDeque<bool>,         // 最近的探测结果
+    pub flapping: bool,                  // 状态反复翻转，暂时视为不健康
+    pub recovered_at: Option<Instant>,   // 最近一次从异常恢复的时间，用于慢启动
+    pub state: TargetState,              // 运维状态：排空或停用的目标不接收新连接
+    pub protocol: Option<String>,        // 协议提示：只用于 tcp 或 udp，为空时不限
+    pub next_check: Instant,
+}
+
+// 目标的运维状态，通过控制接口在运行时切换
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetState {
+    #[default]
+    Enabled, // 正常接收新连接
+    Drain,    // 不接收新连接，已有连接继续直到结束
+    Disabled, // 不接收新连接，停止健康检查，UDP会话迁移到其他目标
+}
+
+impl TargetState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TargetState::Enabled => "enabled",
+            TargetState::Drain => "drain",
+            TargetState::Disabled => "disabled",
+        }
+    }
+}
+
+// DNS解析结果的刷新间隔
+const DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+const SCHEDULE_SLACK: Duration = Duration::from_millis(500);
+
+// EWMA平滑系数：新样本占30%
+const RTT_ALPHA: f64 = 0.3;
+const RTT_HISTORY_LEN: usize = 10;
+const PROBE_HISTORY_LEN: usize = 10;
+
+impl AddressInfo {
+    fn new(addr: TargetAddr) -> Self {
+        Self {
+            addr,
+            healthy: true,
+            last_check: Instant::now(),
+            fail_count: 0,
+            success_count: 0,
+            latency: None,
+        }
+    }
+
+    // 连续成功 rise 次才恢复，连续失败 fall 次才标记为不健康（默认均为1次，快速切换）
+    fn record(&mut self, latency: Option<Duration>, rise: u32, fall: u32) {
+        self.last_check = Instant::now();
+        self.latency = latency;
+        if latency.is_some() {
+            self.fail_count = 0; // 成功时重置失败计数
+            self.success_count += 1;
+            if self.success_count >= rise {
+                self.healthy = true;
+            }
+        } else {
+            self.success_count = 0;
+            self.fail_count += 1;
+            if self.fail_count >= fall {
+                self.healthy = false;
+            }
+        }
+    }
+}
+
+impl TargetInfo {
+    pub fn new(original: &str, addrs: Vec<TargetAddr>) -> Self {
+        Self {
+            original: original.to_string(),
+            resolved: addrs.into_iter().map(AddressInfo::new).collect(),
+            healthy: true,
+            last_check: Instant::now(),
+            fail_count: 0,
+            weight: 1,
+            max_connections: None,
+            rtt: None,
+            rtt_history: VecDeque::with_capacity(RTT_HISTORY_LEN),
+            history: VecDeque::with_capacity(PROBE_HISTORY_LEN),
+            flapping: false,
+            recovered_at: None,
+            state: TargetState::Enabled,
+            protocol: None,
+            next_check: Instant::now(),
+        }
+    }
+
+    // 记录一次探测结果；最近结果中状态翻转次数达到阈值时判定为抖动，
+    // 翻转记录随稳定的结果移出窗口后自动解除
+    fn record_probe(&mut self, success: bool, flap_threshold: u32) {
+        if self.history.len() == PROBE_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(success);
+
+        let flips = self
+            .history
+            .iter()
+            .zip(self.history.iter().skip(1))
+            .filter(|(a, b)| a != b)
+            .count() as u32;
+        self.flapping = flap_threshold > 0 && flips >= flap_threshold;
+    }
+
+    // 平滑后的RTT，供最低延迟策略使用
+    pub fn latency(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_ALPHA) + sample.mul_f64(RTT_ALPHA),
+            None => sample,
+        });
+        if self.rtt_history.len() == RTT_HISTORY_LEN {
+            self.rtt_history.pop_front();
+        }
+        self.rtt_history.push_back(sample);
+    }
+
+    pub fn addrs(&self) -> Vec<TargetAddr> {
+        self.resolved.iter().map(|a| a.addr.clone()).collect()
+    }
+
+    // 连接顺序：健康地址在前（保持解析顺序），异常地址放在最后兜底
+    pub fn connect_order(&self) -> Vec<TargetAddr> {
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = self.resolved.iter().partition(|a| a.healthy);
+        healthy
+            .into_iter()
+            .chain(unhealthy)
+            .map(|a| a.addr.clone())
+            .collect()
+    }
+
+    // 未配置连接上限，或活跃连接数未达上限
+    fn has_capacity(&self, balancer: &Balancer) -> bool {
+        self.max_connections
+            .is_none_or(|max| balancer.active_connections(&self.original) < max)
+    }
+
+    // 可以接收新连接：处于启用状态且未达到连接上限
+    fn accepts_new(&self, balancer: &Balancer) -> bool {
+        self.state == TargetState::Enabled && self.has_capacity(balancer)
+    }
+
+    // 健康且处于启用状态，参与层级切换和主备选择
+    fn in_service(&self) -> bool {
+        self.healthy && self.state == TargetState::Enabled
+    }
+
+    // 目标缓存按地址在规则间共享，权重和连接上限按规则的目标配置填入
+    fn for_rule(&self, spec: &TargetSpec) -> Self {
+        let mut target_info = self.clone();
+        target_info.weight = spec.get_weight();
+        target_info.max_connections = spec.max_connections;
+        target_info
+    }
+
+    // DNS结果变化时替换地址列表，保留仍然存在的地址的健康状态
+    fn with_resolved(&self, addrs: Vec<TargetAddr>) -> Self {
+        let resolved = addrs
+            .into_iter()
+            .map(|addr| {
+                self.resolved
+                    .iter()
+                    .find(|a| a.addr == addr)
+                    .cloned()
+                    .unwrap_or_else(|| AddressInfo::new(addr))
+            })
+            .collect();
+
+        let mut target_info = Self {
+            original: self.original.clone(),
+            resolved,
+            healthy: true,
+            last_check: Instant::now(),
+            fail_count: self.fail_count,
+            weight: self.weight,
+            max_connections: self.max_connections,
+            rtt: self.rtt,
+            rtt_history: self.rtt_history.clone(),
+            history: self.history.clone(),
+            flapping: self.flapping,
+            recovered_at: self.recovered_at,
+            state: self.state,
+            protocol: self.protocol.clone(),
+            next_check: self.next_check,
+        };
+        target_info.healthy =
+            target_info.resolved.iter().any(|a| a.healthy) && !target_info.flapping;
+        if target_info.healthy {
+            target_info.fail_count = 0;
+        }
+        target_info
+    }
 }
 
-#[derive(Debug)]
 pub struct RuleInfo {
     pub targets: Vec<TargetInfo>,
     pub selected_target: Option<TargetInfo>,
     pub last_update: Instant,
+    pub balancer: Arc<Balancer>,
+    pub tier_of: HashMap<String, usize>, // 目标所在层级，未配置 tiers 时为空
+    pub active_tier: usize,
+    pub tier_recovered_at: Option<Instant>, // 更高层级恢复健康的时间，用于抢占延迟
+    pub preempt_delay: Duration,
+}
+
+impl RuleInfo {
+    fn tier(&self, target: &str) -> usize {
+        self.tier_of.get(target).copied().unwrap_or(0)
+    }
+
+    // 当前层级中的目标
+    fn active_targets(&self) -> Vec<TargetInfo> {
+        self.targets
+            .iter()
+            .filter(|t| self.tier(&t.original) == self.active_tier)
+            .cloned()
+            .collect()
+    }
+
+    // 当前层级全部异常时立即降级；更高层级恢复后需持续健康 preempt_delay 才切回
+    fn update_active_tier(&mut self, rule_name: &str) {
+        let Some(best) = self
+            .targets
+            .iter()
+            .filter(|t| t.in_service())
+            .map(|t| self.tier(&t.original))
+            .min()
+        else {
+            // 全部异常时保持当前层级
+            self.tier_recovered_at = None;
+            return;
+        };
+
+        let active_healthy = self
+            .targets
+            .iter()
+            .any(|t| t.in_service() && self.tier(&t.original) == self.active_tier);
+        if !active_healthy {
+            warn!(
+                "规则 {} 层级{}全部异常，切换到层级{}",
+                rule_name,
+                self.active_tier + 1,
+                best + 1
+            );
+            self.active_tier = best;
+            self.tier_recovered_at = None;
+        } else if best < self.active_tier {
+            let recovered_at = *self.tier_recovered_at.get_or_insert_with(Instant::now);
+            if recovered_at.elapsed() >= self.preempt_delay {
+                info!(
+                    "规则 {} 层级{}已恢复{}秒，切回",
+                    rule_name,
+                    best + 1,
+                    recovered_at.elapsed().as_secs()
+                );
+                self.active_tier = best;
+                self.tier_recovered_at = None;
+            }
+        } else {
+            self.tier_recovered_at = None;
+        }
+    }
+}
+
+// 一次目标选择的结果，存活期间计入目标的活跃连接数
+pub struct TargetSelection {
+    pub target: String,
+    pub addrs: Vec<TargetAddr>,
+    _guard: ConnectionGuard,
+}
+
+// 被动健康检查状态：按目标记录真实连接结果，失败率超过阈值时标记可疑并请求立即探测
+struct PassiveState {
+    outcomes: DashMap<String, VecDeque<(Instant, bool)>>,
+    suspects: DashMap<String, Instant>,
+    probe_tx: mpsc::UnboundedSender<String>,
+}
+
+impl PassiveState {
+    fn is_suspect(&self, target: &str) -> bool {
+        self.suspects.contains_key(target)
+    }
+}
+
+// 绑定到单个规则的目标选择器，供转发器按连接选择目标
+#[derive(Clone)]
+pub struct TargetSelector {
+    rule_infos: Arc<RwLock<DashMap<String, RuleInfo>>>,
+    rule_name: String,
+    wants_host: bool,
+    passive: Arc<PassiveState>,
+    passive_config: PassiveHealthConfig,
+    connect_retries: u32,
+    connect_deadline: Duration,
+    no_healthy: Option<NoHealthyAction>,
+    protocol: Option<&'static str>, // 转发器的协议，带协议提示的目标只用于对应协议
+}
+
+impl TargetSelector {
+    // 按 SNI/Host 做亲和时，TCP连接需先读取客户端首批数据再选择目标
+    pub fn wants_host(&self) -> bool {
+        self.wants_host
+    }
+
+    // 绑定到 tcp 或 udp 转发器的选择器
+    pub fn for_protocol(&self, protocol: &'static str) -> Self {
+        Self {
+            protocol: Some(protocol),
+            ..self.clone()
+        }
+    }
+
+    fn serves(&self, target: &TargetInfo) -> bool {
+        match (target.protocol.as_deref(), self.protocol) {
+            (Some(hint), Some(protocol)) => hint == protocol,
+            _ => true,
+        }
+    }
+
+    // 按规则的负载均衡策略选择目标；优先健康且未被标记可疑的目标，没有健康目标时在全部目标中兜底
+    pub async fn select(
+        &self,
+        client: Option<SocketAddr>,
+        host: Option<&str>,
+    ) -> Result<TargetSelection> {
+        let rule_infos = self.rule_infos.read().await;
+        let Some(rule_info) = rule_infos.get(&self.rule_name) else {
+            anyhow::bail!("没有可用的目标: {}", self.rule_name);
+        };
+
+        let balancer = &rule_info.balancer;
+        // 排空、停用或达到连接上限的目标不再接收新连接
+        let serving: Vec<_> = rule_info
+            .targets
+            .iter()
+            .filter(|t| self.serves(t))
+            .collect();
+        let targets: Vec<_> = serving
+            .iter()
+            .filter(|t| t.accepts_new(balancer))
+            .map(|t| (*t).clone())
+            .collect();
+        if targets.is_empty() && !serving.is_empty() {
+            if serving.iter().all(|t| t.state != TargetState::Enabled) {
+                anyhow::bail!("规则 {} 的目标均已排空或停用", self.rule_name);
+            }
+            anyhow::bail!("规则 {} 的目标均已达到连接上限", self.rule_name);
+        }
+        let tier_targets: Vec<_> = rule_info
+            .active_targets()
+            .into_iter()
+            .filter(|t| self.serves(t) && t.accepts_new(balancer))
+            .collect();
+
+        let target = if balancer.strategy() == Strategy::Failover {
+            // 当前目标被标记可疑、排空、连接已满或不用于本协议时，先按顺序切到下一个可用目标
+            let selected = rule_info
+                .selected_target
+                .as_ref()
+                .filter(|t| self.serves(t));
+            match selected {
+                Some(selected)
+                    if !self.passive.is_suspect(&selected.original)
+                        && selected.accepts_new(balancer) =>
+                {
+                    Some(selected.clone())
+                }
+                selected => {
+                    let ordered: Vec<_> = tier_targets.iter().chain(&targets).cloned().collect();
+                    self.eligible(&ordered)
+                        .into_iter()
+                        .next()
+                        .or_else(|| selected.cloned().filter(|t| t.accepts_new(balancer)))
+                        .or_else(|| targets.first().cloned())
+                }
+            }
+        } else {
+            // 先在当前层级中选择，层级内没有健康目标时再看其余层级
+            let mut candidates = self.eligible(&tier_targets);
+            if candidates.is_empty() {
+                candidates = self.eligible(&targets);
+            }
+            if candidates.is_empty() {
+                candidates = targets;
+            }
+            balancer.pick(&candidates, client, host).cloned()
+        };
+
+        match target {
+            Some(target) => Ok(TargetSelection {
+                _guard: balancer.acquire(&target.original),
+                addrs: target.connect_order(),
+                target: target.original,
+            }),
+            None => anyhow::bail!("没有可用的目标: {}", self.rule_name),
+        }
+    }
+
+    // 健康且未被标记可疑的目标，没有时退回全部健康目标
+    fn eligible(&self, targets: &[TargetInfo]) -> Vec<TargetInfo> {
+        let preferred: Vec<_> = targets
+            .iter()
+            .filter(|t| t.healthy && !self.passive.is_suspect(&t.original))
+            .cloned()
+            .collect();
+        if !preferred.is_empty() {
+            return preferred;
+        }
+        targets.iter().filter(|t| t.healthy).cloned().collect()
+    }
+
+    // 连接失败后按规则的目标顺序选择下一个未尝试过的目标，优先健康且未被标记可疑的目标
+    pub async fn select_next(&self, tried: &[String]) -> Option<TargetSelection> {
+        let rule_infos = self.rule_infos.read().await;
+        let rule_info = rule_infos.get(&self.rule_name)?;
+        let untried: Vec<_> = rule_info
+            .targets
+            .iter()
+            .filter(|t| {
+                !tried.contains(&t.original) && self.serves(t) && t.accepts_new(&rule_info.balancer)
+            })
+            .collect();
+        let target = untried
+            .iter()
+            .find(|t| t.healthy && !self.passive.is_suspect(&t.original))
+            .or_else(|| untried.iter().find(|t| t.healthy))
+            .or_else(|| untried.first())?;
+        Some(TargetSelection {
+            _guard: rule_info.balancer.acquire(&target.original),
+            addrs: target.connect_order(),
+            target: target.original.clone(),
+        })
+    }
+
+    pub fn connect_retries(&self) -> u32 {
+        self.connect_retries
+    }
+
+    pub fn connect_deadline(&self) -> Duration {
+        self.connect_deadline
+    }
+
+    pub fn no_healthy_action(&self) -> Option<&NoHealthyAction> {
+        self.no_healthy.as_ref()
+    }
+
+    // 规则中是否有健康且处于启用状态的目标
+    pub async fn has_healthy(&self) -> bool {
+        let rule_infos = self.rule_infos.read().await;
+        rule_infos.get(&self.rule_name).is_some_and(|rule_info| {
+            rule_info
+                .targets
+                .iter()
+                .any(|t| self.serves(t) && t.in_service())
+        })
+    }
+
+    // 上报一次真实连接的结果：连接失败、超时或立即被重置计为失败
+    pub fn report(&self, target: &str, success: bool) {
+        let config = &self.passive_config;
+        if !config.is_enabled() {
+            return;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(config.get_window());
+        let mut outcomes = self.passive.outcomes.entry(target.to_string()).or_default();
+        outcomes.push_back((now, success));
+        while outcomes
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > window)
+        {
+            outcomes.pop_front();
+        }
+
+        let total = outcomes.len();
+        let failures = outcomes.iter().filter(|(_, ok)| !ok).count();
+        if total < config.get_min_requests() as usize
+            || failures * 100 < total * config.get_error_rate() as usize
+        {
+            return;
+        }
+        outcomes.clear();
+        drop(outcomes);
+
+        if self
+            .passive
+            .suspects
+            .insert(target.to_string(), now)
+            .is_none()
+        {
+            warn!(
+                "目标 {} 真实连接失败率过高（{}/{}），标记为可疑并立即探测",
+                target, failures, total
+            );
+        }
+        let _ = self.passive.probe_tx.send(target.to_string());
+    }
+
+    // 各目标的健康状态和RTT，供转发器统计使用（同步接口，不能在异步上下文中调用）
+    pub fn write_stats(&self, result: &mut HashMap<String, String>) {
+        let rule_infos = self.rule_infos.blocking_read();
+        let Some(rule_info) = rule_infos.get(&self.rule_name) else {
+            return;
+        };
+        if !rule_info.tier_of.is_empty() {
+            result.insert(
+                "active_tier".to_string(),
+                (rule_info.active_tier + 1).to_string(),
+            );
+        }
+        let ms = |d: &Duration| format!("{:.1}", d.as_secs_f64() * 1000.0);
+        for target in &rule_info.targets {
+            let prefix = format!("target.{}", target.original);
+            result.insert(format!("{}.healthy", prefix), target.healthy.to_string());
+            result.insert(
+                format!("{}.state", prefix),
+                target.state.as_str().to_string(),
+            );
+            result.insert(
+                format!("{}.suspect", prefix),
+                self.passive.is_suspect(&target.original).to_string(),
+            );
+            result.insert(
+                format!("{}.active", prefix),
+                rule_info
+                    .balancer
+                    .active_connections(&target.original)
+                    .to_string(),
+            );
+            if let Some(max) = target.max_connections {
+                result.insert(format!("{}.max_connections", prefix), max.to_string());
+            }
+            if let Some(progress) = rule_info.balancer.slow_start_progress(target) {
+                result.insert(
+                    format!("{}.slow_start", prefix),
+                    format!("{:.0}%", progress * 100.0),
+                );
+            }
+            result.insert(
+                format!("{}.rtt_ms", prefix),
+                target
+                    .rtt
+                    .as_ref()
+                    .map(ms)
+                    .unwrap_or_else(|| "-".to_string()),
+            );
+            result.insert(
+                format!("{}.rtt_history", prefix),
+                target
+                    .rtt_history
+                    .iter()
+                    .map(ms)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+        }
+    }
+
+    // 已选目标是否仍可继续使用：目标健康，或规则当前没有任何健康目标（重新选择也无法改善）
+    // 排空的目标保留已有会话，停用的目标需要迁移
+    pub async fn is_available(&self, target: &str) -> bool {
+        let rule_infos = self.rule_infos.read().await;
+        rule_infos.get(&self.rule_name).is_some_and(|rule_info| {
+            if rule_info
+                .targets
+                .iter()
+                .any(|t| t.original == target && t.state == TargetState::Disabled)
+            {
+                return false;
+            }
+            let usable =
+                |t: &&TargetInfo| t.healthy && t.state != TargetState::Disabled && self.serves(t);
+            // 当前层级有健康目标时，其余层级的会话需要迁回当前层级
+            let tier_targets = rule_info.active_targets();
+            if tier_targets.iter().any(|t| usable(&t)) {
+                return tier_targets
+                    .iter()
+                    .any(|t| usable(&t) && t.original == target);
+            }
+            let mut healthy = rule_info.targets.iter().filter(usable).peekable();
+            healthy.peek().is_none() || healthy.any(|t| t.original == target)
+        })
+    }
+}
+
+#[cfg(test)]
+impl TargetSelector {
+    // 测试用：由给定目标构造单条规则的选择器，不经过DNS解析和健康检查
+    pub(crate) fn with_targets(
+        targets: Vec<TargetInfo>,
+        strategy: Strategy,
+        connect_retries: u32,
+        connect_deadline: Duration,
+    ) -> Self {
+        let rule_infos = DashMap::new();
+        rule_infos.insert(
+            "test".to_string(),
+            RuleInfo {
+                targets,
+                selected_target: None,
+                last_update: Instant::now(),
+                balancer: Arc::new(Balancer::new(strategy)),
+                tier_of: HashMap::new(),
+                active_tier: 0,
+                tier_recovered_at: None,
+                preempt_delay: Duration::from_secs(30),
+            },
+        );
+        let (probe_tx, _) = mpsc::unbounded_channel();
+        Self {
+            rule_infos: Arc::new(RwLock::new(rule_infos)),
+            rule_name: "test".to_string(),
+            wants_host: false,
+            passive: Arc::new(PassiveState {
+                outcomes: DashMap::new(),
+                suspects: DashMap::new(),
+                probe_tx,
+            }),
+            passive_config: HealthCheckConfig::default().get_passive(),
+            connect_retries,
+            connect_deadline,
+            no_healthy: None,
+            protocol: None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -29,32 +679,74 @@ pub struct CommonManager {
     config: Config,
     target_cache: Arc<DashMap<String, TargetInfo>>,
     rule_infos: Arc<RwLock<DashMap<String, RuleInfo>>>,
+    health_check_started: Arc<AtomicBool>,
+    passive: Arc<PassiveState>,
+    probe_rx: Arc<Mutex<Option<mpsc::UnboundedReceiver<String>>>>,
+    target_states: Arc<DashMap<String, TargetState>>, // 非启用状态的目标
+    state_file: Option<PathBuf>,
+    expanded_targets: Arc<DashMap<String, Vec<ExpandedTarget>>>, // SRV/TXT目标 -> 当前展开出的目标
 }
 
 impl CommonManager {
     pub fn new(config: Config) -> Self {
+        let (probe_tx, probe_rx) = mpsc::unbounded_channel();
+        let state_file = config
+            .get_control()
+            .and_then(|control| control.state_file.as_ref())
+            .map(PathBuf::from);
+        let target_states = state_file
+            .as_ref()
+            .map(|path| load_target_states(path, &config))
+            .unwrap_or_default();
         Self {
             config,
             target_cache: Arc::new(DashMap::new()),
             rule_infos: Arc::new(RwLock::new(DashMap::new())),
+            health_check_started: Arc::new(AtomicBool::new(false)),
+            passive: Arc::new(PassiveState {
+                outcomes: DashMap::new(),
+                suspects: DashMap::new(),
+                probe_tx,
+            }),
+            probe_rx: Arc::new(Mutex::new(Some(probe_rx))),
+            target_states: Arc::new(target_states),
+            state_file,
+            expanded_targets: Arc::new(DashMap::new()),
         }
     }
 
     pub async fn initialize(&self) -> Result<()> {
         // 1. DNS解析阶段：解析所有目标地址
         for rule in &self.config.rules {
+            // DNS规则的目标是上游DNS服务器，透明代理没有固定目标，均不参与目标解析和健康检查
+            if !rule.has_managed_targets() {
+                continue;
+            }
             if let Err(e) = self.initialize_rule_targets(rule).await {
                 error!("规则 {} DNS解析失败: {}", rule.name, e);
             }
         }
 
         // 2. 初始健康检查阶段：批量并发检查所有目标
-        let health_check_result =
-            Self::quick_batch_health_check(&self.target_cache, &self.config).await;
+        let health_check_result = Self::quick_batch_health_check(
+            &self.target_cache,
+            &self.expanded_targets,
+            &self.config,
+            &self.passive,
+            &self.target_states,
+        )
+        .await;
         info!("初始健康检查完成: {}", health_check_result);
 
         // 3. 选择最优地址阶段：为每个规则选择最佳目标
-        Self::update_rule_targets(&self.rule_infos, &self.target_cache, &self.config).await;
+        Self::update_rule_targets(
+            &self.rule_infos,
+            &self.target_cache,
+            &self.expanded_targets,
+            &self.config,
+            &self.target_states,
+        )
+        .await;
 
         // 4. 验证初始化结果
         let rule_infos = self.rule_infos.read().await;
@@ -67,7 +759,9 @@ impl CommonManager {
             if let Some(target) = &rule_info.selected_target {
                 info!(
                     "规则 {}: {} -> {}",
-                    rule_name, target.original, target.resolved
+                    rule_name,
+                    target.original,
+                    format_addrs(&target.addrs())
                 );
                 available_rules += 1;
             } else {
@@ -86,22 +780,30 @@ impl CommonManager {
     async fn initialize_rule_targets(&self, rule: &crate::config::ForwardRule) -> Result<()> {
         let mut targets = Vec::new();
 
-        for target_str in rule.targets.iter() {
-            match resolve_target(target_str).await {
-                Ok(resolved_addr) => {
-                    let target_info = TargetInfo {
-                        original: target_str.clone(),
-                        resolved: resolved_addr,
-                        healthy: true,
-                        last_check: Instant::now(),
-                        fail_count: 0,
-                    };
+        for spec in rule.targets.iter() {
+            // SRV/TXT目标展开为多个目标，由 update_rule_targets 按优先级和权重加入规则
+            if is_expanded_addr(&spec.address) {
+                match resolve_expanded(&spec.address).await {
+                    Ok(expanded_targets) => Self::apply_expanded_targets(
+                        &spec.address,
+                        expanded_targets,
+                        &self.target_cache,
+                        &self.expanded_targets,
+                        &self.config,
+                    ),
+                    Err(e) => error!("无法解析目标 {}: {}", spec.address, e),
+                }
+                continue;
+            }
+            match resolve_target(&spec.address).await {
+                Ok(resolved_addrs) => {
+                    let target_info = TargetInfo::new(&spec.address, resolved_addrs);
 
-                    targets.push(target_info.clone());
-                    self.target_cache.insert(target_str.clone(), target_info);
+                    targets.push(target_info.for_rule(spec));
+                    self.target_cache.insert(spec.address.clone(), target_info);
                 }
                 Err(e) => {
-                    error!("无法解析目标 {}: {}", target_str, e);
+                    error!("无法解析目标 {}: {}", spec.address, e);
                 }
             }
         }
@@ -110,6 +812,17 @@ impl CommonManager {
             targets,
             selected_target: None,
             last_update: Instant::now(),
+            balancer: Arc::new(Balancer::from_rule(rule)),
+            tier_of: rule
+                .tiers
+                .iter()
+                .flatten()
+                .flatten()
+                .map(|target| (target.address.clone(), rule.get_tier(&target.address)))
+                .collect(),
+            active_tier: 0,
+            tier_recovered_at: None,
+            preempt_delay: Duration::from_secs(rule.get_preempt_delay()),
         };
 
         self.rule_infos
@@ -120,33 +833,82 @@ impl CommonManager {
     }
 
     async fn start_health_check_task(&self) {
+        // initialize 可能被调用多次，只保留一个检查任务，避免 rise/fall 计数被重复累加
+        if self.health_check_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
         let target_cache = self.target_cache.clone();
         let rule_infos = self.rule_infos.clone();
         let config = self.config.clone(); // 传递配置信息
+        let passive = self.passive.clone();
+        let target_states = self.target_states.clone();
+        let expanded_targets = self.expanded_targets.clone();
+        let Some(mut probe_rx) = self.probe_rx.lock().unwrap().take() else {
+            return;
+        };
 
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(15)); // 缩短检查间隔到15秒
-            let mut _check_count = 0;
+            // 各规则检查间隔和DNS刷新间隔按最大公约数调度，启用抖动时每秒检查一次是否到期
+            let tick = Self::health_check_tick(&config);
+            let mut interval = tokio::time::interval(Duration::from_secs(tick));
+            let mut last_dns_update = Instant::now();
 
-            info!("启动定期健康检查任务，间隔15秒");
+            info!("启动定期健康检查任务，间隔{}秒", tick);
 
             let mut last_status = None;
 
             loop {
-                // 等待检查间隔
-                interval.tick().await;
+                // 等待检查间隔，或被动检查请求立即探测可疑目标
+                let triggered = tokio::select! {
+                    _ = interval.tick() => false,
+                    Some(target) = probe_rx.recv() => {
+                        let now = Instant::now();
+                        let mut pending = Some(target);
+                        while let Some(target) = pending {
+                            if let Some(mut info) = target_cache.get_mut(&target) {
+                                info.next_check = now;
+                            }
+                            pending = probe_rx.try_recv().ok();
+                        }
+                        true
+                    }
+                };
 
-                // 1. 进行DNS检查，更新所有目标地址的解析结果
-                Self::update_dns_resolutions(&target_cache).await;
+                // 1. 每15秒进行DNS检查，更新所有目标地址的解析结果
+                if !triggered && last_dns_update.elapsed() + SCHEDULE_SLACK >= DNS_REFRESH_INTERVAL
+                {
+                    last_dns_update = Instant::now();
+                    Self::update_dns_resolutions(&target_cache, &expanded_targets, &config).await;
 
-                // 2. 等待5秒后进行健康检查，避免与DNS检查冲突
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                    // 2. 稍等后进行健康检查，避免与DNS检查冲突
+                    tokio::time::sleep(Duration::from_secs(5).min(Duration::from_secs(tick) / 2))
+                        .await;
+                }
 
-                // 3. 基于最新的DNS解析结果进行健康检查
-                let current_status = Self::batch_health_check(&target_cache, &config).await;
+                // 3. 基于最新的DNS解析结果检查到期的目标
+                let Some(current_status) = Self::batch_health_check(
+                    &target_cache,
+                    &expanded_targets,
+                    &config,
+                    &passive,
+                    &target_states,
+                    false,
+                )
+                .await
+                else {
+                    continue;
+                };
 
                 // 4. 更新规则目标选择
-                Self::update_rule_targets(&rule_infos, &target_cache, &config).await;
+                Self::update_rule_targets(
+                    &rule_infos,
+                    &target_cache,
+                    &expanded_targets,
+                    &config,
+                    &target_states,
+                )
+                .await;
 
                 // 只在状态变化时记录日志，减少重复输出
                 if last_status != Some(current_status.clone()) {
@@ -157,25 +919,50 @@ impl CommonManager {
         });
     }
 
+    fn health_check_tick(config: &Config) -> u64 {
+        let global = config.get_dynamic_update_config();
+        let mut tick = DNS_REFRESH_INTERVAL.as_secs();
+        for rule in config.rules.iter().filter(|r| r.has_managed_targets()) {
+            let health_check = rule.get_health_check_config();
+            if health_check.get_jitter() > 0 {
+                return 1;
+            }
+            let interval = health_check.get_interval(&rule.get_dynamic_update_config(&global));
+            tick = gcd(tick, interval);
+        }
+        tick
+    }
+
     // DNS解析更新 - 定期检查DNS变化并更新target_cache
-    async fn update_dns_resolutions(target_cache: &Arc<DashMap<String, TargetInfo>>) {
+    async fn update_dns_resolutions(
+        target_cache: &Arc<DashMap<String, TargetInfo>>,
+        expanded_targets: &Arc<DashMap<String, Vec<ExpandedTarget>>>,
+        config: &Config,
+    ) {
+        // 展开出的目标随SRV/TXT记录一起刷新
         let targets: Vec<_> = target_cache
             .iter()
+            .filter(|entry| !is_expanded_member(expanded_targets, entry.key()))
             .map(|entry| (entry.key().clone(), entry.value().clone()))
             .collect();
 
         // 并发解析所有域名目标
         let mut dns_tasks = Vec::new();
         for (target_str, target_info) in targets {
-            // 只对域名进行DNS解析，跳过IP地址
-            if target_str.parse::<std::net::IpAddr>().is_err() && target_str.contains('.') {
+            // 只对域名进行DNS解析，跳过IP地址和Unix套接字路径
+            if target_str.parse::<std::net::IpAddr>().is_err()
+                && target_str.contains('.')
+                && !is_unix_addr(&target_str)
+            {
                 let task = tokio::spawn(async move {
                     match resolve_target(&target_str).await {
                         Ok(new_resolved) => {
-                            if new_resolved != target_info.resolved {
+                            if new_resolved != target_info.addrs() {
                                 info!(
                                     "目标 {} DNS解析变化: {} -> {}",
-                                    target_str, target_info.resolved, new_resolved
+                                    target_str,
+                                    format_addrs(&target_info.addrs()),
+                                    format_addrs(&new_resolved)
                                 );
                                 Some((target_str, target_info, new_resolved))
                             } else {
@@ -194,188 +981,202 @@ impl CommonManager {
 
         // 等待所有DNS解析完成并更新缓存
         for task in dns_tasks {
-            if let Ok(Some((target_str, mut target_info, new_resolved))) = task.await {
-                target_info.resolved = new_resolved;
-                target_info.last_check = Instant::now();
-                // DNS变化时重置健康状态，让健康检查重新评估
-                target_info.healthy = true;
-                target_info.fail_count = 0;
-                target_cache.insert(target_str, target_info);
+            if let Ok(Some((target_str, target_info, new_resolved))) = task.await {
+                // 新出现的地址默认健康，由下一轮健康检查重新评估
+                target_cache.insert(target_str, target_info.with_resolved(new_resolved));
             }
         }
-    }
 
-    // 快速健康检查 - 启动时使用，缩短超时时间，根据规则配置智能选择协议
-    async fn quick_batch_health_check(
-        target_cache: &Arc<DashMap<String, TargetInfo>>,
-        config: &Config,
-    ) -> String {
-        let targets: Vec<_> = target_cache
+        // 重新解析SRV/TXT记录，增删展开出的目标；解析失败时保留上次的结果
+        let mut expanded_specs: Vec<_> = config
+            .rules
             .iter()
-            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .flat_map(|rule| rule.targets.iter())
+            .map(|spec| spec.address.clone())
+            .filter(|address| is_expanded_addr(address))
             .collect();
-
-        // 建立目标地址到规则的映射，用于决定健康检查协议
-        let mut target_to_protocol = std::collections::HashMap::new();
-        for rule in &config.rules {
-            let protocols = rule.get_protocols();
-            for target_str in &rule.targets {
-                // 对于TCP+UDP规则，只检查TCP；对于纯UDP规则，检查UDP
-                let check_protocol = if protocols.len() == 1 && protocols[0] == "udp" {
-                    "udp" // 只有纯UDP规则才检查UDP
-                } else {
-                    "tcp" // 其他情况都检查TCP（包括TCP+UDP规则）
-                };
-                target_to_protocol.insert(target_str.clone(), check_protocol);
+        expanded_specs.sort();
+        expanded_specs.dedup();
+        let results =
+            futures::future::join_all(expanded_specs.iter().map(|spec| resolve_expanded(spec)))
+                .await;
+        for (spec, result) in expanded_specs.iter().zip(results) {
+            match result {
+                Ok(new_targets) => Self::apply_expanded_targets(
+                    spec,
+                    new_targets,
+                    target_cache,
+                    expanded_targets,
+                    config,
+                ),
+                Err(e) => warn!("DNS解析失败 {}: {}", spec, e),
             }
         }
+    }
 
-        // 并发执行健康检查，使用统一的超时时间
-        let mut tasks = Vec::new();
-        for (target_str, target_info) in targets {
-            let protocol_to_check = target_to_protocol
-                .get(&target_str)
-                .copied()
-                .unwrap_or("tcp");
-
-            let task = tokio::spawn(async move {
-                let start = Instant::now();
-
-                // 使用统一的超时时间
-                let timeout_duration = Duration::from_secs(5); // 统一使用5秒超时
-
-                // 根据规则配置决定健康检查协议
-                let result = if protocol_to_check == "udp" {
-                    // UDP协议：智能健康检查
-                    if target_str.parse::<std::net::SocketAddr>().is_ok() {
-                        // 直接IP:PORT格式，跳过检查（无法有效验证UDP服务）
-                        Ok(Duration::from_millis(0))
-                    } else {
-                        // 域名格式，尝试DNS解析
-                        match crate::utils::resolve_target(&target_str).await {
-                            Ok(_) => Ok(Duration::from_millis(0)), // DNS解析成功即可
-                            Err(e) => Err(anyhow::anyhow!("UDP目标解析失败: {}", e)),
-                        }
-                    }
-                } else {
-                    // TCP测试使用动态超时时间
-                    tokio::time::timeout(
-                        timeout_duration,
-                        crate::utils::test_connection(&target_str),
-                    )
-                    .await
-                    .unwrap_or(Err(anyhow::anyhow!("TCP连接测试超时")))
-                };
-
-                let check_time = start.elapsed();
-                (target_str, target_info, result, check_time)
-            });
-            tasks.push(task);
-        }
-
-        // 等待所有检查完成并统计结果
-        let mut success_count = 0;
-        let mut fail_count = 0;
-        let mut status_changes = Vec::new();
-
-        for task in tasks {
-            if let Ok((target_str, mut target_info, result, _check_time)) = task.await {
-                let old_healthy = target_info.healthy;
-
-                match result {
-                    Ok(_) => {
-                        target_info.healthy = true;
-                        target_info.fail_count = 0; // 成功时重置失败计数
-                        target_info.last_check = Instant::now();
-                        success_count += 1;
-
-                        // 如果之前不健康，现在恢复了
-                        if !old_healthy {
-                            status_changes.push(format!("{} 恢复", target_str));
-                        }
-                    }
-                    Err(_e) => {
-                        target_info.fail_count += 1;
-                        target_info.last_check = Instant::now();
-
-                        // 失败1次就标记为不健康，快速切换
-                        if target_info.fail_count >= 1 && old_healthy {
-                            target_info.healthy = false;
-                            status_changes.push(format!("{} 异常", target_str));
-                        }
-
-                        // 统计时仍然按当前健康状态计算
-                        if target_info.healthy {
-                            success_count += 1;
-                        } else {
-                            fail_count += 1;
-                        }
+    // 用SRV/TXT解析结果更新目标缓存：新增的目标加入缓存，消失的目标在没有其他引用时移除
+    fn apply_expanded_targets(
+        spec: &str,
+        new_targets: Vec<ExpandedTarget>,
+        target_cache: &DashMap<String, TargetInfo>,
+        expanded_targets: &DashMap<String, Vec<ExpandedTarget>>,
+        config: &Config,
+    ) {
+        for target in &new_targets {
+            let existing = target_cache.get(&target.address).map(|info| info.clone());
+            match existing {
+                Some(info) if info.addrs() != target.addrs => {
+                    info!(
+                        "目标 {} DNS解析变化: {} -> {}",
+                        target.address,
+                        format_addrs(&info.addrs()),
+                        format_addrs(&target.addrs)
+                    );
+                    target_cache.insert(
+                        target.address.clone(),
+                        info.with_resolved(target.addrs.clone()),
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    match target.weight {
+                        Some(weight) => info!(
+                            "{} 新增目标 {} (优先级{} 权重{})",
+                            spec, target.address, target.priority, weight
+                        ),
+                        None => info!("{} 新增目标 {}", spec, target.address),
                     }
+                    target_cache.insert(
+                        target.address.clone(),
+                        TargetInfo::new(&target.address, target.addrs.clone()),
+                    );
                 }
-
-                target_cache.insert(target_str, target_info);
             }
         }
 
-        // 生成状态摘要
-        let healthy_addresses = success_count;
-        let unhealthy_addresses = fail_count;
-
-        if !status_changes.is_empty() {
-            format!(
-                "{} 个地址健康，{} 个地址异常 [{}]",
-                healthy_addresses,
-                unhealthy_addresses,
-                status_changes.join(", ")
-            )
-        } else {
-            format!(
-                "{} 个地址健康，{} 个地址异常",
-                healthy_addresses, unhealthy_addresses
-            )
+        let old_targets = expanded_targets
+            .insert(spec.to_string(), new_targets.clone())
+            .unwrap_or_default();
+        for target in old_targets {
+            if new_targets.iter().any(|t| t.address == target.address) {
+                continue;
+            }
+            info!("{} 移除目标 {}", spec, target.address);
+            // TXT旧格式展开出的目标与TXT目标同名，不算作直接配置的目标
+            let configured = config.rules.iter().any(|rule| {
+                rule.targets
+                    .iter()
+                    .any(|t| t.address == target.address && !is_expanded_addr(&t.address))
+            });
+            if !configured && !is_expanded_member(expanded_targets, &target.address) {
+                target_cache.remove(&target.address);
+            }
         }
     }
 
-    // 标准健康检查 - 定期检查使用，根据规则配置智能选择协议
-    async fn batch_health_check(
+    // 快速健康检查 - 启动时使用，与定期检查共用同一套逐地址检查逻辑
+    async fn quick_batch_health_check(
         target_cache: &Arc<DashMap<String, TargetInfo>>,
+        expanded_targets: &DashMap<String, Vec<ExpandedTarget>>,
         config: &Config,
+        passive: &PassiveState,
+        target_states: &DashMap<String, TargetState>,
     ) -> String {
+        Self::batch_health_check(
+            target_cache,
+            expanded_targets,
+            config,
+            passive,
+            target_states,
+            true,
+        )
+        .await
+        .unwrap_or_default()
+    }
+
+    // 标准健康检查 - 定期检查使用，根据规则配置智能选择协议，逐个地址检查
+    // 只检查到期的目标（force 时检查全部），停用的目标不检查，没有到期目标时返回 None
+    async fn batch_health_check(
+        target_cache: &Arc<DashMap<String, TargetInfo>>,
+        expanded_targets: &DashMap<String, Vec<ExpandedTarget>>,
+        config: &Config,
+        passive: &PassiveState,
+        target_states: &DashMap<String, TargetState>,
+        force: bool,
+    ) -> Option<String> {
+        // 以本轮开始时间计算下次检查，留出余量避免因调度误差错过下一轮
+        let now = Instant::now();
         let targets: Vec<_> = target_cache
             .iter()
+            .filter(|entry| force || entry.value().next_check <= now + SCHEDULE_SLACK)
+            .filter(|entry| {
+                target_states
+                    .get(entry.key())
+                    .is_none_or(|state| *state != TargetState::Disabled)
+            })
             .map(|entry| (entry.key().clone(), entry.value().clone()))
             .collect();
+        if targets.is_empty() {
+            return None;
+        }
 
-        // 建立目标地址到规则的映射，用于决定健康检查协议
-        let mut target_to_protocol = std::collections::HashMap::new();
+        // 建立目标地址到规则的映射，用于决定健康检查协议和参数
+        let global = config.get_dynamic_update_config();
+        let mut target_settings = HashMap::new();
         for rule in &config.rules {
             let protocols = rule.get_protocols();
-            for target_str in &rule.targets {
-                // 对于TCP+UDP规则，只检查TCP；对于纯UDP规则，检查UDP
-                let check_protocol = if protocols.len() == 1 && protocols[0] == "udp" {
-                    "udp" // 只有纯UDP规则才检查UDP
+            let health_check = rule.get_health_check_config();
+            // 未配置 type 时：TCP+UDP规则只检查TCP连接；纯UDP规则仅确认能解析（None）
+            let probe_type = health_check.get_probe_type().or(
+                if protocols.len() == 1 && protocols[0] == "udp" {
+                    None
                 } else {
-                    "tcp" // 其他情况都检查TCP（包括TCP+UDP规则）
+                    Some(ProbeType::Tcp)
+                },
+            );
+            let interval = health_check.get_interval(&rule.get_dynamic_update_config(&global));
+            for spec in &rule.targets {
+                let members = match expanded_targets.get(&spec.address) {
+                    Some(targets) => targets
+                        .iter()
+                        .map(|t| (t.address.clone(), t.protocol.clone()))
+                        .collect(),
+                    None => vec![(spec.address.clone(), None)],
                 };
-                target_to_protocol.insert(target_str.clone(), check_protocol);
+                for (address, protocol) in members {
+                    // 只用于UDP的端点不做默认的TCP连接检查
+                    let probe_type = match protocol.as_deref() {
+                        Some("udp") if health_check.get_probe_type().is_none() => None,
+                        _ => probe_type,
+                    };
+                    target_settings.insert(address, (probe_type, health_check.clone(), interval));
+                }
             }
         }
 
         // 并发执行健康检查
         let mut tasks = Vec::new();
         for (target_str, target_info) in targets {
-            let protocol_to_check = target_to_protocol
+            let (probe_type, health_check, interval) = target_settings
                 .get(&target_str)
-                .copied()
-                .unwrap_or("tcp");
+                .cloned()
+                .unwrap_or((Some(ProbeType::Tcp), HealthCheckConfig::default(), 15));
 
             let task = tokio::spawn(async move {
-                let start = Instant::now();
-
-                // 根据规则配置决定健康检查协议
-                let result = if protocol_to_check == "udp" {
-                    // UDP协议：智能健康检查
-                    if target_str.parse::<std::net::SocketAddr>().is_ok() {
+                // 根据规则配置决定健康检查方式
+                let results: Vec<Result<Duration>> = if let Some(probe_type) = probe_type {
+                    // 每个解析地址单独探测
+                    let host = target_host(&target_str);
+                    futures::future::join_all(
+                        target_info
+                            .resolved
+                            .iter()
+                            .map(|a| probe(&a.addr, &host, probe_type, &health_check)),
+                    )
+                    .await
+                } else {
+                    // 未配置探测的UDP目标：智能健康检查
+                    let result = if target_str.parse::<std::net::SocketAddr>().is_ok() {
                         // 直接IP:PORT格式，跳过检查（无法有效验证UDP服务）
                         Ok(Duration::from_millis(0))
                     } else {
@@ -384,66 +1185,114 @@ impl CommonManager {
                             Ok(_) => Ok(Duration::from_millis(0)), // DNS解析成功即可
                             Err(e) => Err(anyhow::anyhow!("UDP目标解析失败: {}", e)),
                         }
-                    }
-                } else {
-                    crate::utils::test_connection(&target_str).await
+                    };
+                    target_info
+                        .resolved
+                        .iter()
+                        .map(|_| match &result {
+                            Ok(d) => Ok(*d),
+                            Err(e) => Err(anyhow::anyhow!("{}", e)),
+                        })
+                        .collect()
                 };
 
-                let check_time = start.elapsed();
-                (target_str, target_info, result, check_time)
+                // 仅确认解析的UDP检查没有真实的往返，不作为RTT样本
+                let measured = probe_type.is_some();
+                (
+                    target_str,
+                    target_info,
+                    results,
+                    measured,
+                    health_check,
+                    interval,
+                )
             });
             tasks.push(task);
         }
 
         // 等待所有检查完成并统计结果
-        let mut success_count = 0;
-        let mut fail_count = 0;
         let mut status_changes = Vec::new();
 
         for task in tasks {
-            if let Ok((target_str, mut target_info, result, _check_time)) = task.await {
+            if let Ok((target_str, mut target_info, results, measured, health_check, interval)) =
+                task.await
+            {
                 let old_healthy = target_info.healthy;
+                let old_flapping = target_info.flapping;
+                let probe_ok = results.iter().any(|r| r.is_ok());
+
+                for (addr_info, result) in target_info.resolved.iter_mut().zip(results) {
+                    addr_info.record(
+                        result.ok(),
+                        health_check.get_rise(),
+                        health_check.get_fall(),
+                    );
+                }
 
-                match result {
-                    Ok(_) => {
-                        target_info.healthy = true;
-                        target_info.fail_count = 0; // 成功时重置失败计数
-                        target_info.last_check = Instant::now();
-                        success_count += 1;
+                target_info.record_probe(probe_ok, health_check.get_flap_threshold());
+                if target_info.flapping != old_flapping {
+                    if target_info.flapping {
+                        warn!("目标 {} 状态频繁翻转，暂停使用直到稳定", target_str);
+                    } else {
+                        info!("目标 {} 状态已稳定", target_str);
+                    }
+                }
 
-                        // 如果之前不健康，现在恢复了
-                        if !old_healthy {
-                            status_changes.push(format!("{} 恢复", target_str));
-                        }
+                // 下次检查时间加上随机抖动，错开各目标的探测
+                let jitter = health_check.get_jitter();
+                let jitter = if jitter > 0 {
+                    rand::thread_rng().gen_range(0..=jitter * 1000)
+                } else {
+                    0
+                };
+                target_info.next_check =
+                    now + Duration::from_secs(interval) + Duration::from_millis(jitter);
+
+                // 以最快的健康地址作为本轮RTT样本
+                let sample = target_info
+                    .resolved
+                    .iter()
+                    .filter(|a| a.healthy)
+                    .filter_map(|a| a.latency)
+                    .min();
+                if let (true, Some(sample)) = (measured, sample) {
+                    target_info.record_rtt(sample);
+                }
+
+                target_info.last_check = Instant::now();
+                if target_info.resolved.iter().any(|a| a.healthy) && !target_info.flapping {
+                    target_info.healthy = true;
+                    target_info.fail_count = 0;
+
+                    // 如果之前不健康，现在恢复了
+                    if !old_healthy {
+                        target_info.recovered_at = Some(Instant::now());
+                        status_changes.push(format!("{} 恢复", target_str));
                     }
-                    Err(_e) => {
-                        target_info.fail_count += 1;
-                        target_info.last_check = Instant::now();
-
-                        // 失败1次就标记为不健康，快速切换
-                        if target_info.fail_count >= 1 && old_healthy {
-                            target_info.healthy = false;
-                            status_changes.push(format!("{} 异常", target_str));
-                        }
+                } else {
+                    // 所有地址都失败（或目标抖动）才标记目标不健康
+                    target_info.fail_count += 1;
+                    target_info.healthy = false;
 
-                        // 统计时仍然按当前健康状态计算
-                        if target_info.healthy {
-                            success_count += 1;
-                        } else {
-                            fail_count += 1;
-                        }
+                    if old_healthy {
+                        status_changes.push(format!("{} 异常", target_str));
                     }
                 }
 
+                // 可疑标记保持到主动探测成功为止，探测失败则按 fall 规则继续判定
+                if probe_ok && passive.suspects.remove(&target_str).is_some() {
+                    info!("目标 {} 主动探测正常，解除可疑标记", target_str);
+                }
+
                 target_cache.insert(target_str, target_info);
             }
         }
 
-        // 生成状态摘要
-        let healthy_addresses = success_count;
-        let unhealthy_addresses = fail_count;
+        // 生成状态摘要（统计全部目标，不只是本轮检查的）
+        let healthy_addresses = target_cache.iter().filter(|t| t.healthy).count();
+        let unhealthy_addresses = target_cache.len() - healthy_addresses;
 
-        if !status_changes.is_empty() {
+        Some(if !status_changes.is_empty() {
             format!(
                 "{} 个地址健康，{} 个地址异常 [{}]",
                 healthy_addresses,
@@ -455,13 +1304,15 @@ impl CommonManager {
                 "{} 个地址健康，{} 个地址异常",
                 healthy_addresses, unhealthy_addresses
             )
-        }
+        })
     }
 
     async fn update_rule_targets(
         rule_infos: &Arc<RwLock<DashMap<String, RuleInfo>>>,
         target_cache: &Arc<DashMap<String, TargetInfo>>,
+        expanded_targets: &DashMap<String, Vec<ExpandedTarget>>,
         config: &Config,
+        target_states: &DashMap<String, TargetState>,
     ) {
         let rule_infos_write = rule_infos.write().await;
 
@@ -470,26 +1321,81 @@ impl CommonManager {
             let rule_info = entry.value_mut();
 
             // 获取当前规则的目标列表（直接从配置中查找）
-            let rule_targets =
-                if let Some(rule) = config.rules.iter().find(|r| r.name == *rule_name) {
-                    &rule.targets
-                } else {
-                    continue;
-                };
+            let Some(rule) = config.rules.iter().find(|r| r.name == *rule_name) else {
+                continue;
+            };
 
-            // 更新目标信息
-            let mut updated_targets = Vec::new();
-            for target_str in rule_targets {
-                if let Some(target_info) = target_cache.get(target_str) {
-                    updated_targets.push(target_info.clone());
+            // 更新目标信息；SRV/TXT目标展开为当前解析出的各个目标，权重和协议提示取自记录
+            let mut updated_targets: Vec<TargetInfo> = Vec::new();
+            let mut tier_keys = Vec::new();
+            for spec in &rule.targets {
+                let expanded: Vec<(String, Option<u32>, Option<String>, usize)> =
+                    match expanded_targets.get(&spec.address) {
+                        Some(targets) => targets
+                            .iter()
+                            .map(|t| {
+                                (
+                                    t.address.clone(),
+                                    t.weight.map(u32::from),
+                                    t.protocol.clone(),
+                                    srv_rank(&targets, t),
+                                )
+                            })
+                            .collect(),
+                        None => vec![(spec.address.clone(), None, None, 0)],
+                    };
+                for (address, weight, protocol, rank) in expanded {
+                    if updated_targets.iter().any(|t| t.original == address) {
+                        continue;
+                    }
+                    let Some(target_info) = target_cache.get(&address) else {
+                        continue;
+                    };
+                    let mut target_info = target_info.for_rule(spec);
+                    if let Some(weight) = weight {
+                        target_info.weight = weight.max(1);
+                    }
+                    target_info.protocol = protocol;
+                    if let Some(state) = target_states.get(&address) {
+                        target_info.state = *state;
+                    }
+                    tier_keys.push((rule.get_tier(&spec.address), rank));
+                    updated_targets.push(target_info);
                 }
             }
 
-            // 选择最佳目标（基于健康状态和配置优先级）
-            let new_selected_target = select_best_target_with_stickiness(
-                &updated_targets,
-                rule_info.selected_target.as_ref(),
-            );
+            // SRV优先级按从低到高的顺序在所在层级内再细分层级（TXT展开的目标优先级相同），优先使用优先级数值最小的目标
+            if rule.has_expanded_targets() {
+                let mut levels = tier_keys.clone();
+                levels.sort();
+                levels.dedup();
+                rule_info.tier_of = updated_targets
+                    .iter()
+                    .zip(&tier_keys)
+                    .map(|(target, key)| {
+                        let tier = levels.iter().position(|level| level == key).unwrap_or(0);
+                        (target.original.clone(), tier)
+                    })
+                    .collect();
+            }
+
+            // 更新规则信息，并按层级健康状态确定当前层级
+            rule_info.targets = updated_targets;
+            rule_info.last_update = Instant::now();
+            rule_info.update_active_tier(&rule_name);
+
+            // 在当前层级的启用目标中选择最佳目标（基于健康状态和配置优先级）
+            // 全部排空或停用时仍保留一个目标，规则照常监听，新连接由选择器拒绝
+            let mut candidates: Vec<_> = rule_info
+                .active_targets()
+                .into_iter()
+                .filter(|t| t.state == TargetState::Enabled)
+                .collect();
+            if candidates.is_empty() {
+                candidates = rule_info.active_targets();
+            }
+            let new_selected_target =
+                select_best_target_with_stickiness(&candidates, rule_info.selected_target.as_ref());
 
             // 检查是否需要更新目标
             let should_update = match (&rule_info.selected_target, &new_selected_target) {
@@ -498,16 +1404,18 @@ impl CommonManager {
                     true
                 }
                 (Some(old), Some(new)) => {
-                    // 比较新旧目标是否相同
-                    if old.resolved != new.resolved {
+                    // 比较新旧目标是否相同（目标或其地址列表变化都需要更新）
+                    if old.original != new.original || old.addrs() != new.addrs() {
                         info!(
                             "规则 {} 切换: {} -> {}",
-                            rule_name, old.resolved, new.resolved
+                            rule_name,
+                            format_addrs(&old.addrs()),
+                            format_addrs(&new.addrs())
                         );
                         true
                     } else {
-                        // 地址相同，不更新
-                        false
+                        // 地址相同时，仅在地址健康顺序变化时静默更新
+                        old.connect_order() != new.connect_order()
                     }
                 }
                 (Some(_old), None) => {
@@ -521,32 +1429,187 @@ impl CommonManager {
                 }
             };
 
-            // 更新规则信息
-            rule_info.targets = updated_targets;
-            rule_info.last_update = Instant::now();
-
             if should_update {
                 rule_info.selected_target = new_selected_target.clone();
             }
         }
     }
 
-    pub async fn get_best_target(&self, rule_name: &str) -> Result<SocketAddr> {
+    // 返回当前选中目标的地址列表（按连接顺序）
+    pub async fn get_best_target(&self, rule_name: &str) -> Result<Vec<TargetAddr>> {
         let rule_infos = self.rule_infos.read().await;
 
         if let Some(rule_info) = rule_infos.get(rule_name) {
             if let Some(target) = &rule_info.selected_target {
-                return Ok(target.resolved);
+                return Ok(target.connect_order());
             }
         }
 
         anyhow::bail!("没有可用的目标: {}", rule_name)
     }
 
+    pub fn selector(&self, rule_name: &str) -> TargetSelector {
+        let wants_host = self.config.rules.iter().any(|rule| {
+            rule.name == rule_name
+                && rule.get_strategy() == Strategy::SourceHash
+                && rule.get_hash_key() == HashKey::Sni
+        });
+        let rule = self.config.rules.iter().find(|rule| rule.name == rule_name);
+        TargetSelector {
+            rule_infos: self.rule_infos.clone(),
+            rule_name: rule_name.to_string(),
+            wants_host,
+            passive: self.passive.clone(),
+            passive_config: rule
+                .map(|rule| rule.get_health_check_config())
+                .unwrap_or_default()
+                .get_passive(),
+            connect_retries: rule.map_or(0, |rule| rule.get_connect_retries()),
+            connect_deadline: Duration::from_secs(
+                rule.map_or(10, |rule| rule.get_connect_deadline()),
+            ),
+            no_healthy: rule.and_then(|rule| rule.get_no_healthy_action()),
+            protocol: None,
+        }
+    }
+
+    // 切换目标的运维状态，立即刷新各规则的目标选择，并写入状态文件
+    pub async fn set_target_state(&self, target: &str, state: TargetState) -> Result<()> {
+        if !self
+            .config
+            .rules
+            .iter()
+            .any(|rule| rule.targets.iter().any(|spec| spec.address == target))
+            && !is_expanded_member(&self.expanded_targets, target)
+        {
+            anyhow::bail!("未知的目标: {}", target);
+        }
+
+        let old = if state == TargetState::Enabled {
+            self.target_states.remove(target).map(|(_, old)| old)
+        } else {
+            self.target_states.insert(target.to_string(), state)
+        }
+        .unwrap_or_default();
+        if old == state {
+            return Ok(());
+        }
+        info!(
+            "目标 {} 状态: {} -> {}",
+            target,
+            old.as_str(),
+            state.as_str()
+        );
+
+        // 停用期间没有健康检查，重新启用时立即探测一次
+        if old == TargetState::Disabled {
+            let _ = self.passive.probe_tx.send(target.to_string());
+        }
+        Self::update_rule_targets(
+            &self.rule_infos,
+            &self.target_cache,
+            &self.expanded_targets,
+            &self.config,
+            &self.target_states,
+        )
+        .await;
+        self.save_target_states()
+    }
+
+    // 所有已解析目标的运维状态和健康状态，按地址排序
+    pub fn target_states(&self) -> Vec<(String, TargetState, bool)> {
+        let mut states: Vec<_> = self
+            .target_cache
+            .iter()
+            .map(|entry| {
+                let state = self
+                    .target_states
+                    .get(entry.key())
+                    .map(|state| *state)
+                    .unwrap_or_default();
+                (entry.key().clone(), state, entry.healthy)
+            })
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    fn save_target_states(&self) -> Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let states: BTreeMap<_, _> = self
+            .target_states
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        std::fs::write(path, serde_json::to_string_pretty(&states)?)
+            .map_err(|e| anyhow::anyhow!("无法写入状态文件 {}: {}", path.display(), e))
+    }
+
     #[allow(dead_code)]
     pub async fn get_best_target_string(&self, rule_name: &str) -> Result<String> {
-        let addr = self.get_best_target(rule_name).await?;
-        Ok(addr.to_string())
+        let addrs = self.get_best_target(rule_name).await?;
+        Ok(format_addrs(&addrs))
+    }
+}
+
+// 读取上次运行保存的目标状态，忽略已不在配置中的目标
+fn load_target_states(path: &PathBuf, config: &Config) -> DashMap<String, TargetState> {
+    let states: HashMap<String, TargetState> = match std::fs::read_to_string(path) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(states) => states,
+            Err(e) => {
+                warn!("状态文件 {} 格式无效: {}", path.display(), e);
+                return DashMap::new();
+            }
+        },
+        // 首次运行时状态文件尚不存在
+        Err(_) => return DashMap::new(),
+    };
+
+    // SRV/TXT展开的目标在解析前未知，配置了这类目标时保留所有记录
+    let has_expanded = config.rules.iter().any(|rule| rule.has_expanded_targets());
+    let configured = |target: &str| {
+        has_expanded
+            || config
+                .rules
+                .iter()
+                .any(|rule| rule.targets.iter().any(|spec| spec.address == target))
+    };
+    states
+        .into_iter()
+        .filter(|(target, state)| *state != TargetState::Enabled && configured(target))
+        .inspect(|(target, state)| info!("恢复目标 {} 状态: {}", target, state.as_str()))
+        .collect()
+}
+
+// 目标是否由某个SRV/TXT目标展开而来
+fn is_expanded_member(
+    expanded_targets: &DashMap<String, Vec<ExpandedTarget>>,
+    target: &str,
+) -> bool {
+    expanded_targets
+        .iter()
+        .any(|entry| entry.value().iter().any(|t| t.address == target))
+}
+
+// SRV目标的优先级在同一SRV记录各优先级中的排名，0为最优先
+fn srv_rank(targets: &[ExpandedTarget], target: &ExpandedTarget) -> usize {
+    let mut priorities: Vec<_> = targets.iter().map(|t| t.priority).collect();
+    priorities.sort();
+    priorities.dedup();
+    priorities
+        .iter()
+        .position(|priority| *priority == target.priority)
+        .unwrap_or(0)
+}
+
+pub fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
     }
 }
 
@@ -565,12 +1628,12 @@ fn select_best_target_with_stickiness(
 
     // 2. 检查当前目标是否仍然健康
     if let Some(current) = current_target {
-        if healthy_targets
+        if let Some(target) = healthy_targets
             .iter()
-            .any(|t| t.resolved == current.resolved)
+            .find(|t| t.original == current.original)
         {
-            // 当前目标仍然健康，保持不变
-            return Some(current.clone());
+            // 当前目标仍然健康，保持不变（使用最新的地址健康状态）
+            return Some((*target).clone());
         }
     }
 
@@ -584,3 +1647,405 @@ fn select_best_target_with_stickiness(
     // 4. 无健康目标，返回第一个
     targets.first().cloned()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rise_fall_and_flap_dampening() {
+        let mut target = TargetInfo::new(
+            "10.0.0.1:80",
+            vec![TargetAddr::Inet("10.0.0.1:80".parse().unwrap())],
+        );
+        let ok = Some(Duration::from_millis(1));
+
+        // fall=2：一次失败不下线
+        target.resolved[0].record(None, 2, 2);
+        assert!(target.resolved[0].healthy);
+        target.resolved[0].record(None, 2, 2);
+        assert!(!target.resolved[0].healthy);
+        // rise=2：一次成功不恢复
+        target.resolved[0].record(ok, 2, 2);
+        assert!(!target.resolved[0].healthy);
+        target.resolved[0].record(ok, 2, 2);
+        assert!(target.resolved[0].healthy);
+
+        for success in [true, false, true, false] {
+            target.record_probe(success, 4);
+        }
+        assert!(!target.flapping);
+        target.record_probe(true, 4);
+        assert!(target.flapping);
+        // 稳定后翻转记录移出窗口，解除抖动
+        for _ in 0..PROBE_HISTORY_LEN {
+            target.record_probe(true, 4);
+        }
+        assert!(!target.flapping);
+    }
+
+    #[test]
+    fn test_passive_report_marks_suspect() {
+        let (probe_tx, mut probe_rx) = mpsc::unbounded_channel();
+        let selector = TargetSelector {
+            rule_infos: Arc::new(RwLock::new(DashMap::new())),
+            rule_name: "web".to_string(),
+            wants_host: false,
+            passive: Arc::new(PassiveState {
+                outcomes: DashMap::new(),
+                suspects: DashMap::new(),
+                probe_tx,
+            }),
+            passive_config: PassiveHealthConfig {
+                error_rate: Some(50),
+                min_requests: Some(4),
+                ..Default::default()
+            },
+            connect_retries: 0,
+            connect_deadline: Duration::from_secs(10),
+            no_healthy: None,
+            protocol: None,
+        };
+
+        // 样本不足 min_requests 时不判定
+        selector.report("a:80", false);
+        selector.report("a:80", false);
+        selector.report("a:80", true);
+        assert!(!selector.passive.is_suspect("a:80"));
+        assert!(probe_rx.try_recv().is_err());
+
+        // 4个样本中2个失败，达到50%阈值
+        selector.report("a:80", true);
+        assert!(selector.passive.is_suspect("a:80"));
+        assert_eq!(probe_rx.try_recv().unwrap(), "a:80");
+        assert!(!selector.passive.is_suspect("b:80"));
+
+        // 未配置 passive 时不启用被动检查
+        assert!(!HealthCheckConfig::default().get_passive().is_enabled());
+    }
+
+    #[test]
+    fn test_tier_failover_and_preempt_delay() {
+        let target =
+            |addr: &str| TargetInfo::new(addr, vec![TargetAddr::Inet(addr.parse().unwrap())]);
+        let mut rule_info = RuleInfo {
+            targets: vec![target("10.0.0.1:80"), target("10.0.0.2:80")],
+            selected_target: None,
+            last_update: Instant::now(),
+            balancer: Arc::new(Balancer::new(Strategy::Failover)),
+            tier_of: HashMap::from([
+                ("10.0.0.1:80".to_string(), 0),
+                ("10.0.0.2:80".to_string(), 1),
+            ]),
+            active_tier: 0,
+            tier_recovered_at: None,
+            preempt_delay: Duration::from_secs(60),
+        };
+
+        // 主层级全部异常，立即降级
+        rule_info.targets[0].healthy = false;
+        rule_info.update_active_tier("web");
+        assert_eq!(rule_info.active_tier, 1);
+        assert_eq!(rule_info.active_targets()[0].original, "10.0.0.2:80");
+
+        // 主层级恢复，未满抢占延迟前保持备用层级
+        rule_info.targets[0].healthy = true;
+        rule_info.update_active_tier("web");
+        assert_eq!(rule_info.active_tier, 1);
+        rule_info.tier_recovered_at = Some(Instant::now() - Duration::from_secs(61));
+        rule_info.update_active_tier("web");
+        assert_eq!(rule_info.active_tier, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_and_disabled_targets() {
+        let target =
+            |addr: &str| TargetInfo::new(addr, vec![TargetAddr::Inet(addr.parse().unwrap())]);
+        let mut targets = vec![target("10.0.0.1:80"), target("10.0.0.2:80")];
+        targets[0].state = TargetState::Drain;
+        let rule_infos = DashMap::new();
+        rule_infos.insert(
+            "web".to_string(),
+            RuleInfo {
+                targets,
+                selected_target: None,
+                last_update: Instant::now(),
+                balancer: Arc::new(Balancer::new(Strategy::RoundRobin)),
+                tier_of: HashMap::new(),
+                active_tier: 0,
+                tier_recovered_at: None,
+                preempt_delay: Duration::from_secs(30),
+            },
+        );
+        let (probe_tx, _probe_rx) = mpsc::unbounded_channel();
+        let selector = TargetSelector {
+            rule_infos: Arc::new(RwLock::new(rule_infos)),
+            rule_name: "web".to_string(),
+            wants_host: false,
+            passive: Arc::new(PassiveState {
+                outcomes: DashMap::new(),
+                suspects: DashMap::new(),
+                probe_tx,
+            }),
+            passive_config: PassiveHealthConfig::default(),
+            connect_retries: 0,
+            connect_deadline: Duration::from_secs(10),
+            no_healthy: None,
+            protocol: None,
+        };
+
+        // 排空的目标不接收新连接，已有会话继续可用
+        for _ in 0..4 {
+            let selection = selector.select(None, None).await.unwrap();
+            assert_eq!(selection.target, "10.0.0.2:80");
+        }
+        assert!(selector.is_available("10.0.0.1:80").await);
+
+        // 停用的目标需要迁移会话；全部不可用时拒绝新连接
+        {
+            let rule_infos = selector.rule_infos.read().await;
+            let mut rule_info = rule_infos.get_mut("web").unwrap();
+            rule_info.targets[0].state = TargetState::Disabled;
+            rule_info.targets[1].state = TargetState::Drain;
+        }
+        assert!(!selector.is_available("10.0.0.1:80").await);
+        assert!(selector.select(None, None).await.is_err());
+        assert!(selector.select_next(&[]).await.is_none());
+        assert!(!selector.has_healthy().await);
+    }
+
+    // 应用层探测结果按 fall/rise 更新地址和目标的健康状态
+    #[tokio::test]
+    async fn test_probe_results_update_target_health() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let up = Arc::new(AtomicBool::new(true));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let server_up = up.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0u8; 1024];
+                let _ = stream.read(&mut buffer).await;
+                let response: &[u8] = if server_up.load(Ordering::Relaxed) {
+                    b"HTTP/1.1 200 OK\r\n\r\nok"
+                } else {
+                    b"HTTP/1.1 503 Service Unavailable\r\n\r\n"
+                };
+                let _ = stream.write_all(response).await;
+            }
+        });
+
+        let config: Config = serde_yaml::from_str(&format!(
+            r#"
+logging: {{level: info, format: text}}
+network: {{listen_addr: "127.0.0.1"}}
+rules:
+  - name: web
+    listen_port: 8080
+    targets: ["{}"]
+    health_check: {{type: http, timeout: 1000, fall: 2, flap_threshold: 0}}
+"#,
+            target
+        ))
+        .unwrap();
+        let manager = CommonManager::new(config);
+        manager.initialize().await.unwrap();
+        let check = || {
+            CommonManager::batch_health_check(
+                &manager.target_cache,
+                &manager.expanded_targets,
+                &manager.config,
+                &manager.passive,
+                &manager.target_states,
+                true,
+            )
+        };
+        let state = || {
+            let info = manager.target_cache.get(&target).unwrap();
+            (info.healthy, info.fail_count, info.resolved[0].fail_count)
+        };
+        assert_eq!(state(), (true, 0, 0));
+
+        // fall=2：第一次失败只累计地址的失败次数
+        up.store(false, Ordering::Relaxed);
+        check().await;
+        assert_eq!(state(), (true, 0, 1));
+        check().await;
+        assert_eq!(state(), (false, 1, 2));
+        check().await;
+        assert_eq!(state(), (false, 2, 3));
+
+        // rise 默认为1，一次成功即恢复并清零失败计数
+        up.store(true, Ordering::Relaxed);
+        check().await;
+        assert_eq!(state(), (true, 0, 0));
+    }
+
+    // 调度间隔同时整除各规则的检查间隔和DNS刷新间隔
+    #[test]
+    fn test_health_check_tick_includes_dns_refresh() {
+        let tick = |interval: u64| {
+            let config: Config = serde_yaml::from_str(&format!(
+                r#"
+logging: {{level: info, format: text}}
+network: {{listen_addr: "127.0.0.1"}}
+rules:
+  - name: web
+    listen_port: 8080
+    targets: ["127.0.0.1:80"]
+    health_check: {{interval: {}}}
+"#,
+                interval
+            ))
+            .unwrap();
+            CommonManager::health_check_tick(&config)
+        };
+        assert_eq!(tick(10), 5);
+        assert_eq!(tick(30), 15);
+        assert_eq!(tick(60), 15);
+        assert_eq!(tick(7), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expanded_targets_expand_by_priority_and_weight() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+logging: {level: info, format: text}
+network: {listen_addr: "127.0.0.1"}
+rules:
+  - name: web
+    listen_port: 8080
+    targets: ["srv:_web._tcp.example.com", "backup.example.com:80"]
+"#,
+        )
+        .unwrap();
+        let rule = &config.rules[0];
+        assert_eq!(rule.get_strategy(), Strategy::WeightedRoundRobin);
+
+        let srv_target = |address: &str, priority, weight| ExpandedTarget {
+            address: address.to_string(),
+            priority,
+            weight: Some(weight),
+            protocol: None,
+            addrs: vec![TargetAddr::Inet("127.0.0.1:80".parse().unwrap())],
+        };
+        let spec = "srv:_web._tcp.example.com";
+        let target_cache = Arc::new(DashMap::new());
+        target_cache.insert(
+            "backup.example.com:80".to_string(),
+            TargetInfo::new("backup.example.com:80", Vec::new()),
+        );
+        let expanded_targets = DashMap::new();
+        CommonManager::apply_expanded_targets(
+            spec,
+            vec![
+                srv_target("a.example.com:80", 10, 3),
+                srv_target("b.example.com:80", 10, 0),
+                srv_target("c.example.com:80", 20, 1),
+            ],
+            &target_cache,
+            &expanded_targets,
+            &config,
+        );
+
+        let rule_infos = Arc::new(RwLock::new(DashMap::new()));
+        rule_infos.read().await.insert(
+            "web".to_string(),
+            RuleInfo {
+                targets: Vec::new(),
+                selected_target: None,
+                last_update: Instant::now(),
+                balancer: Arc::new(Balancer::from_rule(rule)),
+                tier_of: HashMap::new(),
+                active_tier: 0,
+                tier_recovered_at: None,
+                preempt_delay: Duration::from_secs(30),
+            },
+        );
+        let target_states = DashMap::new();
+        let update = || {
+            CommonManager::update_rule_targets(
+                &rule_infos,
+                &target_cache,
+                &expanded_targets,
+                &config,
+                &target_states,
+            )
+        };
+
+        // 最小优先级的SRV目标与普通目标同层，权重取自SRV记录（0按1处理）
+        update().await;
+        {
+            let rule_infos = rule_infos.read().await;
+            let rule_info = rule_infos.get("web").unwrap();
+            let active: Vec<_> = rule_info
+                .active_targets()
+                .iter()
+                .map(|t| (t.original.clone(), t.weight))
+                .collect();
+            assert_eq!(
+                active,
+                vec![
+                    ("a.example.com:80".to_string(), 3),
+                    ("b.example.com:80".to_string(), 1),
+                    ("backup.example.com:80".to_string(), 1),
+                ]
+            );
+            assert_eq!(rule_info.tier("c.example.com:80"), 1);
+        }
+
+        // 重新解析后消失的目标从缓存和规则中移除
+        CommonManager::apply_expanded_targets(
+            spec,
+            vec![srv_target("a.example.com:80", 10, 3)],
+            &target_cache,
+            &expanded_targets,
+            &config,
+        );
+        update().await;
+        assert!(!target_cache.contains_key("b.example.com:80"));
+        let rule_infos = rule_infos.read().await;
+        assert_eq!(rule_infos.get("web").unwrap().targets.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_skips_full_target() {
+        let target = |addr: &str, max_connections| {
+            let mut target = TargetInfo::new(addr, vec![TargetAddr::Inet(addr.parse().unwrap())]);
+            target.max_connections = max_connections;
+            target
+        };
+        let selector = TargetSelector::with_targets(
+            vec![target("10.0.0.1:80", Some(1)), target("10.0.0.2:80", None)],
+            Strategy::Failover,
+            0,
+            Duration::from_secs(10),
+        );
+
+        // 达到上限后新连接跳过该目标，连接结束后恢复
+        let first = selector.select(None, None).await.unwrap();
+        assert_eq!(first.target, "10.0.0.1:80");
+        let second = selector.select(None, None).await.unwrap();
+        assert_eq!(second.target, "10.0.0.2:80");
+        assert!(selector
+            .select_next(&["10.0.0.2:80".to_string()])
+            .await
+            .is_none());
+        drop(first);
+        assert_eq!(
+            selector.select(None, None).await.unwrap().target,
+            "10.0.0.1:80"
+        );
+
+        // 全部目标都达到上限时拒绝新连接
+        let selector = TargetSelector::with_targets(
+            vec![target("10.0.0.1:80", Some(1))],
+            Strategy::RoundRobin,
+            0,
+            Duration::from_secs(10),
+        );
+        let _held = selector.select(None, None).await.unwrap();
+        assert!(selector.select(None, None).await.is_err());
+    }
+}
@@ -1,16 +1,30 @@
+use crate::balancer::{HashKey, Strategy};
+use crate::knock::parse_knock_step;
+use crate::listener::{parse_listen_addrs, ListenAddr};
+use crate::probe::{parse_payload, ProbeType};
+use crate::sniff::SniffProtocol;
+use crate::transparent::parse_cidr;
+use crate::txt::TxtKey;
+use crate::utils::{is_expanded_addr, is_srv_addr, is_unix_addr, parse_upstream_spec};
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
 use std::path::Path;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub logging: LoggingConfig,
     pub network: NetworkConfig,
     pub buffer_size: Option<usize>,
+    #[serde(default)]
     pub rules: Vec<ForwardRule>,
     pub dynamic_update: Option<DynamicUpdateConfig>,
+    pub tunnel: Option<TunnelConfig>, // 反向隧道：服务端和/或客户端
+    pub control: Option<ControlConfig>, // 本地控制接口：运行时排空/停用目标
+    pub dns: Option<DnsResolverConfig>, // 目标域名解析使用的DNS服务器
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,21 +42,229 @@ pub struct NetworkConfig {
 pub struct ForwardRule {
     pub name: String,
     pub listen_port: u16,
+    #[serde(default, deserialize_with = "deserialize_string_or_list")]
+    pub listen: Option<Vec<String>>, // 规则级监听地址列表：IP、网卡名或 unix:/path
     pub protocol: Option<String>,       // 保持向后兼容
     pub protocols: Option<Vec<String>>, // 新增：支持多协议
     pub buffer_size: Option<usize>,
-    pub targets: Vec<String>,
+    #[serde(default)]
+    pub targets: Vec<TargetSpec>, // 目标地址字符串，或带 weight/max_connections 的对象
+    pub tiers: Option<Vec<Vec<TargetSpec>>>, // 按优先级分层的目标，与 targets 二选一
+    pub preempt_delay: Option<u64>,          // 高层级恢复后持续健康多久（秒）才切回
+    pub slow_start: Option<u64>,             // 目标恢复后权重从低到满线性爬升的时长（秒）
     pub dynamic_update: Option<DynamicUpdateConfig>,
+    pub dns: Option<DnsRuleConfig>, // protocol: dns 时的转发设置，targets 为上游DNS
+    pub transparent: Option<TransparentConfig>, // 透明代理：目标取自连接的原始目的地址
+    pub reverse: Option<bool>, // 反向隧道：由隧道服务端监听 listen_port，连接经隧道回到本机 targets
+    pub sniff: Option<SniffConfig>, // 协议嗅探：同一端口按首批字节识别的协议分流
+    pub knock: Option<KnockConfig>, // 敲门放行：完成端口敲门或SPA认证前静默丢弃连接
+    pub strategy: Option<String>, // 负载均衡策略，默认 failover
+    pub latency_margin: Option<u32>, // lowest_latency：新目标RTT需低出的百分比才切换
+    pub hash_key: Option<String>, // source_hash 的哈希键：ip（默认）/ ip_port / sni
+    pub health_check: Option<HealthCheckConfig>,
+    pub connect_retries: Option<u32>, // 连接目标失败时按目标顺序改连下一个目标的次数
+    pub connect_deadline: Option<u64>, // 含重试在内的总连接时限（秒）
+    pub on_no_healthy_target: Option<NoHealthyTargetConfig>, // 没有健康目标时的处理，不配置时仍尝试连接异常目标
+    pub resolver: Option<DnsResolverConfig>, // 规则级解析器，未配置的字段沿用全局 dns
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// 规则目标：可直接写地址字符串，也可写成对象指定权重和最大连接数
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TargetSpec {
+    pub address: String,
+    pub weight: Option<u32>,            // 加权策略使用的权重，默认1
+    pub max_connections: Option<usize>, // 活跃连接数上限，达到后新连接跳过该目标
+}
+
+impl<'de> Deserialize<'de> for TargetSpec {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Detailed {
+            address: String,
+            weight: Option<u32>,
+            max_connections: Option<usize>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum AddressOrDetailed {
+            Address(String),
+            Detailed(Detailed),
+        }
+
+        Ok(match AddressOrDetailed::deserialize(deserializer)? {
+            AddressOrDetailed::Address(address) => TargetSpec::from(address.as_str()),
+            AddressOrDetailed::Detailed(detailed) => TargetSpec {
+                address: detailed.address,
+                weight: detailed.weight,
+                max_connections: detailed.max_connections,
+            },
+        })
+    }
+}
+
+impl From<&str> for TargetSpec {
+    fn from(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            weight: None,
+            max_connections: None,
+        }
+    }
+}
+
+impl TargetSpec {
+    pub fn get_weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DynamicUpdateConfig {
     pub check_interval: Option<u64>,
-    pub connection_timeout: Option<u64>,
+    pub connection_timeout: Option<u64>, // TCP连接空闲超时（秒），0为不限制
+    pub udp_timeout: Option<u64>,        // UDP会话空闲超时（秒）
+    pub max_lifetime: Option<u64>,       // 连接最长存活时间（秒），0为不限制
     pub auto_reconnect: Option<bool>,
     // 移除 health_check_interval，使用统一的 check_interval
 }
 
+// 解析目标域名(A/AAAA/TXT)的DNS服务器，支持 system、UDP/TCP、DoT 和 DoH
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DnsResolverConfig {
+    #[serde(default, deserialize_with = "deserialize_string_or_list")]
+    pub resolvers: Option<Vec<String>>, // system 为系统DNS配置，其余写法同 dns 规则的上游
+    pub timeout: Option<u64>,     // 单次查询超时（秒）
+    pub attempts: Option<usize>,  // 查询失败后的重试次数
+    pub ordering: Option<String>, // sequential：按配置顺序；fastest：优先响应快的服务器
+    pub txt_key: Option<String>, // TXT目标记录的签名校验密钥：hmac:<密钥> 或 ed25519:<十六进制公钥>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DnsRuleConfig {
+    pub overrides: Option<HashMap<String, Vec<String>>>, // 静态解析：域名 -> IP列表
+    pub override_ttl: Option<u32>,
+    pub cache_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransparentConfig {
+    pub mode: Option<String>, // redirect（默认，iptables REDIRECT）或 tproxy（iptables TPROXY）
+    pub routes: Option<Vec<TransparentRoute>>,
+}
+
+// 按原始目的地址匹配的路由，未匹配时直接连接原始目的地址
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransparentRoute {
+    pub dst: Option<String>, // CIDR或IP，不填匹配全部地址
+    pub ports: Option<Vec<u16>>,
+    pub targets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SniffConfig {
+    pub timeout_ms: Option<u64>, // 等待客户端首批数据的时间，超时按 unknown 处理
+    pub routes: Option<HashMap<String, SniffRoute>>, // tls/http/ssh/socks/unknown -> 目标
+}
+
+// 嗅探路由：目标列表，或内置处理器（redirect：HTTP跳转HTTPS）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SniffRoute {
+    Targets(Vec<String>),
+    Handler(String),
+}
+
+// 目标健康检查参数，同一目标出现在多个规则中时以最后一个规则的配置为准
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    pub interval: Option<u64>, // 检查间隔（秒），默认取 dynamic_update.check_interval
+    pub timeout: Option<u64>,  // 单次探测超时（毫秒）
+    pub rise: Option<u32>,     // 连续成功多少次后恢复
+    pub fall: Option<u32>,     // 连续失败多少次后判定异常
+    pub jitter: Option<u64>,   // 每次检查随机延后 0~jitter 秒
+    pub flap_threshold: Option<u32>, // 最近10次结果中状态翻转达到该次数视为抖动，0为关闭
+    pub passive: Option<PassiveHealthConfig>, // 基于真实流量的被动检查
+    #[serde(rename = "type")]
+    pub check_type: Option<String>, // 探测类型：tcp / http / https / tls / udp，默认仅建立TCP连接
+    pub path: Option<String>,  // http(s)：请求路径，默认 /
+    pub host: Option<String>,  // http(s)/tls：Host头和SNI，默认取目标域名
+    pub expect_status: Option<u16>, // http(s)：期望状态码，默认 2xx/3xx
+    pub expect_body: Option<String>, // http(s)：响应体需包含的内容
+    pub send: Option<String>,  // tcp/udp：发送的载荷，hex: 前缀按十六进制
+    pub expect: Option<String>, // tcp/udp：应答需包含的内容
+    pub tls_verify: Option<bool>, // https/tls：是否校验证书，默认只要求握手成功
+}
+
+// 真实连接的失败率超过阈值时将目标标记为可疑，并立即触发一次主动探测
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PassiveHealthConfig {
+    pub enabled: Option<bool>,
+    pub error_rate: Option<u32>,   // 失败率阈值（百分比）
+    pub min_requests: Option<u32>, // 窗口内至少多少个连接才计算失败率
+    pub window: Option<u64>,       // 统计窗口（秒）
+}
+
+// 规则全部目标异常（或均已排空、停用）时对新连接的处理方式
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NoHealthyTargetConfig {
+    pub action: String,           // reject / hold / static_response / fallback_target
+    pub hold: Option<u64>,        // hold：等待目标恢复的最长时间（秒）
+    pub response: Option<String>, // static_response：返回的内容，hex: 前缀按十六进制
+    pub target: Option<String>,   // fallback_target：兜底目标地址
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoHealthyAction {
+    Reject,                  // 立即以RST关闭连接
+    Hold(Duration),          // 等待目标恢复，超时后关闭
+    StaticResponse(Vec<u8>), // 返回固定内容后关闭，例如HTTP维护页
+    FallbackTarget(String),  // 转发到兜底目标
+}
+
+// 端口敲门序列与单包授权(SPA)可同时配置，任一方式通过即放行来源IP
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KnockConfig {
+    pub sequence: Option<Vec<String>>, // 按顺序敲击的端口，例如 udp:7001、tcp:7002
+    pub spa_port: Option<u16>,         // 接收SPA数据包的UDP端口
+    pub secret: Option<String>,        // SPA签名密钥
+    pub open_seconds: Option<u64>,     // 放行时长（秒）
+    pub step_timeout: Option<u64>,     // 敲门序列相邻两步的最大间隔（秒）
+}
+
+// 本地控制接口，smart-forward --ctl 通过它切换目标状态
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ControlConfig {
+    pub listen: Option<String>, // 监听地址：IP:端口 或 unix:/路径，默认 127.0.0.1:7070
+    pub state_file: Option<String>, // 目标状态保存文件，不配置时重启后全部恢复为 enabled
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TunnelConfig {
+    pub server: Option<TunnelServerConfig>,
+    pub client: Option<TunnelClientConfig>,
+}
+
+// 公网实例：接受客户端注册，并在 network.listen_addr 上开放注册的端口
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelServerConfig {
+    pub listen: String, // 隧道控制端口，例如 0.0.0.0:7000
+    pub token: String,
+    pub allow_ports: Option<Vec<u16>>, // 允许注册的公网端口，不填不限制
+}
+
+// NAT后实例：主动连接服务端，注册 reverse: true 的规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelClientConfig {
+    pub server_addr: String,
+    pub token: String,
+    pub client_id: Option<String>,
+    pub max_backoff: Option<u64>, // 重连退避上限（秒）
+}
+
 impl Config {
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let content = fs::read_to_string(path)?;
@@ -62,10 +284,22 @@ impl Config {
             config.dynamic_update = Some(DynamicUpdateConfig {
                 check_interval: Some(15),      // 缩短到15秒，与健康检查保持一致
                 connection_timeout: Some(300), // 5分钟连接超时
-                auto_reconnect: Some(true),    // 默认开启自动重连
+                udp_timeout: None,
+                max_lifetime: None,
+                auto_reconnect: Some(true), // 默认开启自动重连
             });
         }
 
+        // 分层目标按层级顺序展开为 targets，其余逻辑统一按 targets 处理
+        for rule in &mut config.rules {
+            if let Some(tiers) = &rule.tiers {
+                if !rule.targets.is_empty() {
+                    anyhow::bail!("规则 {}: targets 和 tiers 不能同时配置", rule.name);
+                }
+                rule.targets = tiers.concat();
+            }
+        }
+
         // 验证配置
         config.validate()?;
 
@@ -73,20 +307,93 @@ impl Config {
     }
 
     pub fn validate(&self) -> Result<()> {
-        if self.rules.is_empty() {
+        // 隧道服务端可以不配置本地规则，端口由客户端注册
+        if self.rules.is_empty() && self.get_tunnel_server().is_none() {
             anyhow::bail!("至少需要配置一个转发规则");
         }
 
+        if let Some(server) = self.get_tunnel_server() {
+            if server.token.is_empty() {
+                anyhow::bail!("隧道服务端 token 不能为空");
+            }
+            if server.listen.parse::<std::net::SocketAddr>().is_err() {
+                anyhow::bail!("隧道服务端监听地址无效: {}", server.listen);
+            }
+        }
+        if let Some(client) = self.get_tunnel_client() {
+            if client.token.is_empty() {
+                anyhow::bail!("隧道客户端 token 不能为空");
+            }
+            if client.server_addr.is_empty() {
+                anyhow::bail!("隧道客户端 server_addr 不能为空");
+            }
+        }
+        let dns = self.get_dns_config();
+        if let Err(e) = dns.check() {
+            anyhow::bail!("dns: {}", e);
+        }
+        // 同一目标只能用一套解析器解析，否则DNS刷新结果不确定
+        let mut target_resolvers: HashMap<String, (&str, DnsResolverConfig)> = HashMap::new();
+        for rule in &self.rules {
+            let resolver = rule.get_resolver_config(&dns);
+            if rule.resolver.is_some() {
+                if let Err(e) = resolver.check() {
+                    anyhow::bail!("规则 {}: resolver: {}", rule.name, e);
+                }
+            }
+            for target in rule.get_resolved_targets() {
+                match target_resolvers.get(&target) {
+                    Some((other, other_resolver)) if *other_resolver != resolver => {
+                        anyhow::bail!(
+                            "目标 {} 在规则 {} 和 {} 中使用了不同的解析器",
+                            target,
+                            other,
+                            rule.name
+                        );
+                    }
+                    Some(_) => {}
+                    None => {
+                        target_resolvers.insert(target, (&rule.name, resolver.clone()));
+                    }
+                }
+            }
+        }
+
+        if let Some(control) = self.get_control() {
+            let listen = control.get_listen();
+            if !is_unix_addr(&listen) {
+                let Ok(addr) = listen.parse::<std::net::SocketAddr>() else {
+                    anyhow::bail!("控制接口监听地址无效: {}", listen);
+                };
+                // 控制接口没有认证，只允许监听本机地址
+                if !addr.ip().is_loopback() {
+                    anyhow::bail!(
+                        "控制接口只能监听本机地址（127.0.0.1/::1）或Unix套接字: {}",
+                        listen
+                    );
+                }
+            }
+        }
+
         for (i, rule) in self.rules.iter().enumerate() {
             if rule.name.is_empty() {
                 anyhow::bail!("规则 {}: 名称不能为空", i + 1);
             }
 
-            if rule.listen_port == 0 {
+            if rule.listen_port == 0 && !rule.listens_only_on_unix() {
                 anyhow::bail!("规则 {}: 端口号不能为0", rule.name);
             }
 
-            if rule.targets.is_empty() {
+            if let Err(e) = rule.get_listen_addrs(&self.network.listen_addr) {
+                anyhow::bail!("规则 {}: {}", rule.name, e);
+            }
+
+            let dynamic_update = rule.get_dynamic_update_config(&self.get_dynamic_update_config());
+            if dynamic_update.get_udp_timeout() == 0 {
+                anyhow::bail!("规则 {}: udp_timeout 必须大于0", rule.name);
+            }
+
+            if rule.targets.is_empty() && !rule.is_transparent() {
                 anyhow::bail!("规则 {}: 至少需要一个目标", rule.name);
             }
 
@@ -105,17 +412,235 @@ impl Config {
                     }
                 }
             }
+
+            // DNS转发独占监听端口的TCP和UDP
+            if rule.is_dns() {
+                if rule.get_protocols().len() > 1 {
+                    anyhow::bail!("规则 {}: dns协议不能与其他协议同时使用", rule.name);
+                }
+                for (domain, ips) in rule.get_dns_config().get_overrides() {
+                    for ip in ips {
+                        if ip.parse::<std::net::IpAddr>().is_err() {
+                            anyhow::bail!(
+                                "规则 {}: 静态解析 {} 的IP无效: {}",
+                                rule.name,
+                                domain,
+                                ip
+                            );
+                        }
+                    }
+                }
+            }
+
+            if rule.is_reverse() {
+                if self.get_tunnel_client().is_none() {
+                    anyhow::bail!("规则 {}: reverse 规则需要配置 tunnel.client", rule.name);
+                }
+                if rule.is_transparent() || rule.has_unix_listen() {
+                    anyhow::bail!(
+                        "规则 {}: reverse 规则不支持透明代理和Unix套接字监听",
+                        rule.name
+                    );
+                }
+                if rule.get_protocols().iter().any(|p| p != "tcp") {
+                    anyhow::bail!("规则 {}: reverse 规则仅支持tcp协议", rule.name);
+                }
+            }
+
+            if rule.sniff.is_some() {
+                if let Err(e) = rule.validate_sniff() {
+                    anyhow::bail!("规则 {}: {}", rule.name, e);
+                }
+            }
+
+            if let Some(strategy) = &rule.strategy {
+                if Strategy::from_name(strategy).is_none() {
+                    anyhow::bail!("规则 {}: 不支持的负载均衡策略 {}", rule.name, strategy);
+                }
+                if !rule.has_managed_targets() {
+                    anyhow::bail!("规则 {}: DNS和透明代理规则不支持 strategy", rule.name);
+                }
+            }
+            if rule.slow_start.is_some()
+                && !matches!(
+                    rule.get_strategy(),
+                    Strategy::WeightedRoundRobin | Strategy::LeastConnections
+                )
+            {
+                anyhow::bail!(
+                    "规则 {}: slow_start 仅支持 weighted_round_robin 和 least_connections",
+                    rule.name
+                );
+            }
+            if rule.get_latency_margin() >= 100 {
+                anyhow::bail!("规则 {}: latency_margin 必须小于100", rule.name);
+            }
+            if let Some(health_check) = &rule.health_check {
+                let zero = |value: Option<u64>| value == Some(0);
+                if zero(health_check.interval)
+                    || zero(health_check.timeout)
+                    || health_check.rise == Some(0)
+                    || health_check.fall == Some(0)
+                {
+                    anyhow::bail!(
+                        "规则 {}: health_check 的 interval/timeout/rise/fall 必须大于0",
+                        rule.name
+                    );
+                }
+                if let Some(check_type) = &health_check.check_type {
+                    let Some(probe_type) = ProbeType::from_name(check_type) else {
+                        anyhow::bail!("规则 {}: 不支持的健康检查类型 {}", rule.name, check_type);
+                    };
+                    if probe_type == ProbeType::Udp && health_check.send.is_none() {
+                        anyhow::bail!("规则 {}: udp 健康检查需要配置 send", rule.name);
+                    }
+                    // Unix套接字目标没有主机名可作为SNI，TLS握手无从校验
+                    if matches!(probe_type, ProbeType::Tls | ProbeType::Https)
+                        && rule.has_unix_target()
+                    {
+                        anyhow::bail!(
+                            "规则 {}: {} 健康检查不支持Unix套接字目标",
+                            rule.name,
+                            check_type
+                        );
+                    }
+                }
+                for payload in [&health_check.send, &health_check.expect]
+                    .into_iter()
+                    .flatten()
+                {
+                    parse_payload(payload)
+                        .map_err(|e| anyhow::anyhow!("规则 {}: {}", rule.name, e))?;
+                }
+                if let Some(path) = &health_check.path {
+                    if !path.starts_with('/') {
+                        anyhow::bail!("规则 {}: health_check.path 必须以 / 开头", rule.name);
+                    }
+                }
+                if let Some(passive) = &health_check.passive {
+                    let error_rate = passive.get_error_rate();
+                    if error_rate == 0 || error_rate > 100 {
+                        anyhow::bail!("规则 {}: passive.error_rate 必须在1~100之间", rule.name);
+                    }
+                    if passive.min_requests == Some(0) || passive.window == Some(0) {
+                        anyhow::bail!(
+                            "规则 {}: passive 的 min_requests/window 必须大于0",
+                            rule.name
+                        );
+                    }
+                }
+            }
+
+            for spec in &rule.targets {
+                if spec.weight == Some(0) || spec.max_connections == Some(0) {
+                    anyhow::bail!(
+                        "规则 {}: 目标 {} 的 weight/max_connections 必须大于0",
+                        rule.name,
+                        spec.address
+                    );
+                }
+                if is_srv_addr(&spec.address) {
+                    if spec.address.trim_start_matches("srv:").is_empty() {
+                        anyhow::bail!("规则 {}: SRV目标缺少记录名: {}", rule.name, spec.address);
+                    }
+                    if spec.weight.is_some() {
+                        anyhow::bail!(
+                            "规则 {}: SRV目标 {} 的权重取自SRV记录，不能配置 weight",
+                            rule.name,
+                            spec.address
+                        );
+                    }
+                }
+            }
+
+            if let Some(tiers) = &rule.tiers {
+                if tiers.iter().any(|tier| tier.is_empty()) {
+                    anyhow::bail!("规则 {}: tiers 中的每一层至少需要一个目标", rule.name);
+                }
+                if !rule.has_managed_targets() {
+                    anyhow::bail!("规则 {}: DNS和透明代理规则不支持 tiers", rule.name);
+                }
+            }
+
+            if rule.connect_retries.is_some() && !rule.has_managed_targets() {
+                anyhow::bail!(
+                    "规则 {}: DNS和透明代理规则不支持 connect_retries",
+                    rule.name
+                );
+            }
+            if rule.connect_deadline == Some(0) {
+                anyhow::bail!("规则 {}: connect_deadline 必须大于0", rule.name);
+            }
+
+            if let Some(no_healthy) = &rule.on_no_healthy_target {
+                if !rule.has_managed_targets() || rule.is_reverse() {
+                    anyhow::bail!(
+                        "规则 {}: on_no_healthy_target 不支持DNS、透明代理和反向隧道规则",
+                        rule.name
+                    );
+                }
+                if let Err(e) = no_healthy.get_action() {
+                    anyhow::bail!("规则 {}: {}", rule.name, e);
+                }
+            }
+
+            if let Some(hash_key) = &rule.hash_key {
+                if HashKey::from_name(hash_key).is_none() {
+                    anyhow::bail!("规则 {}: 不支持的 hash_key {}", rule.name, hash_key);
+                }
+            }
+
+            if rule.knock.is_some() {
+                if let Err(e) = rule.validate_knock() {
+                    anyhow::bail!("规则 {}: {}", rule.name, e);
+                }
+            }
+
+            if rule.is_transparent() {
+                if let Err(e) = rule.validate_transparent() {
+                    anyhow::bail!("规则 {}: {}", rule.name, e);
+                }
+            }
+
+            // Unix套接字只能承载流式转发
+            if rule.has_unix_listen() || rule.has_unix_target() {
+                if let Some(protocol) = rule.get_protocols().iter().find(|p| *p != "tcp") {
+                    anyhow::bail!(
+                        "规则 {}: Unix套接字仅支持tcp协议，不支持 {}",
+                        rule.name,
+                        protocol
+                    );
+                }
+            }
         }
 
         Ok(())
     }
 
+    pub fn get_tunnel_server(&self) -> Option<&TunnelServerConfig> {
+        self.tunnel.as_ref().and_then(|t| t.server.as_ref())
+    }
+
+    pub fn get_tunnel_client(&self) -> Option<&TunnelClientConfig> {
+        self.tunnel.as_ref().and_then(|t| t.client.as_ref())
+    }
+
+    pub fn get_control(&self) -> Option<&ControlConfig> {
+        self.control.as_ref()
+    }
+
+    pub fn get_dns_config(&self) -> DnsResolverConfig {
+        self.dns.clone().unwrap_or_default()
+    }
+
     // 获取动态更新配置（优化的内置默认值）
     pub fn get_dynamic_update_config(&self) -> DynamicUpdateConfig {
         self.dynamic_update.clone().unwrap_or(DynamicUpdateConfig {
             check_interval: Some(15),      // 缩短到15秒，与健康检查保持一致
             connection_timeout: Some(300), // 5分钟连接超时
-            auto_reconnect: Some(true),    // 默认开启自动重连
+            udp_timeout: None,
+            max_lifetime: None,
+            auto_reconnect: Some(true), // 默认开启自动重连
         })
     }
 }
@@ -129,18 +654,232 @@ impl DynamicUpdateConfig {
         self.connection_timeout.unwrap_or(300)
     }
 
+    pub fn get_udp_timeout(&self) -> u64 {
+        self.udp_timeout.unwrap_or(60)
+    }
+
+    pub fn get_max_lifetime(&self) -> u64 {
+        self.max_lifetime.unwrap_or(0)
+    }
+
     pub fn get_auto_reconnect(&self) -> bool {
         self.auto_reconnect.unwrap_or(true)
     }
 }
 
+impl DnsResolverConfig {
+    pub fn get_resolvers(&self) -> Vec<String> {
+        self.resolvers
+            .clone()
+            .unwrap_or_else(|| vec!["system".to_string()])
+    }
+
+    pub fn get_timeout(&self) -> u64 {
+        self.timeout.unwrap_or(5)
+    }
+
+    pub fn get_attempts(&self) -> usize {
+        self.attempts.unwrap_or(2)
+    }
+
+    pub fn get_ordering(&self) -> String {
+        self.ordering
+            .clone()
+            .unwrap_or_else(|| "sequential".to_string())
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.get_resolvers().is_empty() {
+            anyhow::bail!("resolvers 不能为空");
+        }
+        for resolver in self.get_resolvers() {
+            if resolver != "system" {
+                parse_upstream_spec(&resolver)?;
+            }
+        }
+        if self.get_timeout() == 0 {
+            anyhow::bail!("timeout 必须大于0");
+        }
+        if !matches!(self.get_ordering().as_str(), "sequential" | "fastest") {
+            anyhow::bail!("不支持的 ordering: {}", self.get_ordering());
+        }
+        if let Some(key) = &self.txt_key {
+            TxtKey::parse(key)?;
+        }
+        Ok(())
+    }
+}
+
+impl DnsRuleConfig {
+    pub fn get_overrides(&self) -> HashMap<String, Vec<String>> {
+        self.overrides.clone().unwrap_or_default()
+    }
+
+    pub fn get_override_ttl(&self) -> u32 {
+        self.override_ttl.unwrap_or(60)
+    }
+
+    pub fn get_cache_size(&self) -> usize {
+        self.cache_size.unwrap_or(1024)
+    }
+}
+
+impl SniffConfig {
+    pub fn get_timeout_ms(&self) -> u64 {
+        self.timeout_ms.unwrap_or(300)
+    }
+
+    pub fn get_routes(&self) -> HashMap<String, SniffRoute> {
+        self.routes.clone().unwrap_or_default()
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn get_interval(&self, dynamic_update: &DynamicUpdateConfig) -> u64 {
+        self.interval
+            .unwrap_or_else(|| dynamic_update.get_check_interval())
+    }
+
+    pub fn get_timeout(&self) -> u64 {
+        self.timeout.unwrap_or(5000)
+    }
+
+    pub fn get_rise(&self) -> u32 {
+        self.rise.unwrap_or(1)
+    }
+
+    pub fn get_fall(&self) -> u32 {
+        self.fall.unwrap_or(1)
+    }
+
+    pub fn get_jitter(&self) -> u64 {
+        self.jitter.unwrap_or(0)
+    }
+
+    pub fn get_flap_threshold(&self) -> u32 {
+        self.flap_threshold.unwrap_or(4)
+    }
+
+    // 只有配置了 passive 时才启用被动检查
+    pub fn get_passive(&self) -> PassiveHealthConfig {
+        self.passive.clone().unwrap_or(PassiveHealthConfig {
+            enabled: Some(false),
+            ..Default::default()
+        })
+    }
+
+    pub fn get_probe_type(&self) -> Option<ProbeType> {
+        self.check_type.as_deref().and_then(ProbeType::from_name)
+    }
+
+    pub fn get_path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/")
+    }
+
+    pub fn status_matches(&self, status: u16) -> bool {
+        match self.expect_status {
+            Some(expected) => status == expected,
+            None => (200..400).contains(&status),
+        }
+    }
+
+    pub fn get_tls_verify(&self) -> bool {
+        self.tls_verify.unwrap_or(false)
+    }
+}
+
+impl PassiveHealthConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn get_error_rate(&self) -> u32 {
+        self.error_rate.unwrap_or(50)
+    }
+
+    pub fn get_min_requests(&self) -> u32 {
+        self.min_requests.unwrap_or(5)
+    }
+
+    pub fn get_window(&self) -> u64 {
+        self.window.unwrap_or(30)
+    }
+}
+
+impl NoHealthyTargetConfig {
+    pub fn get_action(&self) -> Result<NoHealthyAction> {
+        match self.action.as_str() {
+            "reject" => Ok(NoHealthyAction::Reject),
+            "hold" => match self.hold.unwrap_or(10) {
+                0 => anyhow::bail!("hold 必须大于0"),
+                secs => Ok(NoHealthyAction::Hold(Duration::from_secs(secs))),
+            },
+            "static_response" => match &self.response {
+                Some(response) => Ok(NoHealthyAction::StaticResponse(parse_payload(response)?)),
+                None => anyhow::bail!("static_response 需要配置 response"),
+            },
+            "fallback_target" => match self.target.as_deref().map(str::trim) {
+                Some(target) if !target.is_empty() => {
+                    Ok(NoHealthyAction::FallbackTarget(target.to_string()))
+                }
+                _ => anyhow::bail!("fallback_target 需要配置 target"),
+            },
+            action => anyhow::bail!("不支持的 on_no_healthy_target 动作: {}", action),
+        }
+    }
+}
+
+impl ControlConfig {
+    pub fn get_listen(&self) -> String {
+        self.listen
+            .clone()
+            .unwrap_or_else(|| "127.0.0.1:7070".to_string())
+    }
+}
+
+impl KnockConfig {
+    pub fn get_sequence(&self) -> Vec<String> {
+        self.sequence.clone().unwrap_or_default()
+    }
+
+    pub fn get_open_seconds(&self) -> u64 {
+        self.open_seconds.unwrap_or(30)
+    }
+
+    pub fn get_step_timeout(&self) -> u64 {
+        self.step_timeout.unwrap_or(10)
+    }
+}
+
+impl TunnelClientConfig {
+    pub fn get_client_id(&self) -> String {
+        self.client_id
+            .clone()
+            .unwrap_or_else(|| "smart-forward".to_string())
+    }
+
+    pub fn get_max_backoff(&self) -> u64 {
+        self.max_backoff.unwrap_or(60)
+    }
+}
+
+impl TransparentConfig {
+    pub fn get_mode(&self) -> &str {
+        self.mode.as_deref().unwrap_or("redirect")
+    }
+
+    pub fn get_routes(&self) -> Vec<TransparentRoute> {
+        self.routes.clone().unwrap_or_default()
+    }
+}
+
 impl ForwardRule {
     pub fn get_effective_buffer_size(&self, default_size: usize) -> usize {
         self.buffer_size.unwrap_or(default_size)
     }
 
     pub fn is_protocol_supported(&self, protocol: &str) -> bool {
-        matches!(protocol, "tcp" | "http" | "udp")
+        matches!(protocol, "tcp" | "http" | "udp" | "dns")
     }
 
     #[allow(dead_code)]
@@ -156,14 +895,291 @@ impl ForwardRule {
         } else if let Some(protocol) = &self.protocol {
             // 如果指定了单个protocol，只使用该协议
             vec![protocol.clone()]
+        } else if self.has_unix_listen() || self.has_unix_target() {
+            // Unix套接字规则只有流式转发
+            vec!["tcp".to_string()]
+        } else if self.is_transparent() && self.get_transparent_config().get_mode() == "redirect" {
+            // REDIRECT 模式只能还原TCP连接的原始目的地址
+            vec!["tcp".to_string()]
+        } else if self.is_reverse() {
+            // 反向隧道只转发TCP
+            vec!["tcp".to_string()]
         } else {
             // 默认同时支持TCP和UDP（最常见的使用场景）
             vec!["tcp".to_string(), "udp".to_string()]
         }
     }
 
-    pub fn get_listen_addr(&self, base_addr: &str) -> String {
-        format!("{}:{}", base_addr, self.listen_port)
+    // 获取规则的全部监听地址，未配置 listen 时使用全局 listen_addr
+    pub fn get_listen_addrs(&self, base_addr: &str) -> Result<Vec<ListenAddr>> {
+        match &self.listen {
+            Some(listen) => parse_listen_addrs(listen, self.listen_port),
+            None => parse_listen_addrs(&[base_addr.to_string()], self.listen_port),
+        }
+    }
+
+    pub fn is_dns(&self) -> bool {
+        self.get_protocols().iter().any(|p| p == "dns")
+    }
+
+    pub fn get_dns_config(&self) -> DnsRuleConfig {
+        self.dns.clone().unwrap_or_default()
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.reverse.unwrap_or(false)
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transparent.is_some()
+    }
+
+    pub fn get_transparent_config(&self) -> TransparentConfig {
+        self.transparent.clone().unwrap_or_default()
+    }
+
+    // 目标是否由公共管理器解析并做健康检查（DNS上游和透明代理的目标不是）
+    pub fn has_managed_targets(&self) -> bool {
+        !self.is_dns() && !self.is_transparent()
+    }
+
+    // 未配置时默认故障转移；含SRV目标的规则默认按SRV权重加权轮询
+    pub fn get_strategy(&self) -> Strategy {
+        self.strategy
+            .as_deref()
+            .and_then(Strategy::from_name)
+            .unwrap_or(if self.has_srv_targets() {
+                Strategy::WeightedRoundRobin
+            } else {
+                Strategy::Failover
+            })
+    }
+
+    pub fn has_srv_targets(&self) -> bool {
+        self.targets.iter().any(|spec| is_srv_addr(&spec.address))
+    }
+
+    // 是否有按SRV或TXT记录展开为多个目标的目标
+    pub fn has_expanded_targets(&self) -> bool {
+        self.targets
+            .iter()
+            .any(|spec| is_expanded_addr(&spec.address))
+    }
+
+    pub fn get_health_check_config(&self) -> HealthCheckConfig {
+        self.health_check.clone().unwrap_or_default()
+    }
+
+    pub fn get_latency_margin(&self) -> u32 {
+        self.latency_margin.unwrap_or(20)
+    }
+
+    // 目标所在的层级，未配置 tiers 时全部目标位于同一层
+    pub fn get_tier(&self, target: &str) -> usize {
+        self.tiers
+            .as_ref()
+            .and_then(|tiers| {
+                tiers
+                    .iter()
+                    .position(|tier| tier.iter().any(|t| t.address == target))
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn get_preempt_delay(&self) -> u64 {
+        self.preempt_delay.unwrap_or(30)
+    }
+
+    pub fn get_target_addresses(&self) -> Vec<String> {
+        self.targets.iter().map(|t| t.address.clone()).collect()
+    }
+
+    pub fn get_slow_start(&self) -> u64 {
+        self.slow_start.unwrap_or(0)
+    }
+
+    pub fn get_connect_retries(&self) -> u32 {
+        self.connect_retries.unwrap_or(0)
+    }
+
+    pub fn get_connect_deadline(&self) -> u64 {
+        self.connect_deadline.unwrap_or(10)
+    }
+
+    // 配置校验已保证动作有效
+    pub fn get_no_healthy_action(&self) -> Option<NoHealthyAction> {
+        self.on_no_healthy_target
+            .as_ref()
+            .and_then(|config| config.get_action().ok())
+    }
+
+    pub fn get_hash_key(&self) -> HashKey {
+        self.hash_key
+            .as_deref()
+            .and_then(HashKey::from_name)
+            .unwrap_or(HashKey::Ip)
+    }
+
+    // 显式配置 sniff，或同时启用 tcp 和 http 时，通过嗅探在同一端口分流
+    pub fn get_sniff_config(&self) -> Option<SniffConfig> {
+        let protocols = self.get_protocols();
+        let has_tcp = protocols.iter().any(|p| p == "tcp");
+        let has_http = protocols.iter().any(|p| p == "http");
+        match &self.sniff {
+            Some(sniff) if has_tcp => Some(sniff.clone()),
+            None if has_tcp && has_http => Some(SniffConfig::default()),
+            _ => None,
+        }
+    }
+
+    fn validate_sniff(&self) -> Result<()> {
+        if !self.get_protocols().iter().any(|p| p == "tcp") {
+            anyhow::bail!("sniff 需要启用tcp协议");
+        }
+        if self.is_transparent() || self.is_reverse() {
+            anyhow::bail!("sniff 不能与透明代理或反向隧道同时使用");
+        }
+        for (name, route) in self.sniff.clone().unwrap_or_default().get_routes() {
+            if SniffProtocol::from_name(&name).is_none() {
+                anyhow::bail!("sniff 不支持的协议类型 {}", name);
+            }
+            match route {
+                SniffRoute::Targets(targets) if targets.is_empty() => {
+                    anyhow::bail!("sniff 路由 {} 至少需要一个目标", name)
+                }
+                SniffRoute::Handler(handler) if handler != "redirect" => {
+                    anyhow::bail!("sniff 路由 {} 不支持的处理器 {}", name, handler)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_knock(&self) -> Result<()> {
+        let knock = self.knock.clone().unwrap_or_default();
+        if !self.get_protocols().iter().any(|p| p == "tcp") {
+            anyhow::bail!("knock 需要启用tcp协议");
+        }
+        if self.is_reverse() || self.is_transparent() || self.listens_only_on_unix() {
+            anyhow::bail!("knock 不支持反向隧道、透明代理和Unix套接字监听");
+        }
+        let sequence = knock.get_sequence();
+        if sequence.is_empty() && knock.spa_port.is_none() {
+            anyhow::bail!("knock 需要配置 sequence 或 spa_port");
+        }
+        if knock.spa_port.is_some() && knock.secret.as_deref().unwrap_or("").is_empty() {
+            anyhow::bail!("knock 使用 spa_port 时必须配置 secret");
+        }
+        let mut ports = sequence
+            .iter()
+            .map(|step| parse_knock_step(step).map(|step| step.port()))
+            .collect::<Result<Vec<_>>>()?;
+        ports.extend(knock.spa_port);
+        if ports.contains(&self.listen_port) {
+            anyhow::bail!("knock 端口不能与监听端口 {} 相同", self.listen_port);
+        }
+        Ok(())
+    }
+
+    fn validate_transparent(&self) -> Result<()> {
+        let config = self.get_transparent_config();
+        let mode = config.get_mode();
+        if !matches!(mode, "redirect" | "tproxy") {
+            anyhow::bail!("不支持的透明代理模式 {}", mode);
+        }
+        if !self.targets.is_empty() {
+            anyhow::bail!("透明代理规则的目标由 transparent.routes 配置，targets 需留空");
+        }
+        if self.has_unix_listen() {
+            anyhow::bail!("透明代理规则不支持Unix套接字监听");
+        }
+        for protocol in self.get_protocols() {
+            match protocol.as_str() {
+                "tcp" => {}
+                // UDP没有 SO_ORIGINAL_DST，只能通过TPROXY取得原始目的地址
+                "udp" if mode == "tproxy" => {}
+                "udp" => anyhow::bail!("透明代理的udp协议需要 tproxy 模式"),
+                other => anyhow::bail!("透明代理不支持 {} 协议", other),
+            }
+        }
+        for route in config.routes.iter().flatten() {
+            if let Some(dst) = &route.dst {
+                parse_cidr(dst)?;
+            }
+            if route.targets.is_empty() {
+                anyhow::bail!("透明代理路由至少需要一个目标");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_unix_listen(&self) -> bool {
+        self.listen
+            .as_ref()
+            .is_some_and(|listen| listen.iter().any(|l| is_unix_addr(l)))
+    }
+
+    pub fn listens_only_on_unix(&self) -> bool {
+        self.listen
+            .as_ref()
+            .is_some_and(|listen| !listen.is_empty() && listen.iter().all(|l| is_unix_addr(l)))
+    }
+
+    pub fn has_unix_target(&self) -> bool {
+        self.targets.iter().any(|t| is_unix_addr(&t.address))
+    }
+
+    // 规则级解析器配置，未配置的字段沿用全局配置
+    pub fn get_resolver_config(&self, global_config: &DnsResolverConfig) -> DnsResolverConfig {
+        match &self.resolver {
+            Some(rule_config) => DnsResolverConfig {
+                resolvers: rule_config
+                    .resolvers
+                    .clone()
+                    .or(global_config.resolvers.clone()),
+                timeout: rule_config.timeout.or(global_config.timeout),
+                attempts: rule_config.attempts.or(global_config.attempts),
+                ordering: rule_config
+                    .ordering
+                    .clone()
+                    .or(global_config.ordering.clone()),
+                txt_key: rule_config
+                    .txt_key
+                    .clone()
+                    .or(global_config.txt_key.clone()),
+            },
+            None => global_config.clone(),
+        }
+    }
+
+    // 按规则解析器解析的目标：targets、嗅探路由目标和兜底目标
+    pub fn get_resolved_targets(&self) -> Vec<String> {
+        // dns 规则的 targets 是上游DNS服务器，不经过目标解析
+        if self.is_dns() {
+            return Vec::new();
+        }
+        let mut targets = self.get_target_addresses();
+        if let Some(routes) = self.sniff.as_ref().and_then(|sniff| sniff.routes.as_ref()) {
+            for route in routes.values() {
+                if let SniffRoute::Targets(route_targets) = route {
+                    targets.extend(route_targets.iter().cloned());
+                }
+            }
+        }
+        if let Some(target) = self
+            .on_no_healthy_target
+            .as_ref()
+            .and_then(|config| config.target.clone())
+        {
+            targets.push(target);
+        }
+        if self.is_transparent() {
+            for route in self.get_transparent_config().get_routes() {
+                targets.extend(route.targets);
+            }
+        }
+        targets
     }
 
     // 获取规则级别的动态更新配置
@@ -177,6 +1193,8 @@ impl ForwardRule {
                 connection_timeout: rule_config
                     .connection_timeout
                     .or(global_config.connection_timeout),
+                udp_timeout: rule_config.udp_timeout.or(global_config.udp_timeout),
+                max_lifetime: rule_config.max_lifetime.or(global_config.max_lifetime),
                 auto_reconnect: rule_config.auto_reconnect.or(global_config.auto_reconnect),
             }
         } else {
@@ -184,3 +1202,138 @@ impl ForwardRule {
         }
     }
 }
+
+// 兼容单个字符串和字符串列表两种写法
+fn deserialize_string_or_list<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        Option::<StringOrList>::deserialize(deserializer)?.map(|value| match value {
+            StringOrList::One(s) => vec![s],
+            StringOrList::Many(list) => list,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_rules(rules: &str) -> Result<Config> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "logging: {{level: info, format: text}}\nnetwork: {{listen_addr: \"127.0.0.1\"}}\nrules:{}",
+            rules
+        ))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_rule_resolver_scoped_to_rule_targets() {
+        let rule = |name: &str, port: u16, resolver: &str| {
+            format!(
+                "\n  - name: {}\n    listen_port: {}\n    targets: [\"home.example.com:443\"]\n{}",
+                name, port, resolver
+            )
+        };
+        let custom = "    resolver: {resolvers: [\"1.1.1.1\"]}\n";
+
+        // 共用目标的规则中只有一条配置了 resolver 时，覆盖会影响另一条规则，直接拒绝
+        let error = parse_rules(&(rule("a", 8080, custom) + &rule("b", 8081, "")))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("不同的解析器"), "{}", error);
+        // 解析器相同时允许共用
+        assert!(parse_rules(&(rule("a", 8080, custom) + &rule("b", 8081, custom))).is_ok());
+
+        // 透明代理路由的目标同样按规则的 resolver 检查
+        let transparent = r#"
+  - name: t
+    listen_port: 8082
+    targets: []
+    transparent:
+      routes:
+        - dst: "10.0.0.0/8"
+          targets: ["home.example.com:443"]
+    resolver: {resolvers: ["1.1.1.1"]}"#;
+        let error = parse_rules(&(transparent.to_string() + &rule("b", 8081, "")))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("不同的解析器"), "{}", error);
+    }
+
+    #[test]
+    fn test_tls_probe_rejects_unix_targets() {
+        let rule = |check_type: &str| {
+            format!(
+                "\n  - name: app\n    listen_port: 8080\n    targets: [\"unix:/run/app.sock\"]\n    health_check: {{type: {}}}",
+                check_type
+            )
+        };
+        assert!(parse_rules(&rule("http")).is_ok());
+        for check_type in ["tls", "https"] {
+            let error = parse_rules(&rule(check_type)).unwrap_err().to_string();
+            assert!(error.contains("不支持Unix套接字目标"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_target_spec_string_or_object() {
+        let targets: Vec<TargetSpec> = serde_yaml::from_str(
+            r#"
+- "10.0.0.1:80"
+- {address: "10.0.0.2:80", weight: 3, max_connections: 100}
+- {address: "10.0.0.3:80"}
+"#,
+        )
+        .unwrap();
+        assert_eq!(targets[0], TargetSpec::from("10.0.0.1:80"));
+        assert_eq!(targets[1].address, "10.0.0.2:80");
+        assert_eq!(targets[1].get_weight(), 3);
+        assert_eq!(targets[1].max_connections, Some(100));
+        assert_eq!(targets[2].get_weight(), 1);
+        assert_eq!(targets[2].max_connections, None);
+        assert!(serde_yaml::from_str::<TargetSpec>("{weight: 2}").is_err());
+    }
+
+    #[test]
+    fn test_no_healthy_target_actions() {
+        let config = |action: &str| NoHealthyTargetConfig {
+            action: action.to_string(),
+            hold: None,
+            response: None,
+            target: None,
+        };
+        assert!(matches!(
+            config("reject").get_action(),
+            Ok(NoHealthyAction::Reject)
+        ));
+        assert!(matches!(
+            config("hold").get_action(),
+            Ok(NoHealthyAction::Hold(hold)) if hold == Duration::from_secs(10)
+        ));
+
+        // static_response 和 fallback_target 缺少参数时报错
+        assert!(config("static_response").get_action().is_err());
+        assert!(config("fallback_target").get_action().is_err());
+        let response = NoHealthyTargetConfig {
+            response: Some("hex:0d0a".to_string()),
+            ..config("static_response")
+        };
+        assert!(matches!(
+            response.get_action(),
+            Ok(NoHealthyAction::StaticResponse(bytes)) if bytes == b"\r\n"
+        ));
+        assert!(config("drop").get_action().is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// 本地控制接口 - 每个连接发送一行文本命令，返回以 OK 或 ERR 开头的应答后关闭
+//
+// 命令：
+//   status                            列出各目标的运维状态和健康状态
+//   enable|drain|disable <目标地址>    切换目标状态（地址与配置中的 targets 一致）
+//   dns                               目标域名解析的缓存统计
+use crate::common::{CommonManager, TargetState};
+use crate::listener::{ListenAddr, StreamListener};
+use crate::resolver;
+use crate::utils::{connect_addrs, is_unix_addr, parse_unix_path, resolve_target, BoxedStream};
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+// 单条命令和应答的最大长度
+const MAX_COMMAND_LEN: u64 = 4096;
+const MAX_REPLY_LEN: u64 = 1024 * 1024;
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn start(listen: &str, manager: CommonManager) -> Result<()> {
+    let listen_addr = parse_control_addr(listen)?;
+    let listener = StreamListener::bind(&listen_addr)
+        .map_err(|e| anyhow::anyhow!("控制接口绑定失败 {}: {}", listen_addr, e))?;
+    info!("控制接口监听: {}", listen_addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let manager = manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &manager).await {
+                            debug!("控制连接处理失败: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("控制接口接受连接失败: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn parse_control_addr(listen: &str) -> Result<ListenAddr> {
+    if is_unix_addr(listen) {
+        let path = parse_unix_path(listen)
+            .ok_or_else(|| anyhow::anyhow!("无效的Unix套接字地址: {}", listen))?;
+        return Ok(ListenAddr::Unix(path));
+    }
+    let addr: SocketAddr = listen
+        .parse()
+        .map_err(|_| anyhow::anyhow!("控制接口监听地址无效: {}", listen))?;
+    Ok(ListenAddr::Socket {
+        addr,
+        device: None,
+        v6_only: false,
+    })
+}
+
+async fn serve(stream: BoxedStream, manager: &CommonManager) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader.take(MAX_COMMAND_LEN));
+    let mut line = String::new();
+    tokio::time::timeout(CONTROL_TIMEOUT, reader.read_line(&mut line))
+        .await
+        .map_err(|_| anyhow::anyhow!("等待命令超时"))??;
+
+    let reply = match execute(line.trim(), manager).await {
+        Ok(reply) => reply,
+        Err(e) => format!("ERR {}\n", e),
+    };
+    writer.write_all(reply.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+async fn execute(command: &str, manager: &CommonManager) -> Result<String> {
+    let parts: Vec<_> = command.split_whitespace().collect();
+    match parts.as_slice() {
+        ["status"] => {
+            let mut reply = String::from("OK\n");
+            for (target, state, healthy) in manager.target_states() {
+                reply.push_str(&format!(
+                    "{} {} {}\n",
+                    target,
+                    state.as_str(),
+                    if healthy { "healthy" } else { "unhealthy" }
+                ));
+            }
+            Ok(reply)
+        }
+        ["dns"] => {
+            let mut stats: Vec<_> = resolver::cache_stats().into_iter().collect();
+            stats.sort();
+            let mut reply = String::from("OK\n");
+            for (key, value) in stats {
+                reply.push_str(&format!("{} {}\n", key, value));
+            }
+            Ok(reply)
+        }
+        [action, target] => {
+            let state = match *action {
+                "enable" => TargetState::Enabled,
+                "drain" => TargetState::Drain,
+                "disable" => TargetState::Disabled,
+                _ => anyhow::bail!("未知命令: {}", action),
+            };
+            manager.set_target_state(target, state).await?;
+            Ok(format!("OK {} {}\n", target, state.as_str()))
+        }
+        _ => anyhow::bail!("用法: status | dns | enable <目标> | drain <目标> | disable <目标>"),
+    }
+}
+
+// --ctl 客户端：向运行中的实例发送一条命令，返回应答原文
+pub async fn send_command(listen: &str, command: &str) -> Result<String> {
+    let addrs = resolve_target(listen).await?;
+    let mut stream = tokio::time::timeout(CONTROL_TIMEOUT, connect_addrs(&addrs))
+        .await
+        .map_err(|_| anyhow::anyhow!("连接控制接口超时: {}", listen))?
+        .map_err(|e| anyhow::anyhow!("无法连接控制接口 {}: {}", listen, e))?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+
+    let mut reply = String::new();
+    tokio::time::timeout(
+        CONTROL_TIMEOUT,
+        stream.take(MAX_REPLY_LEN).read_to_string(&mut reply),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("等待控制接口应答超时"))??;
+    Ok(reply)
+}
@@ -0,0 +1,771 @@
+// DNS 转发器 - 接收局域网设备的DNS查询，转发到上游（UDP/TCP/DoT/DoH）并按TTL缓存
+use crate::config::DnsRuleConfig;
+use crate::forwarder::{bind_all, Forwarder};
+use crate::listener::{bind_tcp, bind_udp, format_bind_results, BindResult, ListenAddr};
+use crate::utils::{get_standard_stats, parse_dns_upstream, ConnectionStats};
+use anyhow::Result;
+use async_trait::async_trait;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::{A, AAAA};
+use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+use hickory_resolver::TokioAsyncResolver;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::RwLock;
+
+// 未携带EDNS时UDP应答的最大长度
+const DEFAULT_UDP_PAYLOAD: usize = 512;
+// 否定应答（NXDOMAIN/无记录）最长缓存时间
+const MAX_NEGATIVE_TTL: u32 = 300;
+// TCP连接空闲超时
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct CacheEntry {
+    response_code: ResponseCode,
+    records: Vec<Record>,
+    expires: Instant,
+}
+
+// 按 (域名, 记录类型) 缓存的应答，过期时间取记录的最小TTL
+struct DnsCache {
+    entries: HashMap<(Name, RecordType), CacheEntry>,
+    capacity: usize,
+}
+
+impl DnsCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &(Name, RecordType)) -> Option<(ResponseCode, Vec<Record>)> {
+        let now = Instant::now();
+        let entry = self.entries.get(key)?;
+        if entry.expires <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        // 返回剩余TTL，而不是上游给出的原始TTL
+        let remaining = entry.expires.duration_since(now).as_secs() as u32;
+        let records = entry
+            .records
+            .iter()
+            .cloned()
+            .map(|mut record| {
+                record.set_ttl(remaining.min(record.ttl()));
+                record
+            })
+            .collect();
+        Some((entry.response_code, records))
+    }
+
+    fn insert(
+        &mut self,
+        key: (Name, RecordType),
+        response_code: ResponseCode,
+        records: Vec<Record>,
+        ttl: u32,
+    ) {
+        if ttl == 0 || self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        if self.entries.len() >= self.capacity {
+            // 仍然满了就淘汰最早过期的一条
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response_code,
+                records,
+                expires: Instant::now() + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[derive(Default)]
+struct DnsStats {
+    queries: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    overrides: AtomicU64,
+    upstream_errors: AtomicU64,
+}
+
+// 查询处理：静态解析 -> 缓存 -> 上游
+struct DnsHandler {
+    name: String,
+    resolver: TokioAsyncResolver,
+    cache: Mutex<DnsCache>,
+    overrides: HashMap<Name, Vec<IpAddr>>,
+    override_ttl: u32,
+    stats: Arc<DnsStats>,
+}
+
+impl DnsHandler {
+    async fn handle(&self, packet: &[u8], max_size: Option<usize>) -> Option<Vec<u8>> {
+        // 无法解析的报文直接丢弃
+        let request = Message::from_vec(packet).ok()?;
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+        self.stats.queries.fetch_add(1, Ordering::Relaxed);
+
+        let mut response = match request.queries().first() {
+            Some(query) => {
+                let (response_code, answers) = self.answer(query.name(), query.query_type()).await;
+                build_response(&request, response_code, answers)
+            }
+            None => build_response(&request, ResponseCode::FormErr, Vec::new()),
+        };
+
+        let mut bytes = response.to_vec().ok()?;
+        // UDP应答超长时设置TC位，客户端会改用TCP重新查询
+        if let Some(default_size) = max_size {
+            let limit = request
+                .extensions()
+                .as_ref()
+                .map(|edns| edns.max_payload() as usize)
+                .unwrap_or(default_size)
+                .max(default_size);
+            if bytes.len() > limit {
+                response.take_answers();
+                response.set_truncated(true);
+                bytes = response.to_vec().ok()?;
+            }
+        }
+
+        Some(bytes)
+    }
+
+    async fn answer(&self, name: &Name, record_type: RecordType) -> (ResponseCode, Vec<Record>) {
+        let key_name = name.to_lowercase();
+
+        // 1. 静态解析优先，覆盖该域名的所有查询类型
+        if let Some(ips) = self.overrides.get(&key_name) {
+            self.stats.overrides.fetch_add(1, Ordering::Relaxed);
+            let records = ips
+                .iter()
+                .filter_map(|ip| match (ip, record_type) {
+                    (IpAddr::V4(v4), RecordType::A) => Some(RData::A(A(*v4))),
+                    (IpAddr::V6(v6), RecordType::AAAA) => Some(RData::AAAA(AAAA(*v6))),
+                    _ => None,
+                })
+                .map(|rdata| Record::from_rdata(name.clone(), self.override_ttl, rdata))
+                .collect();
+            return (ResponseCode::NoError, records);
+        }
+
+        // 2. 缓存
+        let key = (key_name, record_type);
+        if let Some(hit) = self.cache.lock().unwrap().get(&key) {
+            self.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return hit;
+        }
+        self.stats.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        // 3. 上游查询
+        match self.resolver.lookup(name.clone(), record_type).await {
+            Ok(lookup) => {
+                let records = lookup.records().to_vec();
+                let ttl = lookup
+                    .valid_until()
+                    .saturating_duration_since(Instant::now())
+                    .as_secs() as u32;
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(key, ResponseCode::NoError, records.clone(), ttl);
+                (ResponseCode::NoError, records)
+            }
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound {
+                    response_code,
+                    negative_ttl,
+                    ..
+                } => {
+                    // 否定应答同样缓存，TTL取SOA给出的值并设置上限
+                    let ttl = negative_ttl.unwrap_or(60).min(MAX_NEGATIVE_TTL);
+                    self.cache
+                        .lock()
+                        .unwrap()
+                        .insert(key, *response_code, Vec::new(), ttl);
+                    (*response_code, Vec::new())
+                }
+                _ => {
+                    self.stats.upstream_errors.fetch_add(1, Ordering::Relaxed);
+                    warn!("DNS转发器 {} 上游查询失败 {}: {}", self.name, name, e);
+                    (ResponseCode::ServFail, Vec::new())
+                }
+            },
+        }
+    }
+}
+
+fn build_response(request: &Message, response_code: ResponseCode, answers: Vec<Record>) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(response_code);
+    response.add_queries(request.queries().to_vec());
+    response.add_answers(answers);
+    response
+}
+
+// ================================
+// DNS 转发器
+// ================================
+pub struct DNSForwarder {
+    listen_addrs: Vec<ListenAddr>,
+    name: String,
+    upstreams: Vec<String>,
+    config: DnsRuleConfig,
+    stats: Arc<RwLock<ConnectionStats>>,
+    dns_stats: Arc<DnsStats>,
+    handler: Option<Arc<DnsHandler>>,
+    running: Arc<RwLock<bool>>,
+    bind_results: Vec<BindResult>,
+}
+
+impl DNSForwarder {
+    pub fn new(
+        listen_addrs: &[ListenAddr],
+        name: &str,
+        upstreams: &[String],
+        config: &DnsRuleConfig,
+    ) -> Self {
+        Self {
+            listen_addrs: listen_addrs.to_vec(),
+            name: name.to_string(),
+            upstreams: upstreams.to_vec(),
+            config: config.clone(),
+            stats: Arc::new(RwLock::new(ConnectionStats::default())),
+            dns_stats: Arc::new(DnsStats::default()),
+            handler: None,
+            running: Arc::new(RwLock::new(false)),
+            bind_results: Vec::new(),
+        }
+    }
+
+    async fn build_handler(&self) -> Result<DnsHandler> {
+        let mut name_servers = Vec::new();
+        for upstream in &self.upstreams {
+            match parse_dns_upstream(upstream).await {
+                Ok(servers) => name_servers.extend(servers),
+                Err(e) => warn!("DNS转发器 {} 上游 {} 无效: {}", self.name, upstream, e),
+            }
+        }
+        if name_servers.is_empty() {
+            anyhow::bail!("DNS转发器 {} 没有可用的上游", self.name);
+        }
+
+        let mut opts = ResolverOpts::default();
+        opts.timeout = Duration::from_secs(2);
+        opts.attempts = 2;
+        opts.preserve_intermediates = true; // 保留CNAME链
+        opts.ndots = 0;
+
+        let resolver = TokioAsyncResolver::tokio(
+            ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(name_servers)),
+            opts,
+        );
+
+        let mut overrides = HashMap::new();
+        for (domain, ips) in self.config.get_overrides() {
+            let name = Name::from_ascii(format!("{}.", domain.trim_end_matches('.')))?;
+            let ips = ips
+                .iter()
+                .map(|ip| ip.parse::<IpAddr>())
+                .collect::<Result<Vec<_>, _>>()?;
+            overrides.insert(name.to_lowercase(), ips);
+        }
+
+        Ok(DnsHandler {
+            name: self.name.clone(),
+            resolver,
+            cache: Mutex::new(DnsCache::new(self.config.get_cache_size())),
+            overrides,
+            override_ttl: self.config.get_override_ttl(),
+            stats: self.dns_stats.clone(),
+        })
+    }
+
+    async fn serve_udp(
+        socket: UdpSocket,
+        handler: Arc<DnsHandler>,
+        stats: Arc<RwLock<ConnectionStats>>,
+        running: Arc<RwLock<bool>>,
+    ) {
+        let socket = Arc::new(socket);
+        let mut buffer = vec![0u8; 4096];
+
+        while *running.read().await {
+            let (len, client_addr) = match socket.recv_from(&mut buffer).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            stats.write().await.add_bytes_received(len as u64);
+
+            let packet = buffer[..len].to_vec();
+            let socket = socket.clone();
+            let handler = handler.clone();
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                if let Some(response) = handler.handle(&packet, Some(DEFAULT_UDP_PAYLOAD)).await {
+                    if socket.send_to(&response, client_addr).await.is_ok() {
+                        stats.write().await.add_bytes_sent(response.len() as u64);
+                    }
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(
+        listener: TcpListener,
+        handler: Arc<DnsHandler>,
+        stats: Arc<RwLock<ConnectionStats>>,
+        running: Arc<RwLock<bool>>,
+    ) {
+        while *running.read().await {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let handler = handler.clone();
+                    let stats = stats.clone();
+                    tokio::spawn(async move {
+                        stats.write().await.increment_connections();
+                        let _ = Self::handle_tcp_connection(stream, handler, stats).await;
+                    });
+                }
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    // TCP DNS：每条消息前有2字节长度前缀，同一连接可连续查询
+    async fn handle_tcp_connection(
+        mut stream: TcpStream,
+        handler: Arc<DnsHandler>,
+        stats: Arc<RwLock<ConnectionStats>>,
+    ) -> Result<()> {
+        loop {
+            let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+                Ok(Ok(len)) => len as usize,
+                _ => return Ok(()),
+            };
+            let mut packet = vec![0u8; len];
+            stream.read_exact(&mut packet).await?;
+            stats.write().await.add_bytes_received(len as u64 + 2);
+
+            let response = match handler.handle(&packet, None).await {
+                Some(response) => response,
+                None => return Ok(()),
+            };
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+            stats
+                .write()
+                .await
+                .add_bytes_sent(response.len() as u64 + 2);
+        }
+    }
+}
+
+#[async_trait]
+impl Forwarder for DNSForwarder {
+    async fn start(&mut self) -> Result<()> {
+        let handler = Arc::new(self.build_handler().await?);
+        *self.running.write().await = true;
+
+        // 每个监听地址同时提供UDP和TCP服务
+        let socket_listen_addrs: Vec<ListenAddr> = self
+            .listen_addrs
+            .iter()
+            .filter(|addr| matches!(addr, ListenAddr::Socket { .. }))
+            .cloned()
+            .collect();
+        if socket_listen_addrs.len() != self.listen_addrs.len() {
+            warn!("DNS转发器 {} 忽略Unix套接字监听地址", self.name);
+        }
+
+        let (udp_sockets, mut bind_results) = bind_all(
+            "DNS/UDP",
+            &self.name,
+            &socket_listen_addrs,
+            |addr| match addr {
+                ListenAddr::Socket {
+                    addr,
+                    device,
+                    v6_only,
+                } => bind_udp(addr, device.as_deref(), *v6_only),
+                ListenAddr::Unix(_) => anyhow::bail!("DNS不支持Unix套接字监听"),
+            },
+        )?;
+        let tcp_listeners =
+            match bind_all(
+                "DNS/TCP",
+                &self.name,
+                &socket_listen_addrs,
+                |addr| match addr {
+                    ListenAddr::Socket {
+                        addr,
+                        device,
+                        v6_only,
+                    } => bind_tcp(addr, device.as_deref(), *v6_only),
+                    ListenAddr::Unix(_) => anyhow::bail!("DNS不支持Unix套接字监听"),
+                },
+            ) {
+                Ok((listeners, results)) => {
+                    bind_results.extend(results);
+                    listeners
+                }
+                // TCP全部失败时仍提供UDP服务
+                Err(e) => {
+                    warn!("{}", e);
+                    Vec::new()
+                }
+            };
+        self.bind_results = bind_results;
+
+        for socket in udp_sockets {
+            tokio::spawn(Self::serve_udp(
+                socket,
+                handler.clone(),
+                self.stats.clone(),
+                self.running.clone(),
+            ));
+        }
+        for listener in tcp_listeners {
+            tokio::spawn(Self::serve_tcp(
+                listener,
+                handler.clone(),
+                self.stats.clone(),
+                self.running.clone(),
+            ));
+        }
+
+        info!(
+            "DNS转发器 {} 启动: 上游 {:?}，静态解析 {} 条",
+            self.name,
+            self.upstreams,
+            handler.overrides.len()
+        );
+        self.handler = Some(handler);
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        *self.running.write().await = false;
+    }
+
+    fn is_running(&self) -> bool {
+        *self.running.blocking_read()
+    }
+
+    fn get_stats(&self) -> HashMap<String, String> {
+        let stats = self.stats.blocking_read();
+        let mut result = get_standard_stats(&stats);
+        let dns_stats = &self.dns_stats;
+        for (key, value) in [
+            ("queries", &dns_stats.queries),
+            ("cache_hits", &dns_stats.cache_hits),
+            ("cache_misses", &dns_stats.cache_misses),
+            ("overrides", &dns_stats.overrides),
+            ("upstream_errors", &dns_stats.upstream_errors),
+        ] {
+            result.insert(key.to_string(), value.load(Ordering::Relaxed).to_string());
+        }
+        if let Some(handler) = &self.handler {
+            result.insert(
+                "cache_entries".to_string(),
+                handler.cache.lock().unwrap().len().to_string(),
+            );
+        }
+        result.insert(
+            "listeners".to_string(),
+            format_bind_results(&self.bind_results),
+        );
+        result
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{Edns, Query};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_cache_expires_and_counts_down_ttl() {
+        let mut cache = DnsCache::new(2);
+        let name = Name::from_ascii("example.com.").unwrap();
+        let record = Record::from_rdata(name.clone(), 300, RData::A(A("1.2.3.4".parse().unwrap())));
+
+        cache.insert(
+            (name.clone(), RecordType::A),
+            ResponseCode::NoError,
+            vec![record],
+            30,
+        );
+        let (code, records) = cache.get(&(name.clone(), RecordType::A)).unwrap();
+        assert_eq!(code, ResponseCode::NoError);
+        assert!(records[0].ttl() <= 30);
+
+        // TTL为0的应答不缓存
+        cache.insert(
+            (name.clone(), RecordType::AAAA),
+            ResponseCode::NXDomain,
+            Vec::new(),
+            0,
+        );
+        assert!(cache.get(&(name, RecordType::AAAA)).is_none());
+    }
+
+    #[test]
+    fn test_cache_ttl_counts_down_until_expiry() {
+        let mut cache = DnsCache::new(4);
+        let name = Name::from_ascii("example.com.").unwrap();
+        let key = (name.clone(), RecordType::A);
+        let record = Record::from_rdata(name, 300, RData::A(A("1.2.3.4".parse().unwrap())));
+        cache.insert(key.clone(), ResponseCode::NoError, vec![record], 3);
+
+        assert_eq!(cache.get(&key).unwrap().1[0].ttl(), 2);
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(cache.get(&key).unwrap().1[0].ttl(), 1);
+        std::thread::sleep(Duration::from_millis(2000));
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    // 回环上的假上游：每个查询返回一条A记录，记录收到的查询数
+    async fn fake_upstream() -> (SocketAddr, Arc<AtomicU64>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicU64::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+                let Ok(request) = Message::from_vec(&buffer[..len]) else {
+                    continue;
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                let name = request.queries()[0].name().clone();
+                let answers = vec![Record::from_rdata(name, 60, RData::A(A::new(10, 0, 0, 1)))];
+                let response = build_response(&request, ResponseCode::NoError, answers);
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        (addr, queries)
+    }
+
+    struct TestForwarder {
+        udp: SocketAddr,
+        tcp: SocketAddr,
+        handler: Arc<DnsHandler>,
+    }
+
+    async fn start_forwarder(upstream: SocketAddr, config: DnsRuleConfig) -> TestForwarder {
+        let forwarder = DNSForwarder::new(&[], "dns", &[upstream.to_string()], &config);
+        let handler = Arc::new(forwarder.build_handler().await.unwrap());
+        let stats = Arc::new(RwLock::new(ConnectionStats::default()));
+        let running = Arc::new(RwLock::new(true));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let forwarder = TestForwarder {
+            udp: socket.local_addr().unwrap(),
+            tcp: listener.local_addr().unwrap(),
+            handler: handler.clone(),
+        };
+        tokio::spawn(DNSForwarder::serve_udp(
+            socket,
+            handler.clone(),
+            stats.clone(),
+            running.clone(),
+        ));
+        tokio::spawn(DNSForwarder::serve_tcp(listener, handler, stats, running));
+        forwarder
+    }
+
+    fn query(name: &str, record_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), record_type));
+        message
+    }
+
+    async fn query_udp(server: SocketAddr, request: &Message) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(&request.to_vec().unwrap(), server)
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 4096];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+            .await
+            .expect("DNS/UDP应答超时")
+            .unwrap();
+        let response = Message::from_vec(&buffer[..len]).unwrap();
+        assert_eq!(response.id(), request.id());
+        response
+    }
+
+    fn answer_ips(response: &Message) -> Vec<IpAddr> {
+        response
+            .answers()
+            .iter()
+            .filter_map(|record| record.data()?.ip_addr())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_udp_forwarding_and_cache() {
+        let (upstream, upstream_queries) = fake_upstream().await;
+        let forwarder = start_forwarder(upstream, DnsRuleConfig::default()).await;
+
+        for _ in 0..2 {
+            let response = query_udp(forwarder.udp, &query("www.example.", RecordType::A)).await;
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert!(!response.truncated());
+            assert_eq!(answer_ips(&response), vec![IpAddr::from([10, 0, 0, 1])]);
+            assert!(response.answers()[0].ttl() <= 60);
+        }
+        // 第二次查询由缓存应答
+        assert_eq!(upstream_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            forwarder.handler.stats.cache_hits.load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            forwarder.handler.stats.cache_misses.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    // UDP应答超过512字节时设置TC位，客户端改用TCP（2字节长度前缀）取得完整应答
+    #[tokio::test]
+    async fn test_truncated_udp_retried_over_tcp() {
+        let (upstream, _) = fake_upstream().await;
+        let ips = (1..=40).map(|i| format!("10.0.1.{}", i)).collect();
+        let config = DnsRuleConfig {
+            overrides: Some(HashMap::from([("big.lan".to_string(), ips)])),
+            ..Default::default()
+        };
+        let forwarder = start_forwarder(upstream, config).await;
+
+        let request = query("big.lan.", RecordType::A);
+        let response = query_udp(forwarder.udp, &request).await;
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+
+        // 客户端通过EDNS声明更大的UDP缓冲区时不截断
+        let mut edns_request = query("big.lan.", RecordType::A);
+        let mut edns = Edns::new();
+        edns.set_max_payload(4096);
+        edns_request.set_edns(edns);
+        let response = query_udp(forwarder.udp, &edns_request).await;
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 40);
+
+        let mut stream = TcpStream::connect(forwarder.tcp).await.unwrap();
+        // 同一连接上连续两次查询
+        for request in [request, query("www.example.", RecordType::A)] {
+            let packet = request.to_vec().unwrap();
+            stream.write_u16(packet.len() as u16).await.unwrap();
+            stream.write_all(&packet).await.unwrap();
+            let len = stream.read_u16().await.unwrap() as usize;
+            let mut buffer = vec![0u8; len];
+            stream.read_exact(&mut buffer).await.unwrap();
+            let response = Message::from_vec(&buffer).unwrap();
+            assert_eq!(response.id(), request.id());
+            assert!(!response.truncated());
+            let expected = if request.queries()[0].name().to_ascii() == "big.lan." {
+                40
+            } else {
+                1
+            };
+            assert_eq!(response.answers().len(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_overrides() {
+        let (upstream, upstream_queries) = fake_upstream().await;
+        let config = DnsRuleConfig {
+            overrides: Some(HashMap::from([(
+                "NAS.lan".to_string(),
+                vec!["192.168.1.10".to_string(), "fd00::10".to_string()],
+            )])),
+            override_ttl: Some(120),
+            cache_size: None,
+        };
+        let forwarder = start_forwarder(upstream, config).await;
+
+        // 域名大小写不敏感，按查询类型返回对应的地址
+        let response = query_udp(forwarder.udp, &query("nas.LAN.", RecordType::A)).await;
+        assert_eq!(answer_ips(&response), vec![IpAddr::from([192, 168, 1, 10])]);
+        assert_eq!(response.answers()[0].ttl(), 120);
+        let response = query_udp(forwarder.udp, &query("nas.lan.", RecordType::AAAA)).await;
+        assert_eq!(
+            answer_ips(&response),
+            vec!["fd00::10".parse::<IpAddr>().unwrap()]
+        );
+        // 静态解析的域名不转发其他类型的查询
+        let response = query_udp(forwarder.udp, &query("nas.lan.", RecordType::MX)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+
+        assert_eq!(upstream_queries.load(Ordering::Relaxed), 0);
+        assert_eq!(forwarder.handler.stats.overrides.load(Ordering::Relaxed), 3);
+    }
+}
@@ -1,15 +1,31 @@
 // 智能网络转发器 - 完整转发器实现
-use crate::common::CommonManager;
-use crate::config::{Config, ForwardRule};
-use crate::utils::{get_standard_stats, get_stats_with_target, ConnectionStats};
+use crate::common::{CommonManager, TargetSelection, TargetSelector};
+use crate::config::{Config, DynamicUpdateConfig, ForwardRule, NoHealthyAction};
+use crate::dns_forwarder::DNSForwarder;
+use crate::knock::KnockGate;
+use crate::listener::{
+    bind_tcp, bind_udp, format_bind_results, format_listen_addrs, BindResult, ListenAddr,
+    StreamListener,
+};
+use crate::resolver;
+use crate::sniff::{peek_host, SniffAction, Sniffer};
+use crate::transparent::TransparentForwarder;
+use crate::tunnel::{TunnelClient, TunnelServer};
+use crate::utils::{
+    connect_addrs, format_addrs, get_standard_stats, get_stats_with_target, reset_stream,
+    resolve_first_target, resolve_target, BoxedStream, ConnectionStats, ConnectionTimeouts, Expiry,
+    TargetAddr,
+};
 use anyhow::Result;
 use async_trait::async_trait;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::RwLock;
 
 // ================================
@@ -27,74 +43,217 @@ pub trait Forwarder: Send + Sync {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
 }
 
+// 逐个绑定监听地址：单个地址失败只记录结果，全部失败才返回错误
+pub fn bind_all<L>(
+    kind: &str,
+    name: &str,
+    listen_addrs: &[ListenAddr],
+    bind: impl Fn(&ListenAddr) -> Result<L>,
+) -> Result<(Vec<L>, Vec<BindResult>)> {
+    let mut listeners = Vec::new();
+    let mut results = Vec::new();
+
+    for listen_addr in listen_addrs {
+        match bind(listen_addr) {
+            Ok(listener) => {
+                info!("{}监听器 {} 绑定成功: {}", kind, name, listen_addr);
+                listeners.push(listener);
+                results.push(BindResult {
+                    addr: listen_addr.to_string(),
+                    error: None,
+                });
+            }
+            Err(e) => {
+                warn!("{}监听器 {} 绑定失败 {}: {}", kind, name, listen_addr, e);
+                results.push(BindResult {
+                    addr: listen_addr.to_string(),
+                    error: Some(e.to_string()),
+                });
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        anyhow::bail!(
+            "{}监听器 {} 绑定失败: {}",
+            kind,
+            name,
+            format_bind_results(&results)
+        );
+    }
+
+    Ok((listeners, results))
+}
+
 // ================================
 // TCP 转发器
 // ================================
 pub struct TCPForwarder {
-    listen_addr: String,
+    listen_addrs: Vec<ListenAddr>,
     name: String,
     buffer_size: usize,
-    target_addr: Arc<RwLock<String>>,
+    target_addr: Arc<RwLock<Vec<TargetAddr>>>,
     stats: Arc<RwLock<ConnectionStats>>,
     running: Arc<RwLock<bool>>,
+    bind_results: Vec<BindResult>,
+    sniffer: Option<Arc<Sniffer>>,
+    gate: Option<Arc<KnockGate>>,
+    selector: Option<TargetSelector>,
+    timeouts: ConnectionTimeouts,
+}
+
+// 按 SNI/Host 选择目标时等待客户端首批数据的时间，超时按客户端IP哈希
+const HOST_PEEK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// 单个目标的连接超时
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// hold 期间检查目标是否恢复的间隔
+const HOLD_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// static_response 发送后等待客户端关闭的时间，避免未读的请求数据触发RST冲掉应答
+const STATIC_RESPONSE_LINGER: Duration = Duration::from_secs(2);
+
+// 一条TCP连接两个方向共享的活动记录：用于判断空闲超时，连接被超时关闭时也不丢失已转发的字节数
+struct RelayActivity {
+    start: Instant,
+    last_active_ms: AtomicU64, // 最近一次收到数据时距 start 的毫秒数
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl RelayActivity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, bytes: u64, is_sent: bool) {
+        let counter = if is_sent { &self.sent } else { &self.received };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+        self.last_active_ms
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.start.elapsed().saturating_sub(Duration::from_millis(
+            self.last_active_ms.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+// 连接的目标来源：按负载均衡策略逐连接选择，或使用固定目标
+#[derive(Clone)]
+enum TargetSource {
+    Selector(TargetSelector),
+    Fixed(Vec<TargetAddr>),
 }
 
 impl TCPForwarder {
-    pub fn new(listen_addr: &str, name: &str, buffer_size: usize) -> Self {
+    pub fn new(listen_addrs: &[ListenAddr], name: &str, buffer_size: usize) -> Self {
         Self {
-            listen_addr: listen_addr.to_string(),
+            listen_addrs: listen_addrs.to_vec(),
             name: name.to_string(),
             buffer_size,
-            target_addr: Arc::new(RwLock::new(String::new())),
+            target_addr: Arc::new(RwLock::new(Vec::new())),
             stats: Arc::new(RwLock::new(ConnectionStats::default())),
             running: Arc::new(RwLock::new(false)),
+            bind_results: Vec::new(),
+            sniffer: None,
+            gate: None,
+            selector: None,
+            timeouts: ConnectionTimeouts::from_config(&DynamicUpdateConfig::default()),
         }
     }
 
-    pub async fn start_with_target(&mut self, target: &str) -> Result<()> {
-        *self.target_addr.write().await = target.to_string();
+    // 由公共管理器按规则策略为每个连接选择目标
+    pub fn set_selector(&mut self, selector: TargetSelector) {
+        self.selector = Some(selector);
+    }
+
+    // 按规则配置的空闲超时和最长存活时间关闭连接
+    pub fn set_timeouts(&mut self, timeouts: ConnectionTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    // 启用敲门放行：来源IP未通过认证的连接直接关闭
+    pub fn set_gate(&mut self, gate: Arc<KnockGate>) {
+        self.gate = Some(gate);
+    }
+
+    // 启用协议嗅探：按识别出的协议选择目标或处理器
+    pub fn set_sniffer(&mut self, sniffer: Sniffer) {
+        self.sniffer = Some(Arc::new(sniffer));
+    }
+
+    pub async fn start_with_target(&mut self, target: &[TargetAddr]) -> Result<()> {
+        *self.target_addr.write().await = target.to_vec();
         *self.running.write().await = true;
 
-        let listener = match TcpListener::bind(&self.listen_addr).await {
-            Ok(listener) => {
-                log::info!("TCP监听器 {} 绑定成功: {}", self.name, self.listen_addr);
-                listener
-            }
-            Err(e) => {
-                return Err(anyhow::anyhow!(
-                    "TCP监听器 {} 绑定失败 {}: {}",
-                    self.name,
-                    self.listen_addr,
-                    e
-                ));
-            }
-        };
+        let (listeners, bind_results) =
+            bind_all("TCP", &self.name, &self.listen_addrs, StreamListener::bind)?;
+        self.bind_results = bind_results;
+
+        for listener in listeners {
+            self.spawn_accept_loop(listener);
+        }
+
+        Ok(())
+    }
+
+    fn spawn_accept_loop(&self, listener: StreamListener) {
         let target_addr = self.target_addr.clone();
         let stats = self.stats.clone();
         let running = self.running.clone();
         let name = self.name.clone();
         let buffer_size = self.buffer_size;
+        let sniffer = self.sniffer.clone();
+        let gate = self.gate.clone();
+        let selector = self.selector.clone();
+        let timeouts = self.timeouts;
 
         tokio::spawn(async move {
             while *running.read().await {
                 match listener.accept().await {
-                    Ok((stream, _)) => {
-                        let target_str = target_addr.read().await.clone();
+                    Ok((stream, peer)) => {
+                        // 未放行的来源不做任何回应；Unix套接字没有来源IP，同样拒绝
+                        if let Some(gate) = &gate {
+                            if !peer.is_some_and(|peer| gate.check(peer.ip())) {
+                                drop(stream);
+                                continue;
+                            }
+                        }
+
+                        let source = match &selector {
+                            Some(selector) => TargetSource::Selector(selector.clone()),
+                            None => TargetSource::Fixed(target_addr.read().await.clone()),
+                        };
                         let stats = stats.clone();
                         let rule_name = name.clone();
+                        let sniffer = sniffer.clone();
 
                         tokio::spawn(async move {
-                            if (Self::handle_connection(
+                            if let Err(e) = Self::dispatch_connection(
                                 stream,
-                                &target_str,
+                                peer,
+                                source,
+                                sniffer,
                                 buffer_size,
+                                timeouts,
                                 stats,
                                 &rule_name,
                             )
-                            .await)
-                                .is_err()
+                            .await
                             {
-                                // 连接处理失败，但不记录详细错误
+                                warn!(
+                                    "规则 {} 处理来自 {} 的连接失败: {}",
+                                    rule_name,
+                                    peer.map_or("Unix套接字".to_string(), |p| p.to_string()),
+                                    e
+                                );
                             }
                         });
                     }
@@ -108,115 +267,414 @@ impl TCPForwarder {
                 }
             }
         });
+    }
 
+    pub async fn update_target(&mut self, new_target: &[TargetAddr]) -> Result<()> {
+        *self.target_addr.write().await = new_target.to_vec();
         Ok(())
     }
 
-    pub async fn update_target(&mut self, new_target: &str) -> Result<()> {
-        *self.target_addr.write().await = new_target.to_string();
+    // 未启用嗅探时直接转发到规则目标
+    #[allow(clippy::too_many_arguments)]
+    async fn dispatch_connection(
+        client_stream: BoxedStream,
+        peer: Option<SocketAddr>,
+        source: TargetSource,
+        sniffer: Option<Arc<Sniffer>>,
+        buffer_size: usize,
+        timeouts: ConnectionTimeouts,
+        stats: Arc<RwLock<ConnectionStats>>,
+        rule_name: &str,
+    ) -> Result<()> {
+        let Some(sniffer) = sniffer else {
+            return Self::forward_to_source(
+                client_stream,
+                peer,
+                source,
+                buffer_size,
+                timeouts,
+                stats,
+                rule_name,
+            )
+            .await;
+        };
+
+        let (action, client_stream) = sniffer.sniff(client_stream).await;
+        match action {
+            SniffAction::Default => {
+                Self::forward_to_source(
+                    client_stream,
+                    peer,
+                    source,
+                    buffer_size,
+                    timeouts,
+                    stats,
+                    rule_name,
+                )
+                .await
+            }
+            SniffAction::Targets(targets) => {
+                let addrs = resolve_first_target(&targets).await?;
+                Self::handle_connection(
+                    client_stream,
+                    &addrs,
+                    buffer_size,
+                    timeouts,
+                    stats,
+                    rule_name,
+                )
+                .await
+            }
+            SniffAction::Redirect => HTTPForwarder::handle_http_redirect(client_stream).await,
+        }
+    }
+
+    async fn forward_to_source(
+        client_stream: BoxedStream,
+        peer: Option<SocketAddr>,
+        source: TargetSource,
+        buffer_size: usize,
+        timeouts: ConnectionTimeouts,
+        stats: Arc<RwLock<ConnectionStats>>,
+        rule_name: &str,
+    ) -> Result<()> {
+        match source {
+            TargetSource::Fixed(target_addrs) => {
+                Self::handle_connection(
+                    client_stream,
+                    &target_addrs,
+                    buffer_size,
+                    timeouts,
+                    stats,
+                    rule_name,
+                )
+                .await
+            }
+            TargetSource::Selector(selector) => {
+                let Some(client_stream) = Self::apply_no_healthy_action(
+                    client_stream,
+                    &selector,
+                    buffer_size,
+                    timeouts,
+                    &stats,
+                    rule_name,
+                )
+                .await?
+                else {
+                    return Ok(());
+                };
+                let (host, client_stream) = if selector.wants_host() {
+                    peek_host(client_stream, HOST_PEEK_TIMEOUT).await
+                } else {
+                    (None, client_stream)
+                };
+                // selection 在连接结束前一直持有，计入目标的活跃连接数
+                let mut selection = selector.select(peer, host.as_deref()).await?;
+                stats.write().await.increment_connections();
+
+                // 连接失败或超时时在总时限内按目标顺序改连下一个目标；
+                // 真实流量的连接失败和立即重置反馈给公共管理器（被动健康检查）
+                let deadline = Instant::now() + selector.connect_deadline();
+                let mut tried = Vec::new();
+                let target_stream = loop {
+                    let attempt = tried.len() + 1;
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    let result =
+                        Self::connect_target(&selection.addrs, remaining.min(CONNECT_TIMEOUT))
+                            .await;
+                    stats
+                        .write()
+                        .await
+                        .record_connect_attempt(result.is_ok(), attempt > 1);
+
+                    let e = match result {
+                        Ok(stream) => {
+                            if attempt > 1 {
+                                info!(
+                                    "规则 {} 第{}次尝试连接 {} 成功",
+                                    rule_name, attempt, selection.target
+                                );
+                            }
+                            break stream;
+                        }
+                        Err(e) => e,
+                    };
+                    selector.report(&selection.target, false);
+                    tried.push(selection.target.clone());
+                    let next = if tried.len() > selector.connect_retries() as usize
+                        || Instant::now() >= deadline
+                    {
+                        None
+                    } else {
+                        selector.select_next(&tried).await
+                    };
+                    let Some(next) = next else {
+                        info!(
+                            "规则 {} 第{}次尝试连接 {} 失败: {}，不再重试",
+                            rule_name, attempt, selection.target, e
+                        );
+                        return Err(e);
+                    };
+                    info!(
+                        "规则 {} 第{}次尝试连接 {} 失败: {}，改连 {}",
+                        rule_name, attempt, selection.target, e, next.target
+                    );
+                    selection = next;
+                };
+                let reset_early =
+                    Self::relay(client_stream, target_stream, buffer_size, timeouts, &stats).await;
+                selector.report(&selection.target, !reset_early);
+                Ok(())
+            }
+        }
+    }
+
+    // 规则配置了 on_no_healthy_target 且没有健康目标时处理连接；返回连接表示按正常流程选择目标
+    async fn apply_no_healthy_action(
+        client_stream: BoxedStream,
+        selector: &TargetSelector,
+        buffer_size: usize,
+        timeouts: ConnectionTimeouts,
+        stats: &Arc<RwLock<ConnectionStats>>,
+        rule_name: &str,
+    ) -> Result<Option<BoxedStream>> {
+        let Some(action) = selector.no_healthy_action() else {
+            return Ok(Some(client_stream));
+        };
+        if selector.has_healthy().await {
+            return Ok(Some(client_stream));
+        }
+        stats.write().await.no_healthy_target += 1;
+
+        match action {
+            NoHealthyAction::Reject => {
+                debug!("规则 {} 没有健康目标，拒绝连接", rule_name);
+                reset_stream(client_stream);
+            }
+            NoHealthyAction::Hold(hold) => {
+                let deadline = Instant::now() + *hold;
+                while Instant::now() < deadline {
+                    tokio::time::sleep(HOLD_POLL_INTERVAL).await;
+                    if selector.has_healthy().await {
+                        return Ok(Some(client_stream));
+                    }
+                }
+                debug!(
+                    "规则 {} 等待{}秒仍没有健康目标，关闭连接",
+                    rule_name,
+                    hold.as_secs()
+                );
+                reset_stream(client_stream);
+            }
+            NoHealthyAction::StaticResponse(response) => {
+                Self::send_static_response(client_stream, response).await?;
+            }
+            NoHealthyAction::FallbackTarget(target) => {
+                let addrs = resolve_target(target).await?;
+                debug!("规则 {} 没有健康目标，转发到兜底目标 {}", rule_name, target);
+                Self::handle_connection(
+                    client_stream,
+                    &addrs,
+                    buffer_size,
+                    timeouts,
+                    stats.clone(),
+                    rule_name,
+                )
+                .await?;
+            }
+        }
+        Ok(None)
+    }
+
+    // 发送固定内容后半关闭，读完客户端已发送的数据再关闭
+    async fn send_static_response(mut client_stream: BoxedStream, response: &[u8]) -> Result<()> {
+        client_stream.write_all(response).await?;
+        client_stream.shutdown().await?;
+        let mut buffer = [0u8; 1024];
+        let _ = tokio::time::timeout(STATIC_RESPONSE_LINGER, async {
+            while matches!(client_stream.read(&mut buffer).await, Ok(n) if n > 0) {}
+        })
+        .await;
         Ok(())
     }
 
-    async fn handle_connection(
-        mut client_stream: TcpStream,
-        target_addr: &str,
+    pub(crate) async fn handle_connection(
+        client_stream: BoxedStream,
+        target_addrs: &[TargetAddr],
         buffer_size: usize,
+        timeouts: ConnectionTimeouts,
         stats: Arc<RwLock<ConnectionStats>>,
         _rule_name: &str,
     ) -> Result<()> {
-        let target: std::net::SocketAddr = crate::utils::resolve_target(target_addr).await?;
+        if target_addrs.is_empty() {
+            anyhow::bail!("没有可用的目标地址");
+        }
 
         stats.write().await.increment_connections();
 
-        // 优化TCP：降低延迟
-        let _ = client_stream.set_nodelay(true);
-
-        // 直接连接，不重试（让健康检查快速切换到正确地址）
-        let mut target_stream = match tokio::time::timeout(
-            tokio::time::Duration::from_secs(5),
-            tokio::net::TcpStream::connect(target),
-        )
-        .await
-        {
-            Ok(Ok(stream)) => stream,
-            Ok(Err(e)) => return Err(anyhow::anyhow!("连接目标失败: {}", e)),
-            Err(_) => return Err(anyhow::anyhow!("连接目标超时")),
-        };
+        let target_stream = Self::connect_target(target_addrs, CONNECT_TIMEOUT).await?;
+        Self::relay(client_stream, target_stream, buffer_size, timeouts, &stats).await;
+        Ok(())
+    }
 
-        // 目标侧同样禁用Nagle算法
-        let _ = target_stream.set_nodelay(true);
+    // 在单个目标的全部解析地址间做Happy Eyeballs，目标之间的重试由调用方按 connect_retries 决定
+    async fn connect_target(target_addrs: &[TargetAddr], timeout: Duration) -> Result<BoxedStream> {
+        if target_addrs.is_empty() {
+            anyhow::bail!("没有可用的目标地址");
+        }
+        match tokio::time::timeout(timeout, connect_addrs(target_addrs)).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(e)) => Err(anyhow::anyhow!("连接目标失败: {}", e)),
+            Err(_) => Err(anyhow::anyhow!("连接目标超时")),
+        }
+    }
 
-        let (mut client_read, mut client_write) = client_stream.split();
-        let (mut target_read, mut target_write) = target_stream.split();
+    // 双向转发，返回目标是否在发送任何数据之前就重置了连接；空闲超时或达到最长存活时间时关闭两端
+    async fn relay(
+        client_stream: BoxedStream,
+        target_stream: BoxedStream,
+        buffer_size: usize,
+        timeouts: ConnectionTimeouts,
+        stats: &Arc<RwLock<ConnectionStats>>,
+    ) -> bool {
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+        let (mut target_read, mut target_write) = tokio::io::split(target_stream);
 
         let mut client_buffer = vec![0u8; buffer_size];
         let mut target_buffer = vec![0u8; buffer_size];
+        let activity = RelayActivity::new();
+
+        // 连接断开是正常现象，不记录错误日志，减少日志噪音
+        let transfer = async {
+            tokio::join!(
+                Self::forward_data(
+                    &mut client_read,
+                    &mut target_write,
+                    &mut client_buffer,
+                    &activity,
+                    true
+                ),
+                Self::forward_data(
+                    &mut target_read,
+                    &mut client_write,
+                    &mut target_buffer,
+                    &activity,
+                    false
+                ),
+            )
+        };
+        let (target_reset, expiry) = tokio::select! {
+            (_, target_reset) = transfer => (target_reset, None),
+            expiry = Self::wait_expiry(&activity, timeouts) => (false, Some(expiry)),
+        };
 
-        let (client_to_target, target_to_client) = tokio::join!(
-            Self::forward_data(
-                &mut client_read,
-                &mut target_write,
-                &mut client_buffer,
-                &stats,
-                true
-            ),
-            Self::forward_data(
-                &mut target_read,
-                &mut client_write,
-                &mut target_buffer,
-                &stats,
-                false
-            ),
-        );
-
-        // 简化错误处理，连接断开是正常现象，减少日志噪音
-        if client_to_target.is_err() {
-            // 连接断开不记录错误日志
-        }
-        if target_to_client.is_err() {
-            // 连接断开不记录错误日志
+        // 批量更新统计信息，减少锁竞争
+        let sent = activity.sent.load(Ordering::Relaxed);
+        let received = activity.received.load(Ordering::Relaxed);
+        let mut stats = stats.write().await;
+        stats.add_bytes_sent(sent);
+        stats.add_bytes_received(received);
+        if let Some(expiry) = expiry {
+            debug!(
+                "连接{}关闭，已存活{}秒",
+                match expiry {
+                    Expiry::Idle => "空闲超时",
+                    Expiry::Lifetime => "达到最长存活时间",
+                },
+                activity.start.elapsed().as_secs()
+            );
+            stats.record_expiry(expiry);
         }
 
-        Ok(())
+        target_reset && received == 0
+    }
+
+    // 等待连接空闲超时或达到最长存活时间，两者都未配置时永不返回
+    async fn wait_expiry(activity: &RelayActivity, timeouts: ConnectionTimeouts) -> Expiry {
+        loop {
+            let idle_for = activity.idle_for();
+            let age = activity.start.elapsed();
+            if let Some(expiry) = timeouts.tcp_expiry(idle_for, age) {
+                return expiry;
+            }
+
+            // 睡到最早可能到期的时间，期间有数据则醒来后重新计算
+            let wait = [
+                timeouts.idle.map(|idle| idle - idle_for),
+                timeouts.lifetime.map(|lifetime| lifetime - age),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => std::future::pending().await,
+            }
+        }
     }
 
+    // 单向转发，返回读取端是否被对方重置
     async fn forward_data<R, W>(
         reader: &mut R,
         writer: &mut W,
         buffer: &mut [u8],
-        stats: &Arc<RwLock<ConnectionStats>>,
+        activity: &RelayActivity,
         is_sent: bool,
-    ) -> Result<()>
+    ) -> bool
     where
         R: tokio::io::AsyncRead + Unpin,
         W: tokio::io::AsyncWrite + Unpin,
     {
-        let mut total_bytes = 0u64;
         loop {
-            let n = reader.read(buffer).await?;
-            if n == 0 {
-                break;
-            }
-
-            writer.write_all(&buffer[..n]).await?;
-            total_bytes += n as u64;
-        }
+            let n = match reader.read(buffer).await {
+                Ok(0) => {
+                    // 把半关闭传给对端，否则对端等不到EOF，连接（及活跃连接计数）一直保持
+                    let _ = writer.shutdown().await;
+                    return false;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    return matches!(
+                        e.kind(),
+                        std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted
+                    );
+                }
+            };
 
-        // 批量更新统计信息，减少锁竞争
-        if total_bytes > 0 {
-            if is_sent {
-                stats.write().await.add_bytes_sent(total_bytes);
-            } else {
-                stats.write().await.add_bytes_received(total_bytes);
+            if writer.write_all(&buffer[..n]).await.is_err() {
+                return false;
             }
+            activity.record(n as u64, is_sent);
         }
-
-        Ok(())
     }
 
     pub fn get_stats(&self) -> HashMap<String, String> {
         let stats = self.stats.blocking_read();
-        get_standard_stats(&stats)
+        let mut result = get_standard_stats(&stats);
+        result.insert(
+            "listeners".to_string(),
+            format_bind_results(&self.bind_results),
+        );
+        result.insert(
+            "connect_attempts".to_string(),
+            stats.connect_attempts.to_string(),
+        );
+        result.insert(
+            "connect_failures".to_string(),
+            stats.connect_failures.to_string(),
+        );
+        result.insert(
+            "connect_retries".to_string(),
+            stats.connect_retries.to_string(),
+        );
+        result.insert(
+            "no_healthy_target".to_string(),
+            stats.no_healthy_target.to_string(),
+        );
+        if let Some(sniffer) = &self.sniffer {
+            sniffer.write_stats(&mut result);
+        }
+        result
     }
 }
 
@@ -250,24 +708,55 @@ impl Forwarder for TCPForwarder {
 // ================================
 // HTTP 转发器
 // ================================
+fn bind_tcp_listen_addr(listen_addr: &ListenAddr) -> Result<TcpListener> {
+    match listen_addr {
+        ListenAddr::Socket {
+            addr,
+            device,
+            v6_only,
+        } => bind_tcp(addr, device.as_deref(), *v6_only),
+        ListenAddr::Unix(_) => anyhow::bail!("HTTP跳转不支持Unix套接字监听"),
+    }
+}
+
 pub struct HTTPForwarder {
-    listen_addr: String,
+    listen_addrs: Vec<ListenAddr>,
     name: String,
     running: Arc<RwLock<bool>>,
+    bind_results: Vec<BindResult>,
+    gate: Option<Arc<KnockGate>>,
 }
 
 impl HTTPForwarder {
-    pub fn new(listen_addr: &str, name: &str, _buffer_size: usize) -> Self {
+    pub fn new(listen_addrs: &[ListenAddr], name: &str, _buffer_size: usize) -> Self {
         Self {
-            listen_addr: listen_addr.to_string(),
+            listen_addrs: listen_addrs.to_vec(),
             name: name.to_string(),
             running: Arc::new(RwLock::new(false)),
+            bind_results: Vec::new(),
+            gate: None,
         }
     }
 
-    async fn handle_http_redirect(mut stream: TcpStream) -> Result<()> {
+    // 敲门放行前静默关闭连接
+    pub fn set_gate(&mut self, gate: Arc<KnockGate>) {
+        self.gate = Some(gate);
+    }
+
+    pub(crate) async fn handle_http_redirect<S>(mut stream: S) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        // 读取到请求头结束（嗅探后的流首次读取只返回已嗅探的字节）
         let mut buffer = [0; 1024];
-        let n = stream.read(&mut buffer).await?;
+        let mut n = 0;
+        while n < buffer.len() && !buffer[..n].windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer[n..])).await
+            {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+                Ok(Ok(read)) => n += read,
+            }
+        }
 
         if n > 0 {
             let request = String::from_utf8_lossy(&buffer[..n]);
@@ -300,33 +789,30 @@ impl Forwarder for HTTPForwarder {
     async fn start(&mut self) -> Result<()> {
         *self.running.write().await = true;
 
-        let listener = match TcpListener::bind(&self.listen_addr).await {
-            Ok(listener) => {
-                log::info!("HTTP监听器绑定成功: {}", self.listen_addr);
-                listener
-            }
-            Err(e) => {
-                return Err(anyhow::anyhow!(
-                    "HTTP监听器绑定失败 {}: {}",
-                    self.listen_addr,
-                    e
-                ));
-            }
-        };
-        let running = self.running.clone();
+        let (listeners, bind_results) =
+            bind_all("HTTP", &self.name, &self.listen_addrs, bind_tcp_listen_addr)?;
+        self.bind_results = bind_results;
 
-        tokio::spawn(async move {
-            while *running.read().await {
-                match listener.accept().await {
-                    Ok((stream, _)) => {
-                        tokio::spawn(async move {
-                            let _ = Self::handle_http_redirect(stream).await;
-                        });
+        for listener in listeners {
+            let running = self.running.clone();
+            let gate = self.gate.clone();
+
+            tokio::spawn(async move {
+                while *running.read().await {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            if gate.as_ref().is_some_and(|gate| !gate.check(peer.ip())) {
+                                continue;
+                            }
+                            tokio::spawn(async move {
+                                let _ = Self::handle_http_redirect(stream).await;
+                            });
+                        }
+                        Err(_) => break,
                     }
-                    Err(_) => break,
                 }
-            }
-        });
+            });
+        }
 
         Ok(())
     }
@@ -344,6 +830,10 @@ impl Forwarder for HTTPForwarder {
         stats.insert("name".to_string(), self.name.clone());
         stats.insert("type".to_string(), "HTTP Redirect".to_string());
         stats.insert("running".to_string(), self.is_running().to_string());
+        stats.insert(
+            "listeners".to_string(),
+            format_bind_results(&self.bind_results),
+        );
         stats
     }
 
@@ -360,20 +850,28 @@ impl Forwarder for HTTPForwarder {
 // UDP 转发器 - 基于原版优化实现
 // ================================
 pub struct UDPForwarder {
-    listen_addr: String,
+    listen_addrs: Vec<ListenAddr>,
     name: String,
     buffer_size: usize,
-    target_addr: Arc<RwLock<String>>,
+    target_addr: Arc<RwLock<Vec<TargetAddr>>>,
     stats: Arc<RwLock<ConnectionStats>>,
     running: Arc<RwLock<bool>>,
     sessions: Arc<RwLock<HashMap<std::net::SocketAddr, UdpSession>>>,
+    bind_results: Vec<BindResult>,
+    selector: Option<TargetSelector>,
+    timeouts: ConnectionTimeouts,
+    gate: Option<Arc<KnockGate>>,
 }
 
 // UDP会话结构
 struct UdpSession {
     upstream: Option<Arc<UdpSocket>>,
     target: std::net::SocketAddr,
+    created_at: std::time::Instant,
     last_seen: std::time::Instant,
+    selection: Option<TargetSelection>, // 按策略为会话选择的目标
+    fallback: Option<SocketAddr>,       // 没有健康目标时使用的兜底目标
+    reply_task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl UdpSession {
@@ -381,68 +879,134 @@ impl UdpSession {
         Self {
             upstream: None,
             target: "0.0.0.0:0".parse().unwrap(),
+            created_at: std::time::Instant::now(),
             last_seen: std::time::Instant::now(),
+            selection: None,
+            fallback: None,
+            reply_task: None,
+        }
+    }
+}
+
+// 会话移除或更换上游时结束回程任务，释放上游套接字
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        if let Some(task) = &self.reply_task {
+            task.abort();
         }
     }
 }
 
+// 本次数据报的转发去向，在获取会话表写锁之前确定
+enum UdpRoute {
+    Fallback(SocketAddr),    // 没有健康目标时转发到兜底目标
+    Select(TargetSelection), // 新选择的目标
+    Keep,                    // 会话已选目标仍可用
+    Static(SocketAddr),      // 未使用选择器时的固定目标
+}
+
+// UDP取目标的首个网络地址（已按健康状态排序）
+fn first_inet_addr(addrs: &[TargetAddr]) -> Option<SocketAddr> {
+    addrs.iter().find_map(|addr| match addr {
+        TargetAddr::Inet(addr) => Some(*addr),
+        // UDP不支持Unix套接字目标（配置校验阶段已拦截）
+        TargetAddr::Unix(_) => None,
+    })
+}
+
+// 按目标地址族绑定上游socket并连接，IPv6目标需要IPv6套接字
+async fn connect_udp_upstream(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let bind_addr: SocketAddr = if target.is_ipv6() {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let upstream = UdpSocket::bind(bind_addr).await?;
+    upstream.connect(target).await?;
+    Ok(upstream)
+}
+
 impl UDPForwarder {
-    pub fn new(listen_addr: &str, name: &str, buffer_size: usize) -> Self {
+    pub fn new(listen_addrs: &[ListenAddr], name: &str, buffer_size: usize) -> Self {
         Self {
-            listen_addr: listen_addr.to_string(),
+            listen_addrs: listen_addrs.to_vec(),
             name: name.to_string(),
             buffer_size,
-            target_addr: Arc::new(RwLock::new(String::new())),
+            target_addr: Arc::new(RwLock::new(Vec::new())),
             stats: Arc::new(RwLock::new(ConnectionStats::default())),
             running: Arc::new(RwLock::new(false)),
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            bind_results: Vec::new(),
+            selector: None,
+            timeouts: ConnectionTimeouts::from_config(&DynamicUpdateConfig::default()),
+            gate: None,
         }
     }
 
-    pub async fn start_with_target(&mut self, target: &str) -> Result<()> {
-        *self.target_addr.write().await = target.to_string();
-        *self.running.write().await = true;
+    // 由公共管理器按规则策略为每个会话选择目标
+    pub fn set_selector(&mut self, selector: TargetSelector) {
+        self.selector = Some(selector);
+    }
 
-        let socket = match UdpSocket::bind(&self.listen_addr).await {
-            Ok(socket) => {
-                log::info!("UDP监听器绑定成功: {}", self.listen_addr);
-                socket
-            }
-            Err(e) => {
-                return Err(anyhow::anyhow!(
-                    "UDP监听器绑定失败 {}: {}",
-                    self.listen_addr,
-                    e
-                ));
-            }
-        };
+    // 敲门放行前静默丢弃新会话的数据报，已建立的会话不受影响
+    pub fn set_gate(&mut self, gate: Arc<KnockGate>) {
+        self.gate = Some(gate);
+    }
 
-        // 启动主转发循环
-        let stats = self.stats.clone();
-        let running = self.running.clone();
-        let target_addr = self.target_addr.clone();
-        let sessions = self.sessions.clone();
-        let buffer_size = self.buffer_size;
-        let name = self.name.clone();
+    // 按规则配置的空闲超时和最长存活时间清理会话
+    pub fn set_timeouts(&mut self, timeouts: ConnectionTimeouts) {
+        self.timeouts = timeouts;
+    }
 
-        tokio::spawn(async move {
-            Self::udp_forward_loop(
-                socket,
-                buffer_size,
-                name,
-                stats,
-                running,
-                target_addr,
-                sessions,
-            )
-            .await;
-        });
+    pub async fn start_with_target(&mut self, target: &[TargetAddr]) -> Result<()> {
+        *self.target_addr.write().await = target.to_vec();
+        *self.running.write().await = true;
 
-        // 启动会话清理任务
+        let (sockets, bind_results) =
+            bind_all("UDP", &self.name, &self.listen_addrs, |addr| match addr {
+                ListenAddr::Socket {
+                    addr,
+                    device,
+                    v6_only,
+                } => bind_udp(addr, device.as_deref(), *v6_only),
+                ListenAddr::Unix(_) => anyhow::bail!("UDP不支持Unix套接字监听"),
+            })?;
+        self.bind_results = bind_results;
+
+        // 每个监听套接字一个主转发循环，共享会话表
+        for socket in sockets {
+            let stats = self.stats.clone();
+            let running = self.running.clone();
+            let target_addr = self.target_addr.clone();
+            let sessions = self.sessions.clone();
+            let buffer_size = self.buffer_size;
+            let selector = self.selector.clone();
+            let gate = self.gate.clone();
+
+            tokio::spawn(async move {
+                Self::udp_forward_loop(
+                    socket,
+                    buffer_size,
+                    selector,
+                    gate,
+                    stats,
+                    running,
+                    target_addr,
+                    sessions,
+                )
+                .await;
+            });
+        }
+
+        // 启动会话清理任务，检查间隔不超过空闲超时的一半
         let sessions_cleanup = self.sessions.clone();
         let running_cleanup = self.running.clone();
+        let stats_cleanup = self.stats.clone();
+        let timeouts = self.timeouts;
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(30));
+            let period =
+                (timeouts.udp_idle / 2).clamp(Duration::from_secs(1), Duration::from_secs(30));
+            let mut interval = tokio::time::interval(period);
             loop {
                 if !*running_cleanup.read().await {
                     break;
@@ -454,15 +1018,20 @@ impl UDPForwarder {
                 {
                     let sessions_read = sessions_cleanup.read().await;
                     for (client, sess) in sessions_read.iter() {
-                        if now.duration_since(sess.last_seen).as_secs() > 60 {
-                            to_remove.push(*client);
+                        if let Some(expiry) = timeouts.udp_expiry(
+                            now.duration_since(sess.last_seen),
+                            now.duration_since(sess.created_at),
+                        ) {
+                            to_remove.push((*client, expiry));
                         }
                     }
                 }
                 if !to_remove.is_empty() {
                     let mut sessions_write = sessions_cleanup.write().await;
-                    for client in to_remove {
+                    let mut stats = stats_cleanup.write().await;
+                    for (client, expiry) in to_remove {
                         sessions_write.remove(&client);
+                        stats.record_expiry(expiry);
                     }
                 }
             }
@@ -471,19 +1040,19 @@ impl UDPForwarder {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn udp_forward_loop(
         socket: UdpSocket,
         buffer_size: usize,
-        _name: String,
+        selector: Option<TargetSelector>,
+        gate: Option<Arc<KnockGate>>,
         stats: Arc<RwLock<ConnectionStats>>,
         running: Arc<RwLock<bool>>,
-        target_addr: Arc<RwLock<String>>,
+        target_addr: Arc<RwLock<Vec<TargetAddr>>>,
         sessions: Arc<RwLock<HashMap<std::net::SocketAddr, UdpSession>>>,
     ) {
         let mut buffer = vec![0u8; buffer_size];
         let socket = Arc::new(socket);
-        let mut target_cache: HashMap<String, (std::net::SocketAddr, std::time::Instant)> =
-            HashMap::new();
 
         loop {
             if !*running.read().await {
@@ -492,59 +1061,114 @@ impl UDPForwarder {
 
             match socket.recv_from(&mut buffer).await {
                 Ok((len, client_addr)) => {
+                    if let Some(gate) = &gate {
+                        if !sessions.read().await.contains_key(&client_addr)
+                            && !gate.check(client_addr.ip())
+                        {
+                            continue;
+                        }
+                    }
                     stats.write().await.add_bytes_received(len as u64);
 
-                    let target_addr_str = target_addr.read().await.clone();
-
-                    // DNS缓存：5分钟有效期
-                    let target = if let Some((cached_target, timestamp)) =
-                        target_cache.get(&target_addr_str)
-                    {
-                        if timestamp.elapsed().as_secs() < 300 {
-                            *cached_target
-                        } else {
-                            target_cache.remove(&target_addr_str);
-                            match crate::utils::resolve_target(&target_addr_str).await {
-                                Ok(addr) => {
-                                    target_cache.insert(
-                                        target_addr_str.clone(),
-                                        (addr, std::time::Instant::now()),
-                                    );
-                                    addr
+                    // 新会话或会话目标不再可用时，按策略重新选择目标；
+                    // 解析和选择可能较慢，在获取会话表写锁之前完成，避免阻塞其他会话
+                    let (selected, fallback) = {
+                        let sessions_guard = sessions.read().await;
+                        let entry = sessions_guard.get(&client_addr);
+                        (
+                            entry
+                                .and_then(|entry| entry.selection.as_ref())
+                                .map(|selection| selection.target.clone()),
+                            entry.and_then(|entry| entry.fallback),
+                        )
+                    };
+                    let route = match &selector {
+                        Some(selector) => {
+                            // 没有健康目标时按 on_no_healthy_target 处理：UDP只能转发到兜底目标，其余动作丢弃数据报
+                            let no_healthy = match selector.no_healthy_action() {
+                                Some(action) if !selector.has_healthy().await => Some(action),
+                                _ => None,
+                            };
+                            match no_healthy {
+                                Some(NoHealthyAction::FallbackTarget(target)) => {
+                                    let fallback = match fallback {
+                                        Some(fallback) => Some(fallback),
+                                        None => resolve_target(target)
+                                            .await
+                                            .ok()
+                                            .and_then(|addrs| first_inet_addr(&addrs)),
+                                    };
+                                    match fallback {
+                                        Some(fallback) => UdpRoute::Fallback(fallback),
+                                        None => continue,
+                                    }
+                                }
+                                Some(_) => continue,
+                                None => {
+                                    let reselect = match &selected {
+                                        Some(target) => !selector.is_available(target).await,
+                                        None => true,
+                                    };
+                                    if reselect {
+                                        match selector.select(Some(client_addr), None).await {
+                                            Ok(selection) => UdpRoute::Select(selection),
+                                            Err(_) => continue,
+                                        }
+                                    } else {
+                                        UdpRoute::Keep
+                                    }
                                 }
-                                Err(_) => continue,
-                            }
-                        }
-                    } else {
-                        match crate::utils::resolve_target(&target_addr_str).await {
-                            Ok(addr) => {
-                                target_cache.insert(
-                                    target_addr_str.clone(),
-                                    (addr, std::time::Instant::now()),
-                                );
-                                addr
                             }
-                            Err(_) => continue,
                         }
+                        None => match first_inet_addr(&target_addr.read().await) {
+                            Some(target) => UdpRoute::Static(target),
+                            None => continue,
+                        },
                     };
 
-                    // 获取或创建会话
+                    // 获取或创建会话，只在持有写锁期间读写会话状态
                     let mut sessions_guard = sessions.write().await;
                     let entry = sessions_guard
                         .entry(client_addr)
                         .or_insert_with(UdpSession::new);
+                    let target = match route {
+                        UdpRoute::Fallback(fallback) => {
+                            entry.selection = None;
+                            entry.fallback = Some(fallback);
+                            Some(fallback)
+                        }
+                        UdpRoute::Select(selection) => {
+                            entry.fallback = None;
+                            entry.selection = Some(selection);
+                            entry
+                                .selection
+                                .as_ref()
+                                .and_then(|selection| first_inet_addr(&selection.addrs))
+                        }
+                        UdpRoute::Keep => {
+                            entry.fallback = None;
+                            entry
+                                .selection
+                                .as_ref()
+                                .and_then(|selection| first_inet_addr(&selection.addrs))
+                        }
+                        UdpRoute::Static(target) => Some(target),
+                    };
+                    let Some(target) = target else {
+                        continue;
+                    };
 
                     // 如果没有上游socket或目标变化，重新连接
                     if entry.upstream.is_none() || entry.target != target {
-                        if let Ok(upstream) = UdpSocket::bind("0.0.0.0:0").await {
-                            if upstream.connect(target).await.is_ok() {
+                        match connect_udp_upstream(target).await {
+                            Ok(upstream) => {
                                 let upstream = Arc::new(upstream);
 
                                 // 启动回程任务
                                 let upstream_reader = upstream.clone();
                                 let socket_clone = socket.clone();
                                 let stats_clone = stats.clone();
-                                tokio::spawn(async move {
+                                let reply_task = tokio::spawn(async move {
                                     let mut resp_buf = vec![0u8; 4096];
                                     while let Ok(resp_len) =
                                         upstream_reader.recv(&mut resp_buf).await
@@ -561,9 +1185,13 @@ impl UDPForwarder {
                                     }
                                 });
 
+                                if let Some(old_task) = entry.reply_task.replace(reply_task) {
+                                    old_task.abort();
+                                }
                                 entry.upstream = Some(upstream);
                                 entry.target = target;
                             }
+                            Err(e) => warn!("UDP上游连接失败 {}: {}", target, e),
                         }
                     }
                     entry.last_seen = std::time::Instant::now();
@@ -581,14 +1209,20 @@ impl UDPForwarder {
         }
     }
 
-    pub async fn update_target(&mut self, new_target: &str) -> Result<()> {
-        *self.target_addr.write().await = new_target.to_string();
+    pub async fn update_target(&mut self, new_target: &[TargetAddr]) -> Result<()> {
+        *self.target_addr.write().await = new_target.to_vec();
         Ok(())
     }
 
     pub fn get_stats(&self) -> HashMap<String, String> {
         let stats = self.stats.blocking_read();
-        get_stats_with_target(&stats, &self.target_addr.blocking_read())
+        let mut result =
+            get_stats_with_target(&stats, &format_addrs(&self.target_addr.blocking_read()));
+        result.insert(
+            "listeners".to_string(),
+            format_bind_results(&self.bind_results),
+        );
+        result
     }
 }
 
@@ -624,32 +1258,58 @@ impl Forwarder for UDPForwarder {
 // ================================
 pub struct UnifiedForwarder {
     rule: ForwardRule,
-    listen_addr: String,
-    target_addr: String,
+    listen_addrs: Vec<ListenAddr>,
+    target_addr: Vec<TargetAddr>,
     tcp_forwarder: Option<TCPForwarder>,
     http_forwarder: Option<HTTPForwarder>,
     udp_forwarder: Option<UDPForwarder>,
+    dns_forwarder: Option<DNSForwarder>,
+    transparent_forwarder: Option<TransparentForwarder>,
+    knock_gate: Option<Arc<KnockGate>>,
+    selector: Option<TargetSelector>,
+    timeouts: ConnectionTimeouts,
     running: Arc<RwLock<bool>>,
     last_update: Arc<RwLock<Instant>>,
 }
 
 impl UnifiedForwarder {
-    pub fn new_with_target(rule: &ForwardRule, listen_addr: &str, target_addr: &str) -> Self {
+    pub fn new_with_target(
+        rule: &ForwardRule,
+        listen_addrs: &[ListenAddr],
+        target_addr: &[TargetAddr],
+    ) -> Self {
         Self {
             rule: rule.clone(),
-            listen_addr: listen_addr.to_string(),
-            target_addr: target_addr.to_string(),
+            listen_addrs: listen_addrs.to_vec(),
+            target_addr: target_addr.to_vec(),
             tcp_forwarder: None,
             http_forwarder: None,
             udp_forwarder: None,
+            dns_forwarder: None,
+            transparent_forwarder: None,
+            knock_gate: None,
+            selector: None,
+            timeouts: ConnectionTimeouts::from_config(
+                &rule.get_dynamic_update_config(&DynamicUpdateConfig::default()),
+            ),
             running: Arc::new(RwLock::new(false)),
             last_update: Arc::new(RwLock::new(Instant::now())),
         }
     }
 
-    pub async fn update_target(&mut self, new_target: &str) -> Result<()> {
+    // TCP连接和UDP会话按规则的负载均衡策略选择目标
+    pub fn set_selector(&mut self, selector: TargetSelector) {
+        self.selector = Some(selector);
+    }
+
+    // 规则与全局 dynamic_update 合并后的连接超时设置
+    pub fn set_timeouts(&mut self, timeouts: ConnectionTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub async fn update_target(&mut self, new_target: &[TargetAddr]) -> Result<()> {
         if self.target_addr != new_target {
-            self.target_addr = new_target.to_string();
+            self.target_addr = new_target.to_vec();
             *self.last_update.write().await = Instant::now();
 
             // 更新各转发器的目标地址
@@ -669,6 +1329,21 @@ impl Forwarder for UnifiedForwarder {
     async fn start(&mut self) -> Result<()> {
         *self.running.write().await = true;
 
+        // 透明代理按原始目的地址转发，TCP/UDP由透明代理转发器统一处理
+        if self.rule.is_transparent() {
+            if self.transparent_forwarder.is_none() {
+                let mut transparent_forwarder = TransparentForwarder::new(
+                    &self.listen_addrs,
+                    &format!("{}_TPROXY", self.rule.name),
+                    &self.rule,
+                )?;
+                transparent_forwarder.set_timeouts(self.timeouts);
+                transparent_forwarder.start().await?;
+                self.transparent_forwarder = Some(transparent_forwarder);
+            }
+            return Ok(());
+        }
+
         let protocols = if let Some(ref protocols) = self.rule.protocols {
             protocols.clone()
         } else if let Some(ref protocol) = self.rule.protocol {
@@ -677,40 +1352,77 @@ impl Forwarder for UnifiedForwarder {
             vec!["tcp".to_string()]
         };
 
+        // 敲门端口先于TCP监听器启动
+        if let Some(knock) = &self.rule.knock {
+            if self.knock_gate.is_none() {
+                let gate = Arc::new(KnockGate::new(&self.rule.name, knock)?);
+                gate.start(&self.listen_addrs)?;
+                self.knock_gate = Some(gate);
+            }
+        }
+
         for protocol in &protocols {
             match protocol.as_str() {
-                "tcp" => {
-                    if self.tcp_forwarder.is_none() {
-                        let mut tcp_forwarder = TCPForwarder::new(
-                            &self.listen_addr,
-                            &format!("{}_TCP", self.rule.name),
-                            self.rule.get_effective_buffer_size(8192),
-                        );
-                        tcp_forwarder.start_with_target(&self.target_addr).await?;
-                        self.tcp_forwarder = Some(tcp_forwarder);
+                "tcp" if self.tcp_forwarder.is_none() => {
+                    let mut tcp_forwarder = TCPForwarder::new(
+                        &self.listen_addrs,
+                        &format!("{}_TCP", self.rule.name),
+                        self.rule.get_effective_buffer_size(8192),
+                    );
+                    if let Some(sniff) = self.rule.get_sniff_config() {
+                        let http_redirect = protocols.iter().any(|p| p == "http");
+                        tcp_forwarder.set_sniffer(Sniffer::new(&sniff, http_redirect));
+                    }
+                    if let Some(gate) = &self.knock_gate {
+                        tcp_forwarder.set_gate(gate.clone());
+                    }
+                    if let Some(selector) = &self.selector {
+                        tcp_forwarder.set_selector(selector.for_protocol("tcp"));
                     }
+                    tcp_forwarder.set_timeouts(self.timeouts);
+                    tcp_forwarder.start_with_target(&self.target_addr).await?;
+                    self.tcp_forwarder = Some(tcp_forwarder);
                 }
-                "udp" => {
-                    if self.udp_forwarder.is_none() {
-                        let mut udp_forwarder = UDPForwarder::new(
-                            &self.listen_addr,
-                            &format!("{}_UDP", self.rule.name),
-                            self.rule.get_effective_buffer_size(8192),
-                        );
-                        udp_forwarder.start_with_target(&self.target_addr).await?;
-                        self.udp_forwarder = Some(udp_forwarder);
+                "udp" if self.udp_forwarder.is_none() => {
+                    let mut udp_forwarder = UDPForwarder::new(
+                        &self.listen_addrs,
+                        &format!("{}_UDP", self.rule.name),
+                        self.rule.get_effective_buffer_size(8192),
+                    );
+                    if let Some(selector) = &self.selector {
+                        udp_forwarder.set_selector(selector.for_protocol("udp"));
+                    }
+                    if let Some(gate) = &self.knock_gate {
+                        udp_forwarder.set_gate(gate.clone());
                     }
+                    udp_forwarder.set_timeouts(self.timeouts);
+                    udp_forwarder.start_with_target(&self.target_addr).await?;
+                    self.udp_forwarder = Some(udp_forwarder);
                 }
-                "http" => {
-                    if self.http_forwarder.is_none() {
-                        let mut http_forwarder = HTTPForwarder::new(
-                            &self.listen_addr,
-                            &format!("{}_HTTP", self.rule.name),
-                            self.rule.get_effective_buffer_size(8192),
-                        );
-                        http_forwarder.start().await?;
-                        self.http_forwarder = Some(http_forwarder);
+                // 启用嗅探时HTTP跳转由TCP监听器处理，避免重复绑定同一端口
+                "http"
+                    if self.http_forwarder.is_none() && self.rule.get_sniff_config().is_none() =>
+                {
+                    let mut http_forwarder = HTTPForwarder::new(
+                        &self.listen_addrs,
+                        &format!("{}_HTTP", self.rule.name),
+                        self.rule.get_effective_buffer_size(8192),
+                    );
+                    if let Some(gate) = &self.knock_gate {
+                        http_forwarder.set_gate(gate.clone());
                     }
+                    http_forwarder.start().await?;
+                    self.http_forwarder = Some(http_forwarder);
+                }
+                "dns" if self.dns_forwarder.is_none() => {
+                    let mut dns_forwarder = DNSForwarder::new(
+                        &self.listen_addrs,
+                        &format!("{}_DNS", self.rule.name),
+                        &self.rule.get_target_addresses(),
+                        &self.rule.get_dns_config(),
+                    );
+                    dns_forwarder.start().await?;
+                    self.dns_forwarder = Some(dns_forwarder);
                 }
                 _ => {}
             }
@@ -731,6 +1443,15 @@ impl Forwarder for UnifiedForwarder {
         if let Some(ref mut http) = self.http_forwarder {
             http.stop().await;
         }
+        if let Some(ref mut dns) = self.dns_forwarder {
+            dns.stop().await;
+        }
+        if let Some(ref mut transparent) = self.transparent_forwarder {
+            transparent.stop().await;
+        }
+        if let Some(gate) = self.knock_gate.take() {
+            gate.stop();
+        }
     }
 
     fn is_running(&self) -> bool {
@@ -740,7 +1461,7 @@ impl Forwarder for UnifiedForwarder {
     fn get_stats(&self) -> HashMap<String, String> {
         let mut stats = HashMap::new();
         stats.insert("rule_name".to_string(), self.rule.name.clone());
-        stats.insert("target_addr".to_string(), self.target_addr.clone());
+        stats.insert("target_addr".to_string(), format_addrs(&self.target_addr));
         let protocols_str = if let Some(ref protocols) = self.rule.protocols {
             protocols.join("+")
         } else if let Some(ref protocol) = self.rule.protocol {
@@ -749,6 +1470,12 @@ impl Forwarder for UnifiedForwarder {
             "tcp".to_string()
         };
         stats.insert("protocols".to_string(), protocols_str);
+        if self.rule.has_managed_targets() {
+            stats.insert(
+                "strategy".to_string(),
+                self.rule.get_strategy().as_str().to_string(),
+            );
+        }
         stats.insert("running".to_string(), self.is_running().to_string());
 
         if let Some(ref tcp) = self.tcp_forwarder {
@@ -765,6 +1492,26 @@ impl Forwarder for UnifiedForwarder {
             }
         }
 
+        if let Some(ref dns) = self.dns_forwarder {
+            for (k, v) in dns.get_stats() {
+                stats.insert(format!("dns_{}", k), v);
+            }
+        }
+
+        if let Some(ref transparent) = self.transparent_forwarder {
+            for (k, v) in transparent.get_stats() {
+                stats.insert(format!("transparent_{}", k), v);
+            }
+        }
+
+        if let Some(ref gate) = self.knock_gate {
+            gate.write_stats(&mut stats);
+        }
+
+        if let Some(ref selector) = self.selector {
+            selector.write_stats(&mut stats);
+        }
+
         stats
     }
 
@@ -812,7 +1559,7 @@ impl SmartForwarder {
         let mut success_count = 0;
         let total_count = rules.len();
 
-        for rule in &rules {
+        for rule in rules.iter().filter(|rule| !rule.is_reverse()) {
             match self.start_forwarder(rule).await {
                 Ok(_) => {
                     success_count += 1;
@@ -824,6 +1571,9 @@ impl SmartForwarder {
             }
         }
 
+        let (tunnel_rules, tunnel_server_started) = self.start_tunnel().await;
+        success_count += tunnel_rules;
+
         info!(
             "启动完成: {} 个规则可用 (总共 {} 个规则)",
             success_count, total_count
@@ -836,7 +1586,7 @@ impl SmartForwarder {
         }
 
         // 如果没有任何规则启动成功，返回错误
-        if success_count == 0 {
+        if success_count == 0 && !tunnel_server_started {
             return Err(anyhow::anyhow!(
                 "没有规则成功启动，请检查配置和端口占用情况"
             ));
@@ -846,20 +1596,40 @@ impl SmartForwarder {
     }
 
     async fn start_forwarder(&mut self, rule: &ForwardRule) -> Result<()> {
-        let listen_addr = rule.get_listen_addr(&self.config.network.listen_addr);
+        let listen_addrs = rule.get_listen_addrs(&self.config.network.listen_addr)?;
 
-        // 获取最佳目标
-        if let Ok(best_target) = self.common_manager.get_best_target(&rule.name).await {
-            let target_addr = best_target.to_string();
+        // DNS规则的 targets 是上游DNS服务器，透明代理的目标来自原始目的地址，均不经过公共管理器
+        let best_target = if rule.has_managed_targets() {
+            self.common_manager.get_best_target(&rule.name).await
+        } else {
+            Ok(Vec::new())
+        };
 
+        // 获取最佳目标
+        if let Ok(target_addr) = best_target {
+            let target_desc = if rule.is_transparent() {
+                "原始目的地址".to_string()
+            } else if rule.is_dns() {
+                rule.get_target_addresses().join(",")
+            } else {
+                format_addrs(&target_addr)
+            };
             info!(
                 "规则 {} 启动: {} -> {}",
-                rule.name, listen_addr, target_addr
+                rule.name,
+                format_listen_addrs(&listen_addrs),
+                target_desc
             );
 
             // 创建统一转发器
             let mut unified_forwarder =
-                UnifiedForwarder::new_with_target(rule, &listen_addr, &target_addr);
+                UnifiedForwarder::new_with_target(rule, &listen_addrs, &target_addr);
+            unified_forwarder.set_timeouts(ConnectionTimeouts::from_config(
+                &rule.get_dynamic_update_config(&self.config.get_dynamic_update_config()),
+            ));
+            if rule.has_managed_targets() {
+                unified_forwarder.set_selector(self.common_manager.selector(&rule.name));
+            }
             match unified_forwarder.start().await {
                 Ok(_) => {
                     self.forwarders
@@ -879,6 +1649,51 @@ impl SmartForwarder {
         Ok(())
     }
 
+    // 启动反向隧道，返回交给隧道客户端的规则数和服务端是否启动
+    async fn start_tunnel(&mut self) -> (usize, bool) {
+        let mut server_started = false;
+        if let Some(server_config) = self.config.get_tunnel_server() {
+            let mut server = TunnelServer::new(server_config, &self.config.network.listen_addr);
+            match server.start().await {
+                Ok(_) => {
+                    server_started = true;
+                    self.forwarders
+                        .write()
+                        .await
+                        .insert("tunnel_server".to_string(), Box::new(server));
+                }
+                Err(e) => error!("隧道服务端启动失败: {}", e),
+            }
+        }
+
+        let reverse_rules: Vec<ForwardRule> = self
+            .config
+            .rules
+            .iter()
+            .filter(|rule| rule.is_reverse())
+            .cloned()
+            .collect();
+        let mut rule_count = 0;
+        if let (false, Some(client_config)) =
+            (reverse_rules.is_empty(), self.config.get_tunnel_client())
+        {
+            let mut client =
+                TunnelClient::new(client_config, &reverse_rules, self.common_manager.clone());
+            match client.start().await {
+                Ok(_) => {
+                    rule_count = reverse_rules.len();
+                    self.forwarders
+                        .write()
+                        .await
+                        .insert("tunnel_client".to_string(), Box::new(client));
+                }
+                Err(e) => error!("隧道客户端启动失败: {}", e),
+            }
+        }
+
+        (rule_count, server_started)
+    }
+
     async fn start_dynamic_update_task(&self) {
         let forwarders = self.forwarders.clone();
         let common_manager = self.common_manager.clone();
@@ -891,9 +1706,7 @@ impl SmartForwarder {
                 interval.tick().await;
 
                 for rule in &rules {
-                    if let Ok(best_target) = common_manager.get_best_target(&rule.name).await {
-                        let target_addr = best_target.to_string();
-
+                    if let Ok(target_addr) = common_manager.get_best_target(&rule.name).await {
                         let mut forwarders_guard = forwarders.write().await;
                         if let Some(forwarder) = forwarders_guard.get_mut(&rule.name) {
                             if let Some(unified) =
@@ -927,7 +1740,240 @@ impl SmartForwarder {
         for (name, forwarder) in forwarders.iter() {
             all_stats.insert(name.clone(), forwarder.get_stats());
         }
+        all_stats.insert("resolver".to_string(), resolver::cache_stats());
 
         all_stats
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 双栈目标的地址按IPv6优先排列，UDP会话需要用IPv6套接字连接上游
+    #[tokio::test]
+    async fn test_udp_dual_stack_target() {
+        let upstream = UdpSocket::bind("[::1]:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, from)) = upstream.recv_from(&mut buf).await {
+                let _ = upstream.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let targets = crate::utils::interleave_address_families(vec![
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port)),
+        ]);
+        assert!(targets[0].is_ipv6());
+        tokio::spawn(UDPForwarder::udp_forward_loop(
+            listener,
+            2048,
+            None,
+            None,
+            Arc::new(RwLock::new(ConnectionStats::default())),
+            Arc::new(RwLock::new(true)),
+            Arc::new(RwLock::new(
+                targets.into_iter().map(TargetAddr::Inet).collect(),
+            )),
+            Arc::new(RwLock::new(HashMap::new())),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", listen_addr).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(3), client.recv_from(&mut buf))
+            .await
+            .expect("双栈目标的UDP应答超时")
+            .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+    }
+
+    // unix: 监听地址转发到 unix: 目标
+    #[tokio::test]
+    async fn test_unix_listener_to_unix_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let listen_path = dir.path().join("listen.sock");
+        let target_path = dir.path().join("target.sock");
+
+        let upstream = tokio::net::UnixListener::bind(&target_path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut forwarder =
+            TCPForwarder::new(&[ListenAddr::Unix(listen_path.clone())], "unix", 1024);
+        forwarder
+            .start_with_target(&[TargetAddr::Unix(target_path)])
+            .await
+            .unwrap();
+
+        let mut client = tokio::net::UnixStream::connect(&listen_path).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(3), client.read_exact(&mut buf))
+            .await
+            .expect("Unix套接字转发应答超时")
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    // 敲门规则的UDP会话在放行前静默丢弃
+    #[tokio::test]
+    async fn test_udp_knock_gate() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, from)) = upstream.recv_from(&mut buf).await {
+                let _ = upstream.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let gate = Arc::new(
+            KnockGate::new(
+                "test",
+                &crate::config::KnockConfig {
+                    sequence: Some(vec!["udp:7001".to_string()]),
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+        );
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        tokio::spawn(UDPForwarder::udp_forward_loop(
+            listener,
+            2048,
+            None,
+            Some(gate.clone()),
+            Arc::new(RwLock::new(ConnectionStats::default())),
+            Arc::new(RwLock::new(true)),
+            Arc::new(RwLock::new(vec![TargetAddr::Inet(upstream_addr)])),
+            Arc::new(RwLock::new(HashMap::new())),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 64];
+        client.send_to(b"ping", listen_addr).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), client.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        gate.open(client.local_addr().unwrap().ip(), "测试");
+        client.send_to(b"ping", listen_addr).await.unwrap();
+        let (n, _) = tokio::time::timeout(Duration::from_secs(3), client.recv_from(&mut buf))
+            .await
+            .expect("放行后UDP应答超时")
+            .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+    }
+
+    fn tcp_target(addr: SocketAddr) -> crate::common::TargetInfo {
+        crate::common::TargetInfo::new(&addr.to_string(), vec![TargetAddr::Inet(addr)])
+    }
+
+    fn forward_with_selector(
+        selector: TargetSelector,
+        stats: Arc<RwLock<ConnectionStats>>,
+    ) -> (tokio::task::JoinHandle<Result<()>>, tokio::io::DuplexStream) {
+        let (client, server) = tokio::io::duplex(1024);
+        let timeouts = ConnectionTimeouts {
+            idle: None,
+            udp_idle: Duration::from_secs(30),
+            lifetime: None,
+        };
+        let forward = tokio::spawn(async move {
+            TCPForwarder::forward_to_source(
+                Box::new(server),
+                None,
+                TargetSource::Selector(selector),
+                1024,
+                timeouts,
+                stats,
+                "test",
+            )
+            .await
+        });
+        (forward, client)
+    }
+
+    // 第一个目标拒绝连接时，在 connect_retries 内按目标顺序改连下一个目标
+    #[tokio::test]
+    async fn test_connect_retry_moves_to_next_target() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        drop(dead);
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_addr = live.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = live.accept().await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+        });
+
+        let selector = TargetSelector::with_targets(
+            vec![tcp_target(dead_addr), tcp_target(live_addr)],
+            crate::balancer::Strategy::Failover,
+            1,
+            Duration::from_secs(10),
+        );
+        let stats = Arc::new(RwLock::new(ConnectionStats::default()));
+        let (forward, mut client) = forward_with_selector(selector, stats.clone());
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+        drop(client);
+        forward.await.unwrap().unwrap();
+
+        let stats = stats.read().await;
+        assert_eq!(stats.connect_attempts, 2);
+        assert_eq!(stats.connect_failures, 1);
+        assert_eq!(stats.connect_retries, 1);
+    }
+
+    // 连接挂起的目标耗尽总时限后不再改连，即使还有重试次数
+    #[tokio::test]
+    async fn test_connect_deadline_stops_retries() {
+        // 接受队列已满的监听套接字不再响应新连接的SYN，连接一直挂起
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let hung = socket.listen(0).unwrap();
+        let hung_addr = hung.local_addr().unwrap();
+        let mut backlog = Vec::new();
+        for _ in 0..4 {
+            let connect = tokio::net::TcpStream::connect(hung_addr);
+            if let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(200), connect).await
+            {
+                backlog.push(stream);
+            }
+        }
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_addr = live.local_addr().unwrap();
+
+        let selector = TargetSelector::with_targets(
+            vec![tcp_target(hung_addr), tcp_target(live_addr)],
+            crate::balancer::Strategy::Failover,
+            3,
+            Duration::from_secs(1),
+        );
+        let stats = Arc::new(RwLock::new(ConnectionStats::default()));
+        let started = Instant::now();
+        let (forward, _client) = forward_with_selector(selector, stats.clone());
+        assert!(forward.await.unwrap().is_err());
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+        assert_eq!(stats.read().await.connect_attempts, 1);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), live.accept())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,405 @@
+// 敲门放行 - 端口敲门序列或HMAC签名的单包授权(SPA)通过后，在限定时间内放行来源IP
+//
+// SPA数据包为UDP文本：`时间戳:随机数:签名`，签名为 HMAC-SHA256(secret, "规则名:时间戳:随机数") 的十六进制。
+// 所有敲门端口都不回复任何数据。
+use crate::config::KnockConfig;
+use crate::forwarder::bind_all;
+use crate::listener::{bind_tcp, bind_udp, ListenAddr};
+use crate::utils::hmac_verify;
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+
+// SPA时间戳允许的时钟偏差（秒），同时也是随机数防重放的保留时间
+const SPA_MAX_SKEW: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnockStep {
+    Udp(u16),
+    Tcp(u16),
+}
+
+impl KnockStep {
+    pub fn port(&self) -> u16 {
+        match self {
+            KnockStep::Udp(port) | KnockStep::Tcp(port) => *port,
+        }
+    }
+}
+
+/// 解析敲门步骤：`udp:7001`、`tcp:7002`，只写端口时默认UDP
+pub fn parse_knock_step(step: &str) -> Result<KnockStep> {
+    let (protocol, port) = step.split_once(':').unwrap_or(("udp", step));
+    let port: u16 = port
+        .trim()
+        .parse()
+        .ok()
+        .filter(|p| *p != 0)
+        .ok_or_else(|| anyhow::anyhow!("无效的敲门端口: {}", step))?;
+    match protocol.trim() {
+        "udp" => Ok(KnockStep::Udp(port)),
+        "tcp" => Ok(KnockStep::Tcp(port)),
+        other => anyhow::bail!("敲门协议仅支持udp/tcp: {}", other),
+    }
+}
+
+#[derive(Default)]
+struct KnockState {
+    allowed: HashMap<IpAddr, Instant>,           // 放行截止时间
+    progress: HashMap<IpAddr, (usize, Instant)>, // 已完成的步骤数、上一步时间
+    nonces: HashMap<String, Instant>,            // 已使用的SPA随机数
+}
+
+#[derive(Default)]
+struct KnockStats {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    opened: AtomicU64,
+    bad_packets: AtomicU64,
+}
+
+pub struct KnockGate {
+    name: String,
+    sequence: Vec<KnockStep>,
+    spa_port: Option<u16>,
+    secret: Option<String>,
+    open_for: Duration,
+    step_timeout: Duration,
+    state: Mutex<KnockState>,
+    stats: KnockStats,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+// 双栈套接字上的IPv4来源以映射地址出现，统一后才能与连接来源比较
+fn canonical_ip(addr: &SocketAddr) -> IpAddr {
+    addr.ip().to_canonical()
+}
+
+// 敲门端口沿用规则的监听地址，仅替换端口
+fn with_port(listen_addrs: &[ListenAddr], port: u16) -> Vec<ListenAddr> {
+    listen_addrs
+        .iter()
+        .filter_map(|addr| match addr {
+            ListenAddr::Socket {
+                addr,
+                device,
+                v6_only,
+            } => Some(ListenAddr::Socket {
+                addr: SocketAddr::new(addr.ip(), port),
+                device: device.clone(),
+                v6_only: *v6_only,
+            }),
+            ListenAddr::Unix(_) => None,
+        })
+        .collect()
+}
+
+fn bind_udp_addr(addr: &ListenAddr) -> Result<UdpSocket> {
+    match addr {
+        ListenAddr::Socket {
+            addr,
+            device,
+            v6_only,
+        } => bind_udp(addr, device.as_deref(), *v6_only),
+        ListenAddr::Unix(_) => anyhow::bail!("敲门端口不支持Unix套接字"),
+    }
+}
+
+fn bind_tcp_addr(addr: &ListenAddr) -> Result<TcpListener> {
+    match addr {
+        ListenAddr::Socket {
+            addr,
+            device,
+            v6_only,
+        } => bind_tcp(addr, device.as_deref(), *v6_only),
+        ListenAddr::Unix(_) => anyhow::bail!("敲门端口不支持Unix套接字"),
+    }
+}
+
+impl KnockGate {
+    pub fn new(name: &str, config: &KnockConfig) -> Result<Self> {
+        let sequence = config
+            .get_sequence()
+            .iter()
+            .map(|step| parse_knock_step(step))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            name: name.to_string(),
+            sequence,
+            spa_port: config.spa_port,
+            secret: config.secret.clone(),
+            open_for: Duration::from_secs(config.get_open_seconds()),
+            step_timeout: Duration::from_secs(config.get_step_timeout()),
+            state: Mutex::new(KnockState::default()),
+            stats: KnockStats::default(),
+            tasks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// 绑定敲门序列端口和SPA端口，并启动过期清理
+    pub fn start(self: &Arc<Self>, listen_addrs: &[ListenAddr]) -> Result<()> {
+        let mut tasks = Vec::new();
+
+        for (index, step) in self.sequence.iter().enumerate() {
+            let addrs = with_port(listen_addrs, step.port());
+            match step {
+                KnockStep::Udp(_) => {
+                    let (sockets, _) = bind_all("敲门UDP", &self.name, &addrs, bind_udp_addr)?;
+                    for socket in sockets {
+                        let gate = self.clone();
+                        tasks.push(tokio::spawn(async move {
+                            let mut buffer = [0u8; 64];
+                            loop {
+                                match socket.recv_from(&mut buffer).await {
+                                    Ok((_, peer)) => gate.knock(canonical_ip(&peer), index),
+                                    Err(e) => gate.recover("敲门UDP端口接收失败", e).await,
+                                }
+                            }
+                        }));
+                    }
+                }
+                KnockStep::Tcp(_) => {
+                    let (listeners, _) = bind_all("敲门TCP", &self.name, &addrs, bind_tcp_addr)?;
+                    for listener in listeners {
+                        let gate = self.clone();
+                        tasks.push(tokio::spawn(async move {
+                            // 接受后立即关闭，不发送任何数据
+                            loop {
+                                match listener.accept().await {
+                                    Ok((_, peer)) => gate.knock(canonical_ip(&peer), index),
+                                    Err(e) => gate.recover("敲门TCP端口接受连接失败", e).await,
+                                }
+                            }
+                        }));
+                    }
+                }
+            }
+        }
+
+        if let Some(port) = self.spa_port {
+            let addrs = with_port(listen_addrs, port);
+            let (sockets, _) = bind_all("SPA", &self.name, &addrs, bind_udp_addr)?;
+            for socket in sockets {
+                let gate = self.clone();
+                tasks.push(tokio::spawn(async move {
+                    let mut buffer = [0u8; 256];
+                    loop {
+                        match socket.recv_from(&mut buffer).await {
+                            Ok((len, peer)) if gate.verify_spa(&buffer[..len]) => {
+                                gate.open(canonical_ip(&peer), "SPA")
+                            }
+                            Ok(_) => {
+                                gate.stats.bad_packets.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => gate.recover("SPA端口接收失败", e).await,
+                        }
+                    }
+                }));
+            }
+        }
+
+        let gate = self.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                gate.prune();
+            }
+        }));
+
+        self.tasks.lock().unwrap().extend(tasks);
+        Ok(())
+    }
+
+    // 接收出错（如文件描述符耗尽）时记录日志，短暂等待后继续，避免敲门端口永久失效
+    async fn recover(&self, what: &str, e: std::io::Error) {
+        warn!("规则 {} {}: {}", self.name, what, e);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    pub fn stop(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    /// 来源IP当前是否已放行
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let allowed = self
+            .state
+            .lock()
+            .unwrap()
+            .allowed
+            .get(&ip)
+            .is_some_and(|until| Instant::now() < *until);
+        let counter = if allowed {
+            &self.stats.allowed
+        } else {
+            &self.stats.denied
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        allowed
+    }
+
+    pub(crate) fn open(&self, ip: IpAddr, reason: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .allowed
+            .insert(ip, Instant::now() + self.open_for);
+        self.stats.opened.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "规则 {} 通过{}放行 {}，有效期 {} 秒",
+            self.name,
+            reason,
+            ip,
+            self.open_for.as_secs()
+        );
+    }
+
+    // 按顺序敲击：敲错或两步间隔超时则重新开始
+    fn knock(&self, ip: IpAddr, step: usize) {
+        let now = Instant::now();
+        let completed = {
+            let mut state = self.state.lock().unwrap();
+            let done = match state.progress.get(&ip) {
+                Some((done, last)) if now.duration_since(*last) <= self.step_timeout => *done,
+                _ => 0,
+            };
+            let done = if step == done {
+                done + 1
+            } else if step == 0 {
+                1
+            } else {
+                0
+            };
+
+            if done == self.sequence.len() {
+                state.progress.remove(&ip);
+                true
+            } else {
+                state.progress.insert(ip, (done, now));
+                false
+            }
+        };
+        debug!("规则 {} 收到 {} 的敲门 #{}", self.name, ip, step + 1);
+
+        if completed {
+            self.open(ip, "端口敲门");
+        }
+    }
+
+    fn verify_spa(&self, packet: &[u8]) -> bool {
+        let Some(secret) = &self.secret else {
+            return false;
+        };
+        let Ok(packet) = std::str::from_utf8(packet) else {
+            return false;
+        };
+        let mut parts = packet.trim().splitn(3, ':');
+        let (Some(timestamp), Some(nonce), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+        let Ok(timestamp) = timestamp.parse::<i64>() else {
+            return false;
+        };
+        if nonce.is_empty() || (chrono::Utc::now().timestamp() - timestamp).abs() > SPA_MAX_SKEW {
+            return false;
+        }
+        let message = format!("{}:{}:{}", self.name, timestamp, nonce);
+        if !hmac_verify(secret, &message, signature) {
+            return false;
+        }
+
+        // 防重放：随机数在时间窗口内只能使用一次
+        let mut state = self.state.lock().unwrap();
+        let key = format!("{}:{}", timestamp, nonce);
+        if state.nonces.contains_key(&key) {
+            return false;
+        }
+        state.nonces.insert(
+            key,
+            Instant::now() + Duration::from_secs(SPA_MAX_SKEW as u64 * 2),
+        );
+        true
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        let step_timeout = self.step_timeout;
+        let mut state = self.state.lock().unwrap();
+        state.allowed.retain(|_, until| *until > now);
+        state
+            .progress
+            .retain(|_, (_, last)| now.duration_since(*last) <= step_timeout);
+        state.nonces.retain(|_, until| *until > now);
+    }
+
+    pub fn write_stats(&self, result: &mut HashMap<String, String>) {
+        for (key, value) in [
+            ("knock_allowed", &self.stats.allowed),
+            ("knock_denied", &self.stats.denied),
+            ("knock_opened", &self.stats.opened),
+            ("knock_bad_packets", &self.stats.bad_packets),
+        ] {
+            result.insert(key.to_string(), value.load(Ordering::Relaxed).to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hmac_sign;
+
+    fn gate(sequence: &[&str]) -> KnockGate {
+        let config = KnockConfig {
+            sequence: Some(sequence.iter().map(|s| s.to_string()).collect()),
+            spa_port: Some(62201),
+            secret: Some("secret".to_string()),
+            open_seconds: None,
+            step_timeout: None,
+        };
+        KnockGate::new("SSH", &config).unwrap()
+    }
+
+    #[test]
+    fn test_knock_sequence_must_be_in_order() {
+        let gate = gate(&["udp:7001", "tcp:7002", "7003"]);
+        let ip: IpAddr = "203.0.113.5".parse().unwrap();
+
+        gate.knock(ip, 0);
+        gate.knock(ip, 2); // 敲错，重新开始
+        gate.knock(ip, 1);
+        assert!(!gate.check(ip));
+
+        gate.knock(ip, 0);
+        gate.knock(ip, 1);
+        gate.knock(ip, 2);
+        assert!(gate.check(ip));
+        assert!(!gate.check("203.0.113.6".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_spa_rejects_replay_and_bad_signature() {
+        let gate = gate(&[]);
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = hmac_sign("secret", &format!("SSH:{}:abc123", timestamp));
+        let packet = format!("{}:abc123:{}", timestamp, signature);
+
+        assert!(gate.verify_spa(packet.as_bytes()));
+        assert!(!gate.verify_spa(packet.as_bytes()));
+
+        let forged = format!("{}:abc124:{}", timestamp, signature);
+        assert!(!gate.verify_spa(forged.as_bytes()));
+    }
+}
@@ -0,0 +1,312 @@
+// 监听地址解析与绑定 - 支持多地址、双栈、按网卡绑定和Unix套接字
+use crate::utils::{is_unix_addr, parse_unix_path, BoxedStream};
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// 单个监听地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Socket {
+        addr: SocketAddr,
+        device: Option<String>, // Linux 网卡名（SO_BINDTODEVICE）
+        v6_only: bool,          // IPv6 套接字是否设置 IPV6_V6ONLY
+    },
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Socket {
+                addr,
+                device: Some(device),
+                ..
+            } => write!(f, "{}%{}", addr, device),
+            ListenAddr::Socket { addr, .. } => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub fn format_listen_addrs(addrs: &[ListenAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 单个监听地址的绑定结果
+#[derive(Debug, Clone)]
+pub struct BindResult {
+    pub addr: String,
+    pub error: Option<String>,
+}
+
+/// 绑定结果摘要，例如 `0.0.0.0:80=ok,[::]:80=Address in use`
+pub fn format_bind_results(results: &[BindResult]) -> String {
+    results
+        .iter()
+        .map(|r| format!("{}={}", r.addr, r.error.as_deref().unwrap_or("ok")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 将配置中的监听项展开为具体监听地址
+///
+/// 每一项可以是 IP（`0.0.0.0`、`::`、`192.168.1.2`）、Linux网卡名（`eth0`）或 `unix:/path`。
+/// 同一列表中同时存在IPv4和IPv6时，IPv6套接字设置 IPV6_V6ONLY，避免与IPv4监听冲突；
+/// 只有IPv6时保持双栈，一个 `::` 即可同时接收IPv4连接。
+pub fn parse_listen_addrs(entries: &[String], port: u16) -> Result<Vec<ListenAddr>> {
+    let has_ipv4 =
+        entries
+            .iter()
+            .map(|entry| entry.trim())
+            .any(|entry| match entry.parse::<IpAddr>() {
+                Ok(ip) => ip.is_ipv4(),
+                // 网卡名会同时绑定IPv4
+                Err(_) => !is_unix_addr(entry),
+            });
+
+    let mut addrs = Vec::new();
+    for entry in entries {
+        let entry = entry.trim();
+        if is_unix_addr(entry) {
+            let path = parse_unix_path(entry)
+                .ok_or_else(|| anyhow::anyhow!("无效的Unix套接字地址: {}", entry))?;
+            addrs.push(ListenAddr::Unix(path));
+        } else if let Ok(ip) = entry.parse::<IpAddr>() {
+            addrs.push(ListenAddr::Socket {
+                addr: SocketAddr::new(ip, port),
+                device: None,
+                v6_only: ip.is_ipv6() && has_ipv4,
+            });
+        } else if is_interface_name(entry) {
+            // 按网卡绑定：IPv4 和 IPv6 各一个套接字，分别报告绑定结果
+            for ip in [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ] {
+                addrs.push(ListenAddr::Socket {
+                    addr: SocketAddr::new(ip, port),
+                    device: Some(entry.to_string()),
+                    v6_only: ip.is_ipv6(),
+                });
+            }
+        } else {
+            anyhow::bail!("无效的监听地址: {}", entry);
+        }
+    }
+
+    if addrs.is_empty() {
+        anyhow::bail!("监听地址列表不能为空");
+    }
+
+    Ok(addrs)
+}
+
+fn is_interface_name(name: &str) -> bool {
+    // Linux 网卡名最长15字节（IFNAMSIZ - 1）
+    !name.is_empty()
+        && name.len() < 16
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+fn new_socket(
+    addr: &SocketAddr,
+    device: Option<&str>,
+    v6_only: bool,
+    transparent: bool,
+    ty: Type,
+    protocol: Protocol,
+) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+
+    // 与 tokio 的 TcpListener::bind 行为保持一致；透明代理的UDP回程套接字会重复绑定同一原始地址
+    #[cfg(unix)]
+    if ty == Type::STREAM || transparent {
+        socket.set_reuse_address(true)?;
+    }
+
+    if transparent {
+        set_ip_transparent(&socket, addr.is_ipv6())?;
+    }
+
+    if let Some(device) = device {
+        bind_device(&socket, device)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, device: &str) -> Result<()> {
+    socket
+        .bind_device(Some(device.as_bytes()))
+        .map_err(|e| anyhow::anyhow!("绑定网卡 {} 失败: {}", device, e))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, device: &str) -> Result<()> {
+    anyhow::bail!("当前平台不支持按网卡名监听: {}", device)
+}
+
+// IP_TRANSPARENT 允许接收TPROXY转来的流量及绑定非本机地址，需要 CAP_NET_ADMIN
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_ip_transparent(socket: &Socket, ipv6: bool) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        (libc::SOL_IP, libc::IP_TRANSPARENT)
+    };
+    crate::transparent::set_int_option(socket.as_raw_fd(), level, name, 1)
+        .map_err(|e| anyhow::anyhow!("设置IP_TRANSPARENT失败（需要CAP_NET_ADMIN权限）: {}", e))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_ip_transparent(_socket: &Socket, _ipv6: bool) -> Result<()> {
+    anyhow::bail!("当前平台不支持透明代理")
+}
+
+/// 绑定TCP监听套接字
+pub fn bind_tcp(addr: &SocketAddr, device: Option<&str>, v6_only: bool) -> Result<TcpListener> {
+    let socket = new_socket(addr, device, v6_only, false, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// 绑定UDP监听套接字
+pub fn bind_udp(addr: &SocketAddr, device: Option<&str>, v6_only: bool) -> Result<UdpSocket> {
+    let socket = new_socket(addr, device, v6_only, false, Type::DGRAM, Protocol::UDP)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// 绑定带 IP_TRANSPARENT 的TCP监听套接字（TPROXY模式）
+pub fn bind_tcp_transparent(
+    addr: &SocketAddr,
+    device: Option<&str>,
+    v6_only: bool,
+) -> Result<TcpListener> {
+    let socket = new_socket(addr, device, v6_only, true, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// 绑定带 IP_TRANSPARENT 的UDP套接字，可绑定到非本机地址（TPROXY模式）
+pub fn bind_udp_transparent(
+    addr: &SocketAddr,
+    device: Option<&str>,
+    v6_only: bool,
+) -> Result<UdpSocket> {
+    let socket = new_socket(addr, device, v6_only, true, Type::DGRAM, Protocol::UDP)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// 流式监听器：TCP端口或Unix套接字
+pub enum StreamListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl StreamListener {
+    pub fn bind(listen_addr: &ListenAddr) -> Result<Self> {
+        match listen_addr {
+            ListenAddr::Socket {
+                addr,
+                device,
+                v6_only,
+            } => Ok(StreamListener::Tcp(bind_tcp(
+                addr,
+                device.as_deref(),
+                *v6_only,
+            )?)),
+            ListenAddr::Unix(path) => Self::bind_unix(path),
+        }
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &std::path::Path) -> Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // 清理上次运行遗留的套接字文件，避免 address in use
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(StreamListener::Unix(tokio::net::UnixListener::bind(path)?))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(path: &std::path::Path) -> Result<Self> {
+        anyhow::bail!("当前平台不支持Unix套接字: {}", path.display())
+    }
+
+    /// 接受新连接，返回统一的流和客户端地址（Unix套接字没有网络地址）
+    pub async fn accept(&self) -> std::io::Result<(BoxedStream, Option<SocketAddr>)> {
+        match self {
+            StreamListener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                // 优化TCP：降低延迟
+                let _ = stream.set_nodelay(true);
+                Ok((Box::new(stream), Some(peer)))
+            }
+            #[cfg(unix)]
+            StreamListener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dual_stack_sets_v6_only() {
+        let entries = vec!["0.0.0.0".to_string(), "::".to_string()];
+        let addrs = parse_listen_addrs(&entries, 8080).unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert!(matches!(addrs[1], ListenAddr::Socket { v6_only: true, .. }));
+
+        // 只有IPv6时保持双栈，带空白的条目同样按IPv6判断
+        for entry in ["::", " :: "] {
+            let addrs = parse_listen_addrs(&[entry.to_string()], 8080).unwrap();
+            assert!(matches!(
+                addrs[0],
+                ListenAddr::Socket { v6_only: false, .. }
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_v4_and_v6_on_same_port() {
+        let v4 = bind_tcp(&"127.0.0.1:0".parse().unwrap(), None, false).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        // 环境不支持IPv6时跳过
+        if let Ok(v6) = bind_tcp(
+            &SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+            None,
+            true,
+        ) {
+            assert_eq!(v6.local_addr().unwrap().port(), port);
+        }
+    }
+}
@@ -1,6 +1,17 @@
+mod balancer;
 mod common;
 mod config;
+mod control;
+mod dns_forwarder;
 mod forwarder;
+mod knock;
+mod listener;
+mod probe;
+mod resolver;
+mod sniff;
+mod transparent;
+mod tunnel;
+mod txt;
 mod utils;
 
 use anyhow::Result;
@@ -9,8 +20,9 @@ use log::info;
 use std::path::PathBuf;
 
 use crate::common::CommonManager;
-use crate::config::Config;
+use crate::config::{Config, NoHealthyAction};
 use crate::forwarder::SmartForwarder;
+use crate::listener::format_listen_addrs;
 
 /// 后台运行处理
 fn daemonize(pid_file: &PathBuf) -> Result<()> {
@@ -45,6 +57,10 @@ struct Args {
     /// 验证配置模式
     #[arg(short, long)]
     validate_config: bool,
+
+    /// 向运行中的实例发送控制命令，例如 --ctl drain 192.168.1.10:80、--ctl status、--ctl dns
+    #[arg(long, num_args = 1.., value_name = "COMMAND")]
+    ctl: Option<Vec<String>>,
 }
 
 #[tokio::main]
@@ -56,6 +72,20 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
+    // 控制命令：通过配置中的控制接口发送给运行中的实例后直接退出
+    if let Some(command) = &args.ctl {
+        let config = Config::load_from_file(&args.config)?;
+        let Some(control) = config.get_control() else {
+            anyhow::bail!("配置文件未启用 control 控制接口");
+        };
+        let reply = control::send_command(&control.get_listen(), &command.join(" ")).await?;
+        if let Some(error) = reply.strip_prefix("ERR ") {
+            anyhow::bail!("{}", error.trim());
+        }
+        print!("{}", reply);
+        return Ok(());
+    }
+
     // 后台运行处理
     if args.daemon {
         daemonize(&args.pid_file)?;
@@ -136,13 +166,45 @@ async fn main() -> Result<()> {
             "  连接超时: {}秒",
             global_dynamic_config.get_connection_timeout()
         );
+        println!(
+            "  UDP会话超时: {}秒",
+            global_dynamic_config.get_udp_timeout()
+        );
+        if global_dynamic_config.get_max_lifetime() > 0 {
+            println!("  最长存活: {}秒", global_dynamic_config.get_max_lifetime());
+        }
         println!("  自动重连: {}", global_dynamic_config.get_auto_reconnect());
+        let dns_config = config.get_dns_config();
+        println!(
+            "  DNS解析器: {} 超时{}秒 重试{}次 {}",
+            dns_config.get_resolvers().join(", "),
+            dns_config.get_timeout(),
+            dns_config.get_attempts(),
+            dns_config.get_ordering()
+        );
+        // 只显示密钥类型，不输出密钥内容
+        if let Some((kind, _)) = dns_config
+            .txt_key
+            .as_deref()
+            .and_then(|k| k.split_once(':'))
+        {
+            println!("  TXT记录签名校验: {}", kind);
+        }
+        if let Some(control) = config.get_control() {
+            println!("  控制接口: {}", control.get_listen());
+            if let Some(state_file) = &control.state_file {
+                println!("  状态文件: {}", state_file);
+            }
+        }
 
         // 验证规则配置
         println!("\n📋 转发规则配置:");
         for (i, rule) in config.rules.iter().enumerate() {
             println!("  规则 {}: {}", i + 1, rule.name);
             println!("    监听端口: {}", rule.listen_port);
+            if let Ok(listen_addrs) = rule.get_listen_addrs(&config.network.listen_addr) {
+                println!("    监听地址: {}", format_listen_addrs(&listen_addrs));
+            }
 
             // 显示协议信息
             let protocols = rule.get_protocols();
@@ -156,7 +218,67 @@ async fn main() -> Result<()> {
                 "    缓冲区大小: {}字节",
                 rule.get_effective_buffer_size(8192)
             );
-            println!("    目标地址: {:?}", rule.targets);
+            println!("    目标地址: {:?}", rule.get_target_addresses());
+            if rule.has_managed_targets() && rule.targets.len() > 1 {
+                println!("    负载均衡: {}", rule.get_strategy().as_str());
+                if rule.get_slow_start() > 0 {
+                    println!("    慢启动: {}秒", rule.get_slow_start());
+                }
+            }
+            if let Some(tiers) = &rule.tiers {
+                let tiers: Vec<_> = tiers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, tier)| format!("层级{}{:?}", i + 1, tier))
+                    .collect();
+                println!(
+                    "    优先级分层: {} 抢占延迟{}秒",
+                    tiers.join(" "),
+                    rule.get_preempt_delay()
+                );
+            }
+            if rule.get_connect_retries() > 0 {
+                println!(
+                    "    连接重试: {}次 总时限{}秒",
+                    rule.get_connect_retries(),
+                    rule.get_connect_deadline()
+                );
+            }
+            if let Some(health_check) = &rule.health_check {
+                println!(
+                    "    健康检查: {} 间隔{}秒 超时{}毫秒 rise={} fall={}",
+                    health_check.check_type.as_deref().unwrap_or("默认"),
+                    health_check
+                        .get_interval(&rule.get_dynamic_update_config(&global_dynamic_config)),
+                    health_check.get_timeout(),
+                    health_check.get_rise(),
+                    health_check.get_fall()
+                );
+            }
+            if rule.resolver.is_some() {
+                let resolver = rule.get_resolver_config(&dns_config);
+                println!("    DNS解析器: {}", resolver.get_resolvers().join(", "));
+                if let Some((kind, _)) = resolver.txt_key.as_deref().and_then(|k| k.split_once(':'))
+                {
+                    println!("    TXT记录签名校验: {}", kind);
+                }
+            }
+            if let Some(action) = rule.get_no_healthy_action() {
+                let action = match action {
+                    NoHealthyAction::Reject => "reject".to_string(),
+                    NoHealthyAction::Hold(hold) => format!("hold {}秒", hold.as_secs()),
+                    NoHealthyAction::StaticResponse(response) => {
+                        format!("static_response {}字节", response.len())
+                    }
+                    NoHealthyAction::FallbackTarget(target) => {
+                        format!("fallback_target {}", target)
+                    }
+                };
+                println!("    无健康目标时: {}", action);
+            }
+            if rule.is_reverse() {
+                println!("    反向隧道: 由隧道服务端开放端口 {}", rule.listen_port);
+            }
 
             // 验证规则级别的动态更新配置
             let rule_dynamic_config = rule.get_dynamic_update_config(&global_dynamic_config);
@@ -169,6 +291,16 @@ async fn main() -> Result<()> {
                 "      连接超时: {}秒",
                 rule_dynamic_config.get_connection_timeout()
             );
+            println!(
+                "      UDP会话超时: {}秒",
+                rule_dynamic_config.get_udp_timeout()
+            );
+            if rule_dynamic_config.get_max_lifetime() > 0 {
+                println!(
+                    "      最长存活: {}秒",
+                    rule_dynamic_config.get_max_lifetime()
+                );
+            }
             println!(
                 "      自动重连: {}",
                 rule_dynamic_config.get_auto_reconnect()
@@ -181,10 +313,18 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    // 按 dns 配置构建目标域名解析器
+    resolver::configure(&config).await;
+
     // 创建公共管理器
     let common_manager = CommonManager::new(config.clone());
     common_manager.initialize().await?;
 
+    // 启动本地控制接口
+    if let Some(control) = config.get_control() {
+        control::start(&control.get_listen(), common_manager.clone()).await?;
+    }
+
     // 创建智能转发器
     let mut forwarder = SmartForwarder::new(config, common_manager);
 
@@ -0,0 +1,478 @@
+// 应用层健康探测 - HTTP(S) 请求、TLS握手、TCP收发校验和UDP请求应答
+use crate::config::HealthCheckConfig;
+use crate::utils::{connect_target, TargetAddr};
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, Error as TlsError, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use tokio_rustls::TlsConnector;
+
+// 探测响应最多读取的字节数
+const MAX_RESPONSE_LEN: usize = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeType {
+    Tcp,   // 建立连接，配置 send/expect 时再做一次收发校验
+    Http,  // GET 请求，校验状态码和响应体
+    Https, // TLS 之上的 HTTP 探测
+    Tls,   // 完成TLS握手
+    Udp,   // 发送 send 并等待匹配 expect 的应答
+}
+
+impl ProbeType {
+    pub const ALL: [ProbeType; 5] = [
+        ProbeType::Tcp,
+        ProbeType::Http,
+        ProbeType::Https,
+        ProbeType::Tls,
+        ProbeType::Udp,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeType::Tcp => "tcp",
+            ProbeType::Http => "http",
+            ProbeType::Https => "https",
+            ProbeType::Tls => "tls",
+            ProbeType::Udp => "udp",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.as_str() == name)
+    }
+}
+
+// 探测载荷：hex: 前缀按十六进制解码，其余按原始字节
+pub fn parse_payload(payload: &str) -> Result<Vec<u8>> {
+    match payload.strip_prefix("hex:") {
+        Some(hex_str) => hex::decode(hex_str.trim())
+            .map_err(|e| anyhow::anyhow!("无效的十六进制载荷 {}: {}", payload, e)),
+        None => Ok(payload.as_bytes().to_vec()),
+    }
+}
+
+// 目标字符串中的主机名，用作HTTP Host头和TLS SNI的默认值
+pub fn target_host(target: &str) -> String {
+    let host = match target.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => target,
+    };
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string()
+}
+
+// 对单个解析地址执行一次探测，返回探测耗时
+pub async fn probe(
+    addr: &TargetAddr,
+    host: &str,
+    probe_type: ProbeType,
+    check: &HealthCheckConfig,
+) -> Result<Duration> {
+    let timeout = Duration::from_millis(check.get_timeout());
+    let start = Instant::now();
+
+    match tokio::time::timeout(timeout, run_probe(addr, host, probe_type, check)).await {
+        Ok(Ok(())) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(anyhow::anyhow!(
+            "{}探测失败 {}: {}",
+            probe_type.as_str(),
+            addr,
+            e
+        )),
+        Err(_) => Err(anyhow::anyhow!("{}探测超时: {}", probe_type.as_str(), addr)),
+    }
+}
+
+async fn run_probe(
+    addr: &TargetAddr,
+    host: &str,
+    probe_type: ProbeType,
+    check: &HealthCheckConfig,
+) -> Result<()> {
+    let host = check.host.as_deref().unwrap_or(host);
+    if probe_type == ProbeType::Udp {
+        let TargetAddr::Inet(addr) = addr else {
+            anyhow::bail!("Unix套接字目标不支持UDP探测");
+        };
+        return probe_udp(*addr, check).await;
+    }
+
+    let stream = connect_target(addr).await?;
+    match probe_type {
+        ProbeType::Tcp => send_expect(stream, check).await,
+        ProbeType::Http => http_get(stream, host, check).await,
+        ProbeType::Tls => tls_connect(stream, host, check).await.map(|_| ()),
+        ProbeType::Https => {
+            let stream = tls_connect(stream, host, check).await?;
+            http_get(stream, host, check).await
+        }
+        ProbeType::Udp => unreachable!(),
+    }
+}
+
+async fn send_expect<S>(mut stream: S, check: &HealthCheckConfig) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(send) = &check.send {
+        stream.write_all(&parse_payload(send)?).await?;
+    }
+    let Some(expect) = &check.expect else {
+        return Ok(());
+    };
+
+    let expect = parse_payload(expect)?;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while response.len() < MAX_RESPONSE_LEN {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..n]);
+        if contains(&response, &expect) {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("应答不包含期望内容")
+}
+
+async fn probe_udp(addr: SocketAddr, check: &HealthCheckConfig) -> Result<()> {
+    let bind_addr: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    socket
+        .send(&parse_payload(check.send.as_deref().unwrap_or_default())?)
+        .await?;
+
+    // 不匹配的应答继续等待，直到超时
+    let expect = check.expect.as_deref().map(parse_payload).transpose()?;
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        let n = socket.recv(&mut buffer).await?;
+        match &expect {
+            Some(expect) if !contains(&buffer[..n], expect) => continue,
+            _ => return Ok(()),
+        }
+    }
+}
+
+async fn http_get<S>(mut stream: S, host: &str, check: &HealthCheckConfig) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: smart-forward\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        check.get_path(),
+        host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let mut status = None;
+    loop {
+        let n = stream.read(&mut buffer).await?;
+        response.extend_from_slice(&buffer[..n]);
+
+        if status.is_none() {
+            if let Some(line_end) = find(&response, b"\r\n") {
+                let code = parse_status_line(&response[..line_end])?;
+                if !check.status_matches(code) {
+                    anyhow::bail!("HTTP状态码 {}", code);
+                }
+                status = Some(code);
+            }
+        }
+
+        // 状态码符合且无需校验响应体时不必读完整个响应
+        if status.is_some() {
+            match &check.expect_body {
+                None => return Ok(()),
+                Some(body) => {
+                    let body_start = find(&response, b"\r\n\r\n").map(|i| i + 4);
+                    if body_start.is_some_and(|start| contains(&response[start..], body.as_bytes()))
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if n == 0 || response.len() >= MAX_RESPONSE_LEN {
+            break;
+        }
+    }
+
+    match status {
+        Some(_) => anyhow::bail!("响应体不包含期望内容"),
+        None => anyhow::bail!("无效的HTTP响应"),
+    }
+}
+
+fn parse_status_line(line: &[u8]) -> Result<u16> {
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code
+            .parse()
+            .map_err(|_| anyhow::anyhow!("无效的HTTP状态行: {}", line)),
+        _ => anyhow::bail!("无效的HTTP状态行: {}", line),
+    }
+}
+
+async fn tls_connect<S>(
+    stream: S,
+    host: &str,
+    check: &HealthCheckConfig,
+) -> Result<tokio_rustls::client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name =
+        ServerName::try_from(host).map_err(|_| anyhow::anyhow!("无效的TLS服务器名: {}", host))?;
+    let connector = TlsConnector::from(tls_config(check.get_tls_verify()));
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+// 默认只校验能否完成握手，内网目标多为自签名证书；tls_verify 开启时按系统内置根证书校验
+fn tls_config(verify: bool) -> Arc<ClientConfig> {
+    static VERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static UNVERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    if verify {
+        VERIFIED
+            .get_or_init(|| {
+                let mut roots = RootCertStore::empty();
+                roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+                Arc::new(
+                    ClientConfig::builder()
+                        .with_safe_defaults()
+                        .with_root_certificates(roots)
+                        .with_no_client_auth(),
+                )
+            })
+            .clone()
+    } else {
+        UNVERIFIED
+            .get_or_init(|| {
+                let mut config = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(RootCertStore::empty())
+                    .with_no_client_auth();
+                config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(AcceptAnyCert));
+                Arc::new(config)
+            })
+            .clone()
+    }
+}
+
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || find(haystack, needle).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_and_status_line() {
+        assert_eq!(parse_payload("PING\r\n").unwrap(), b"PING\r\n");
+        assert_eq!(parse_payload("hex:ff00").unwrap(), vec![0xff, 0x00]);
+        assert!(parse_payload("hex:zz").is_err());
+
+        assert_eq!(parse_status_line(b"HTTP/1.1 204 No Content").unwrap(), 204);
+        assert!(parse_status_line(b"SSH-2.0-OpenSSH").is_err());
+
+        assert_eq!(target_host("example.com:443"), "example.com");
+        assert_eq!(target_host("[::1]:8443"), "::1");
+    }
+
+    // 读完请求头后返回固定响应并关闭连接
+    async fn respond<S>(mut stream: S, response: &[u8])
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while find(&request, b"\r\n\r\n").is_none() {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buffer[..n]),
+            }
+        }
+        let _ = stream.write_all(response).await;
+        let _ = stream.shutdown().await;
+    }
+
+    async fn http_server(response: &'static [u8]) -> TargetAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(respond(stream, response));
+            }
+        });
+        TargetAddr::Inet(addr)
+    }
+
+    // 按YAML写法的 health_check 配置探测一次，默认超时1秒
+    async fn probe_with(addr: &TargetAddr, probe_type: ProbeType, yaml: &str) -> Result<Duration> {
+        let mut check: HealthCheckConfig = serde_yaml::from_str(yaml).unwrap();
+        check.timeout.get_or_insert(1000);
+        probe(addr, "localhost", probe_type, &check).await
+    }
+
+    #[tokio::test]
+    async fn test_http_probe_status_and_body() {
+        let addr = http_server(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world").await;
+        let http = |yaml| probe_with(&addr, ProbeType::Http, yaml);
+
+        assert!(http("{}").await.is_ok());
+        assert!(http("{expect_body: world}").await.is_ok());
+        assert!(http("{expect_status: 200, path: /health}").await.is_ok());
+        let error = http("{expect_status: 204}").await.unwrap_err().to_string();
+        assert!(error.contains("HTTP状态码 200"), "{}", error);
+        let error = http("{expect_body: missing}")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("响应体不包含期望内容"), "{}", error);
+
+        let addr = http_server(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await;
+        assert!(probe_with(&addr, ProbeType::Http, "{}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_send_expect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TargetAddr::Inet(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 6];
+                    if stream.read_exact(&mut buffer).await.is_ok() && &buffer == b"PING\r\n" {
+                        let _ = stream.write_all(b"+PONG\r\n").await;
+                    }
+                });
+            }
+        });
+
+        let tcp = |yaml| probe_with(&addr, ProbeType::Tcp, yaml);
+        assert!(tcp("{}").await.is_ok());
+        assert!(tcp("{send: \"PING\\r\\n\", expect: PONG}").await.is_ok());
+        assert!(
+            tcp("{send: \"hex:50494e470d0a\", expect: \"hex:504f4e47\"}")
+                .await
+                .is_ok()
+        );
+        // 对端关闭前未收到期望内容
+        assert!(tcp("{send: \"PING\\r\\n\", expect: NOPE}").await.is_err());
+        assert!(tcp("{send: \"QUIT\\r\\n\", expect: PONG}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_and_https() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = tokio_rustls::rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                tokio_rustls::rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = TargetAddr::Inet(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        respond(stream, b"HTTP/1.1 200 OK\r\n\r\nsecure").await;
+                    }
+                });
+            }
+        });
+
+        assert!(probe_with(&addr, ProbeType::Tls, "{}").await.is_ok());
+        assert!(probe_with(&addr, ProbeType::Https, "{expect_body: secure}")
+            .await
+            .is_ok());
+        // 自签名证书在开启校验时握手失败
+        assert!(probe_with(&addr, ProbeType::Tls, "{tls_verify: true}")
+            .await
+            .is_err());
+        // 明文服务不能完成TLS握手
+        let plain = http_server(b"HTTP/1.1 200 OK\r\n\r\n").await;
+        assert!(probe_with(&plain, ProbeType::Tls, "{}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_probe() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = TargetAddr::Inet(server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            while let Ok((n, peer)) = server.recv_from(&mut buffer).await {
+                if &buffer[..n] == b"ping" {
+                    let _ = server.send_to(b"pong", peer).await;
+                }
+            }
+        });
+
+        let udp = |yaml| probe_with(&addr, ProbeType::Udp, yaml);
+        assert!(udp("{send: ping}").await.is_ok());
+        assert!(udp("{send: ping, expect: pong}").await.is_ok());
+        // 应答不匹配或没有应答时等到超时
+        let error = udp("{send: ping, expect: nope, timeout: 200}")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("udp探测超时"), "{}", error);
+        assert!(udp("{send: hello, timeout: 200}").await.is_err());
+    }
+}
@@ -0,0 +1,489 @@
+// 目标域名解析 - 按 dns 配置构建异步解析器，配置了 resolver 的规则使用自己的解析器
+//
+// 所有转发器、健康检查和DNS刷新共用同一组解析器：应答按记录TTL缓存，
+// NXDOMAIN/无记录按否定TTL缓存，同一域名的并发查询合并为一次上游请求
+use crate::config::{Config, DnsResolverConfig};
+use crate::txt::{self, TxtEndpoint, TxtKey};
+use crate::utils::parse_dns_upstream;
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts, ServerOrderingStrategy,
+};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// 系统DNS配置不可用时（如Android没有resolv.conf）使用的服务器
+const FALLBACK_NAME_SERVERS: [IpAddr; 2] = [
+    IpAddr::V4(std::net::Ipv4Addr::new(223, 5, 5, 5)),
+    IpAddr::V4(std::net::Ipv4Addr::new(223, 6, 6, 6)),
+];
+
+// 否定应答没有携带SOA时的缓存时间，以及否定缓存的上限
+const DEFAULT_NEGATIVE_TTL: u32 = 30;
+const MAX_NEGATIVE_TTL: u32 = 300;
+// 缓存条目达到该数量时清理已过期的条目
+const CACHE_PRUNE_THRESHOLD: usize = 1024;
+
+static RESOLVERS: RwLock<Option<Arc<Resolvers>>> = RwLock::new(None);
+
+struct Resolvers {
+    default: Arc<DnsResolver>,
+    targets: HashMap<String, Arc<DnsResolver>>, // 规则级解析器：目标 -> 解析器
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Ip(String),  // A/AAAA
+    Txt(String), // TXT
+    Srv(String), // SRV
+}
+
+#[derive(Debug, Clone)]
+enum Answer {
+    Ips(Vec<IpAddr>),
+    Txt(Vec<String>), // 每条TXT记录的字符串拼接结果
+    Srv(Vec<SrvRecord>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String, // 目标主机名，已去掉末尾的点
+}
+
+type LookupResult = std::result::Result<Answer, String>;
+
+struct CacheEntry {
+    result: LookupResult,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct CacheStats {
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64, // 合并到进行中查询的请求
+    errors: AtomicU64,
+}
+
+#[derive(Default)]
+struct DnsCache {
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    inflight: Mutex<HashMap<CacheKey, Shared<BoxFuture<'static, LookupResult>>>>,
+    stats: CacheStats,
+}
+
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+    cache: Arc<DnsCache>,
+    txt_key: Option<TxtKey>, // TXT目标记录的签名校验密钥
+}
+
+impl DnsResolver {
+    pub async fn new(config: &DnsResolverConfig) -> Self {
+        let mut name_servers = NameServerConfigGroup::new();
+        for spec in config.get_resolvers() {
+            if spec == "system" {
+                match hickory_resolver::system_conf::read_system_conf() {
+                    Ok((system, _)) => name_servers.extend(system.name_servers().iter().cloned()),
+                    Err(e) => warn!("读取系统DNS配置失败: {}", e),
+                }
+                continue;
+            }
+            match parse_dns_upstream(&spec).await {
+                Ok(servers) => name_servers.extend(servers),
+                Err(e) => warn!("DNS服务器 {} 无效: {}", spec, e),
+            }
+        }
+        if name_servers.is_empty() {
+            warn!("没有可用的DNS服务器，使用 223.5.5.5/223.6.6.6");
+            name_servers = NameServerConfigGroup::from_ips_clear(&FALLBACK_NAME_SERVERS, 53, true);
+        }
+
+        let mut opts = ResolverOpts::default();
+        opts.timeout = Duration::from_secs(config.get_timeout());
+        opts.attempts = config.get_attempts();
+        // 同时查询A和AAAA记录，由Happy Eyeballs决定实际使用的地址
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        if config.get_ordering() == "sequential" {
+            // 严格按配置顺序逐个尝试，前面的服务器失败才查询下一个
+            opts.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+            opts.num_concurrent_reqs = 1;
+        }
+
+        Self {
+            resolver: TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, vec![], name_servers),
+                opts,
+            ),
+            cache: Arc::new(DnsCache::default()),
+            // 配置加载时已校验过密钥格式
+            txt_key: config
+                .txt_key
+                .as_deref()
+                .and_then(|key| TxtKey::parse(key).ok()),
+        }
+    }
+
+    // 查询缓存，未命中时发起上游查询；同一键已有查询进行中则等待其结果
+    async fn lookup(&self, key: CacheKey) -> Result<Answer> {
+        if let Some(entry) = self.cache.entries.lock().unwrap().get(&key) {
+            if entry.expires > Instant::now() {
+                let counter = match entry.result {
+                    Ok(_) => &self.cache.stats.hits,
+                    Err(_) => &self.cache.stats.negative_hits,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                return entry.result.clone().map_err(anyhow::Error::msg);
+            }
+        }
+
+        let pending = {
+            let mut inflight = self.cache.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(pending) => {
+                    self.cache.stats.coalesced.fetch_add(1, Ordering::Relaxed);
+                    pending.clone()
+                }
+                None => {
+                    self.cache.stats.misses.fetch_add(1, Ordering::Relaxed);
+                    let pending =
+                        Self::query(self.resolver.clone(), self.cache.clone(), key.clone())
+                            .boxed()
+                            .shared();
+                    inflight.insert(key, pending.clone());
+                    pending
+                }
+            }
+        };
+        pending.await.map_err(anyhow::Error::msg)
+    }
+
+    // 上游查询：先写入缓存再移出进行中列表，避免两者之间的重复查询
+    async fn query(
+        resolver: TokioAsyncResolver,
+        cache: Arc<DnsCache>,
+        key: CacheKey,
+    ) -> LookupResult {
+        let (result, expires) = match &key {
+            CacheKey::Ip(hostname) => match resolver.lookup_ip(hostname.as_str()).await {
+                Ok(lookup) => (
+                    Ok(Answer::Ips(lookup.iter().collect())),
+                    Some(lookup.valid_until()),
+                ),
+                Err(e) => (
+                    Err(format!("DNS解析失败 {}: {}", hostname, e)),
+                    negative_expiry(&e),
+                ),
+            },
+            CacheKey::Txt(hostname) => match resolver.txt_lookup(hostname.as_str()).await {
+                Ok(lookup) => {
+                    let records = lookup
+                        .iter()
+                        .map(|txt| {
+                            txt.iter()
+                                .map(|data| String::from_utf8_lossy(data))
+                                .collect::<String>()
+                        })
+                        .collect();
+                    (
+                        Ok(Answer::Txt(records)),
+                        Some(lookup.as_lookup().valid_until()),
+                    )
+                }
+                Err(e) => (
+                    Err(format!("TXT记录查询失败 {}: {}", hostname, e)),
+                    negative_expiry(&e),
+                ),
+            },
+            CacheKey::Srv(name) => match resolver.srv_lookup(name.as_str()).await {
+                Ok(lookup) => {
+                    let records = lookup
+                        .iter()
+                        .map(|srv| SrvRecord {
+                            priority: srv.priority(),
+                            weight: srv.weight(),
+                            port: srv.port(),
+                            target: srv.target().to_ascii().trim_end_matches('.').to_string(),
+                        })
+                        .collect();
+                    (
+                        Ok(Answer::Srv(records)),
+                        Some(lookup.as_lookup().valid_until()),
+                    )
+                }
+                Err(e) => (
+                    Err(format!("SRV记录查询失败 {}: {}", name, e)),
+                    negative_expiry(&e),
+                ),
+            },
+        };
+
+        match (&result, expires) {
+            (Err(e), None) => {
+                cache.stats.errors.fetch_add(1, Ordering::Relaxed);
+                debug!("{}", e);
+            }
+            (_, Some(expires)) => {
+                let mut entries = cache.entries.lock().unwrap();
+                if entries.len() >= CACHE_PRUNE_THRESHOLD {
+                    let now = Instant::now();
+                    entries.retain(|_, entry| entry.expires > now);
+                }
+                entries.insert(
+                    key.clone(),
+                    CacheEntry {
+                        result: result.clone(),
+                        expires,
+                    },
+                );
+            }
+            _ => {}
+        }
+        cache.inflight.lock().unwrap().remove(&key);
+        result
+    }
+
+    // 解析A和AAAA记录，然后拼接端口
+    pub async fn lookup_ip(&self, hostname: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let Answer::Ips(ips) = self.lookup(CacheKey::Ip(hostname.to_lowercase())).await? else {
+            unreachable!()
+        };
+        let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+        if addrs.is_empty() {
+            anyhow::bail!("没有找到可用的IP地址: {}", hostname)
+        }
+        Ok(addrs)
+    }
+
+    // 解析TXT记录中发布的端点，配置了 txt_key 时只接受签名有效的记录
+    pub async fn lookup_txt(&self, hostname: &str) -> Result<Vec<TxtEndpoint>> {
+        let Answer::Txt(records) = self.lookup(CacheKey::Txt(hostname.to_lowercase())).await?
+        else {
+            unreachable!()
+        };
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        txt::parse_records(hostname, &records, self.txt_key.as_ref(), now)
+    }
+
+    // 解析SRV记录，目标为 "." 的记录表示服务不可用，直接忽略
+    pub async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+        let Answer::Srv(records) = self.lookup(CacheKey::Srv(name.to_lowercase())).await? else {
+            unreachable!()
+        };
+        let records: Vec<_> = records
+            .into_iter()
+            .filter(|record| !record.target.is_empty())
+            .collect();
+        if records.is_empty() {
+            anyhow::bail!("SRV记录中没有可用的目标: {}", name)
+        }
+        Ok(records)
+    }
+}
+
+// 按配置构建全局解析器，启动转发前调用。
+// 目标缓存按地址在规则间共享，Config::validate 保证同一目标在各规则中使用相同的解析器，
+// 因此按目标登记规则级解析器不会影响其他规则
+pub async fn configure(config: &Config) {
+    let global = config.get_dns_config();
+    info!("DNS解析器: {}", global.get_resolvers().join(", "));
+    let default = Arc::new(DnsResolver::new(&global).await);
+
+    let mut targets = HashMap::new();
+    for rule in &config.rules {
+        if rule.resolver.is_none() {
+            continue;
+        }
+        let rule_config = rule.get_resolver_config(&global);
+        info!(
+            "规则 {} DNS解析器: {}",
+            rule.name,
+            rule_config.get_resolvers().join(", ")
+        );
+        let resolver = Arc::new(DnsResolver::new(&rule_config).await);
+        for target in rule.get_resolved_targets() {
+            targets.insert(target, resolver.clone());
+        }
+    }
+
+    *RESOLVERS.write().unwrap() = Some(Arc::new(Resolvers { default, targets }));
+}
+
+// 目标使用的解析器；未调用 configure 时（如 --ctl 客户端）按默认配置构建
+pub async fn resolver_for(target: &str) -> Arc<DnsResolver> {
+    let resolvers = RESOLVERS.read().unwrap().clone();
+    let resolvers = match resolvers {
+        Some(resolvers) => resolvers,
+        None => {
+            let resolvers = Arc::new(Resolvers {
+                default: Arc::new(DnsResolver::new(&DnsResolverConfig::default()).await),
+                targets: HashMap::new(),
+            });
+            RESOLVERS.write().unwrap().get_or_insert(resolvers).clone()
+        }
+    };
+    resolvers
+        .targets
+        .get(target)
+        .unwrap_or(&resolvers.default)
+        .clone()
+}
+
+// 否定应答（NXDOMAIN/无记录）按SOA给出的否定TTL缓存，超时等其他错误不缓存
+fn negative_expiry(error: &ResolveError) -> Option<Instant> {
+    match error.kind() {
+        ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+            let ttl = negative_ttl
+                .unwrap_or(DEFAULT_NEGATIVE_TTL)
+                .min(MAX_NEGATIVE_TTL);
+            Some(Instant::now() + Duration::from_secs(ttl as u64))
+        }
+        _ => None,
+    }
+}
+
+// 所有解析器的缓存统计
+pub fn cache_stats() -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let Some(resolvers) = RESOLVERS.read().unwrap().clone() else {
+        return result;
+    };
+
+    // 多个目标可能共用同一个规则级解析器，按实例去重
+    let mut caches: Vec<&Arc<DnsCache>> = vec![&resolvers.default.cache];
+    for resolver in resolvers.targets.values() {
+        if !caches
+            .iter()
+            .any(|cache| Arc::ptr_eq(cache, &resolver.cache))
+        {
+            caches.push(&resolver.cache);
+        }
+    }
+
+    let mut entries = 0;
+    let mut totals = [0u64; 5];
+    for cache in caches {
+        entries += cache.entries.lock().unwrap().len();
+        let stats = &cache.stats;
+        for (total, counter) in totals.iter_mut().zip([
+            &stats.hits,
+            &stats.negative_hits,
+            &stats.misses,
+            &stats.coalesced,
+            &stats.errors,
+        ]) {
+            *total += counter.load(Ordering::Relaxed);
+        }
+    }
+    for (key, value) in [
+        "cache_hits",
+        "negative_hits",
+        "cache_misses",
+        "coalesced",
+        "errors",
+    ]
+    .into_iter()
+    .zip(totals)
+    {
+        result.insert(key.to_string(), value.to_string());
+    }
+    result.insert("cache_entries".to_string(), entries.to_string());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::A;
+    use hickory_resolver::proto::rr::{RData, Record, RecordType};
+    use tokio::net::UdpSocket;
+
+    // 本地DNS服务器：web.test 返回 127.0.0.1，其余域名返回NXDOMAIN，记录收到的查询数
+    async fn spawn_dns_server(queries: Arc<AtomicU64>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+                queries.fetch_add(1, Ordering::Relaxed);
+                let request = Message::from_vec(&buffer[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_query(query.clone());
+                if query.name().to_ascii() != "web.test." {
+                    response.set_response_code(ResponseCode::NXDomain);
+                } else if query.query_type() == RecordType::A {
+                    response.add_answer(Record::from_rdata(
+                        query.name().clone(),
+                        60,
+                        RData::A(A("127.0.0.1".parse().unwrap())),
+                    ));
+                }
+                // 稍作延迟，让并发查询有机会合并
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_cache_and_coalescing() {
+        let queries = Arc::new(AtomicU64::new(0));
+        let server = spawn_dns_server(queries.clone()).await;
+        let resolver = Arc::new(
+            DnsResolver::new(&DnsResolverConfig {
+                resolvers: Some(vec![format!("udp://{}", server)]),
+                attempts: Some(0),
+                ..Default::default()
+            })
+            .await,
+        );
+
+        // 并发查询合并为一次上游请求（A和AAAA各一个报文）
+        let lookups: Vec<_> = (0..5)
+            .map(|_| {
+                let resolver = resolver.clone();
+                tokio::spawn(async move { resolver.lookup_ip("web.test", 80).await })
+            })
+            .collect();
+        for lookup in lookups {
+            assert_eq!(
+                lookup.await.unwrap().unwrap(),
+                vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()]
+            );
+        }
+        let upstream_queries = queries.load(Ordering::Relaxed);
+        assert_eq!(resolver.cache.stats.misses.load(Ordering::Relaxed), 1);
+        assert_eq!(resolver.cache.stats.coalesced.load(Ordering::Relaxed), 4);
+
+        // TTL内直接命中缓存
+        resolver.lookup_ip("WEB.test", 443).await.unwrap();
+        assert_eq!(resolver.cache.stats.hits.load(Ordering::Relaxed), 1);
+
+        // NXDOMAIN 缓存为否定应答
+        assert!(resolver.lookup_ip("missing.test", 80).await.is_err());
+        assert!(resolver.lookup_ip("missing.test", 80).await.is_err());
+        assert_eq!(
+            resolver.cache.stats.negative_hits.load(Ordering::Relaxed),
+            1
+        );
+        assert!(queries.load(Ordering::Relaxed) <= upstream_queries + 2);
+    }
+}
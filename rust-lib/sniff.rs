@@ -0,0 +1,465 @@
+// 协议嗅探 - 读取连接的首批字节识别协议，使同一端口按协议分流到不同目标
+use crate::config::{SniffConfig, SniffRoute};
+use crate::utils::BoxedStream;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+// 识别协议最多读取的字节数
+const MAX_SNIFF_LEN: usize = 16;
+
+// 读取 SNI/Host 最多缓存的字节数（ClientHello 一般不超过一个TLS记录）
+const MAX_HOST_PEEK_LEN: usize = 4096;
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SniffProtocol {
+    Tls,
+    Http,
+    Ssh,
+    Socks,
+    Unknown, // 无法识别，或客户端在超时内没有发送数据（服务端先发言的协议）
+}
+
+impl SniffProtocol {
+    pub const ALL: [SniffProtocol; 5] = [
+        SniffProtocol::Tls,
+        SniffProtocol::Http,
+        SniffProtocol::Ssh,
+        SniffProtocol::Socks,
+        SniffProtocol::Unknown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SniffProtocol::Tls => "tls",
+            SniffProtocol::Http => "http",
+            SniffProtocol::Ssh => "ssh",
+            SniffProtocol::Socks => "socks",
+            SniffProtocol::Unknown => "unknown",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.as_str() == name)
+    }
+}
+
+// 前缀匹配：数据不足时返回 None 表示需要更多字节
+fn match_prefix(data: &[u8], pattern: &[u8]) -> Option<bool> {
+    if data.len() >= pattern.len() {
+        Some(data.starts_with(pattern))
+    } else if pattern.starts_with(data) {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+/// 根据首批字节识别协议，返回 None 表示还需要更多数据
+pub fn classify(data: &[u8]) -> Option<SniffProtocol> {
+    let first = *data.first()?;
+    match first {
+        // TLS记录头：握手类型0x16，版本主号0x03
+        0x16 => {
+            let version = data.get(1..3)?;
+            Some(if version[0] == 0x03 && version[1] <= 0x04 {
+                SniffProtocol::Tls
+            } else {
+                SniffProtocol::Unknown
+            })
+        }
+        // SOCKS5问候：版本5 + 认证方法数；SOCKS4请求：版本4 + CONNECT/BIND命令
+        0x05 => Some(if *data.get(1)? > 0 {
+            SniffProtocol::Socks
+        } else {
+            SniffProtocol::Unknown
+        }),
+        0x04 => Some(if matches!(*data.get(1)?, 0x01 | 0x02) {
+            SniffProtocol::Socks
+        } else {
+            SniffProtocol::Unknown
+        }),
+        b'S' => match_prefix(data, b"SSH-").map(|ssh| {
+            if ssh {
+                SniffProtocol::Ssh
+            } else {
+                SniffProtocol::Unknown
+            }
+        }),
+        _ => {
+            let mut need_more = false;
+            for method in HTTP_METHODS {
+                match match_prefix(data, method) {
+                    Some(true) => return Some(SniffProtocol::Http),
+                    Some(false) => {}
+                    None => need_more = true,
+                }
+            }
+            if need_more {
+                None
+            } else {
+                Some(SniffProtocol::Unknown)
+            }
+        }
+    }
+}
+
+/// 先回放嗅探时已读取的字节，再读取原始流
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = (self.prefix.len() - self.pos).min(buf.remaining());
+            let start = self.pos;
+            buf.put_slice(&self.prefix[start..start + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 嗅探连接协议，返回协议类型和可从头读取的流
+pub async fn sniff_stream(
+    mut stream: BoxedStream,
+    timeout: Duration,
+) -> (SniffProtocol, BoxedStream) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut data = Vec::with_capacity(MAX_SNIFF_LEN);
+    let mut chunk = [0u8; MAX_SNIFF_LEN];
+
+    let protocol = loop {
+        if let Some(protocol) = classify(&data) {
+            break protocol;
+        }
+        let want = MAX_SNIFF_LEN - data.len();
+        match tokio::time::timeout_at(deadline, stream.read(&mut chunk[..want])).await {
+            Ok(Ok(n)) if n > 0 => data.extend_from_slice(&chunk[..n]),
+            // 超时、EOF或读取错误：按未知协议处理，已读数据照常回放
+            _ => break SniffProtocol::Unknown,
+        }
+    };
+
+    (protocol, Box::new(PrefixedStream::new(data, stream)))
+}
+
+// 按字节游标读取 ClientHello，越界时返回 None
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn skip_u8_vec(&mut self) -> Option<()> {
+        let len = self.u8()?;
+        self.take(len).map(|_| ())
+    }
+
+    fn skip_u16_vec(&mut self) -> Option<()> {
+        let len = self.u16()?;
+        self.take(len).map(|_| ())
+    }
+}
+
+/// 从完整的TLS ClientHello记录中解析 server_name 扩展
+pub fn parse_sni(record: &[u8]) -> Option<String> {
+    let mut reader = Reader {
+        data: record,
+        pos: 0,
+    };
+    reader.take(5)?; // 记录头
+    if reader.u8()? != 0x01 {
+        return None; // 不是 ClientHello
+    }
+    reader.take(3)?; // 握手消息长度
+    reader.take(2 + 32)?; // 客户端版本 + 随机数
+    reader.skip_u8_vec()?; // session_id
+    reader.skip_u16_vec()?; // cipher_suites
+    reader.skip_u8_vec()?; // compression_methods
+
+    let extensions_len = reader.u16()?;
+    let end = reader.pos + extensions_len;
+    while reader.pos + 4 <= end {
+        let ext_type = reader.u16()?;
+        let ext_len = reader.u16()?;
+        let ext = reader.take(ext_len)?;
+        if ext_type != 0x0000 {
+            continue;
+        }
+        // server_name_list：列表长度、名称类型(0=host_name)、名称长度、名称
+        let mut names = Reader { data: ext, pos: 2 };
+        while let Some(name_type) = names.u8() {
+            let name_len = names.u16()?;
+            let name = names.take(name_len)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|n| n.to_string());
+            }
+        }
+    }
+    None
+}
+
+/// 从HTTP请求头中取 Host（去掉端口）
+pub fn parse_http_host(headers: &[u8]) -> Option<String> {
+    let headers = std::str::from_utf8(headers).ok()?;
+    headers.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("host") {
+            return None;
+        }
+        let value = value.trim();
+        // [IPv6]:port 保留方括号内的地址，其余去掉 :port
+        let host = match value.strip_prefix('[') {
+            Some(rest) => rest.split(']').next().unwrap_or(rest),
+            None => value.split(':').next().unwrap_or(value),
+        };
+        (!host.is_empty()).then(|| host.to_string())
+    })
+}
+
+// 返回 None 表示数据还不完整
+fn extract_host(data: &[u8]) -> Option<Option<String>> {
+    match classify(data)? {
+        SniffProtocol::Tls => {
+            let header = data.get(..5)?;
+            let record_len = 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+            if data.len() < record_len.min(MAX_HOST_PEEK_LEN) {
+                return None;
+            }
+            Some(parse_sni(&data[..record_len.min(data.len())]))
+        }
+        SniffProtocol::Http => {
+            let end = data.windows(4).position(|w| w == b"\r\n\r\n")?;
+            Some(parse_http_host(&data[..end]))
+        }
+        _ => Some(None),
+    }
+}
+
+/// 读取TLS SNI或HTTP Host，返回主机名和可从头读取的流
+pub async fn peek_host(
+    mut stream: BoxedStream,
+    timeout: Duration,
+) -> (Option<String>, BoxedStream) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    let host = loop {
+        if let Some(host) = extract_host(&data) {
+            break host;
+        }
+        if data.len() >= MAX_HOST_PEEK_LEN {
+            break None;
+        }
+        let want = chunk.len().min(MAX_HOST_PEEK_LEN - data.len());
+        match tokio::time::timeout_at(deadline, stream.read(&mut chunk[..want])).await {
+            Ok(Ok(n)) if n > 0 => data.extend_from_slice(&chunk[..n]),
+            _ => break None,
+        }
+    };
+
+    (host, Box::new(PrefixedStream::new(data, stream)))
+}
+
+/// 嗅探后的处理方式
+#[derive(Debug, Clone)]
+pub enum SniffAction {
+    Targets(Vec<String>), // 转发到指定目标列表（按顺序取第一个可解析的）
+    Redirect,             // HTTP跳转HTTPS
+    Default,              // 转发到规则的 targets
+}
+
+pub struct Sniffer {
+    timeout: Duration,
+    routes: HashMap<SniffProtocol, SniffAction>,
+    counters: HashMap<SniffProtocol, AtomicU64>,
+}
+
+impl Sniffer {
+    pub fn new(config: &SniffConfig, http_redirect: bool) -> Self {
+        let mut routes = HashMap::new();
+        // protocols 中包含 http 时，未单独配置的HTTP流量交给跳转处理
+        if http_redirect {
+            routes.insert(SniffProtocol::Http, SniffAction::Redirect);
+        }
+        for (name, route) in config.get_routes() {
+            if let Some(protocol) = SniffProtocol::from_name(&name) {
+                let action = match route {
+                    SniffRoute::Targets(targets) => SniffAction::Targets(targets),
+                    SniffRoute::Handler(_) => SniffAction::Redirect,
+                };
+                routes.insert(protocol, action);
+            }
+        }
+
+        Self {
+            timeout: Duration::from_millis(config.get_timeout_ms()),
+            routes,
+            counters: SniffProtocol::ALL
+                .into_iter()
+                .map(|p| (p, AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    pub async fn sniff(&self, stream: BoxedStream) -> (SniffAction, BoxedStream) {
+        let (protocol, stream) = sniff_stream(stream, self.timeout).await;
+        if let Some(counter) = self.counters.get(&protocol) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        let action = self
+            .routes
+            .get(&protocol)
+            .cloned()
+            .unwrap_or(SniffAction::Default);
+        (action, stream)
+    }
+
+    pub fn write_stats(&self, result: &mut HashMap<String, String>) {
+        for (protocol, counter) in &self.counters {
+            result.insert(
+                format!("sniff_{}", protocol.as_str()),
+                counter.load(Ordering::Relaxed).to_string(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify(&[0x16, 0x03, 0x01, 0x02]),
+            Some(SniffProtocol::Tls)
+        );
+        assert_eq!(classify(b"GET / HTTP/1.1"), Some(SniffProtocol::Http));
+        assert_eq!(classify(b"SSH-2.0-OpenSSH"), Some(SniffProtocol::Ssh));
+        assert_eq!(classify(&[0x05, 0x01, 0x00]), Some(SniffProtocol::Socks));
+        assert_eq!(classify(b"\x00\x01garbage"), Some(SniffProtocol::Unknown));
+        // 数据不足时继续等待
+        assert_eq!(classify(b"PO"), None);
+        assert_eq!(classify(b"SS"), None);
+        assert_eq!(classify(&[0x16]), None);
+        assert_eq!(classify(b"POX"), Some(SniffProtocol::Unknown));
+    }
+
+    #[test]
+    fn test_parse_sni_and_host() {
+        // 最小 ClientHello：无密码套件和压缩方法之外的内容，只带 server_name 扩展
+        let name = b"example.com";
+        let mut sni_ext = vec![0x00, 0x00];
+        sni_ext.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+        sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0x00);
+        sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(name);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&sni_ext);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+
+        assert_eq!(extract_host(&record), Some(Some("example.com".to_string())));
+        assert_eq!(extract_host(&record[..20]), None);
+        assert_eq!(
+            extract_host(b"GET / HTTP/1.1\r\nHost: Web.example.com:8080\r\n\r\n"),
+            Some(Some("Web.example.com".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sniffed_bytes_are_replayed() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = client;
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"GET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+
+        let (protocol, mut stream) =
+            sniff_stream(Box::new(server), Duration::from_millis(200)).await;
+        assert_eq!(protocol, SniffProtocol::Http);
+
+        let mut buffer = vec![0u8; 16];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"GET / HTTP/1.1\r\n");
+    }
+}
@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionInfo {
@@ -12,7 +11,7 @@ pub struct ConnectionInfo {
     pub start_time: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ProxyStats {
     pub total_connections: u64,
     pub active_connections: u64,
@@ -21,17 +20,6 @@ pub struct ProxyStats {
     pub uptime_seconds: u64,
 }
 
-impl Default for ProxyStats {
-    fn default() -> Self {
-        Self {
-            total_connections: 0,
-            active_connections: 0,
-            total_bytes_sent: 0,
-            total_bytes_received: 0,
-            uptime_seconds: 0,
-        }
-    }
-}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyConfig {
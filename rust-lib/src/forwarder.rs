@@ -34,11 +34,12 @@ impl SmartForwarder {
     
     async fn start_simple_proxy(&self) -> Result<()> {
         use tokio::net::TcpListener;
-        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::io::AsyncWriteExt;
         
-        // 启动HTTP代理
-        let http_listener = TcpListener::bind(format!("0.0.0.0:{}", self.config.proxy.http_port)).await?;
-        info!("HTTP代理服务器监听端口: {}", self.config.proxy.http_port);
+        // 启动HTTP代理（使用配置中的监听地址，元组形式同时兼容IPv4和IPv6）
+        let host = self.config.server.host.as_str();
+        let http_listener = TcpListener::bind((host, self.config.proxy.http_port)).await?;
+        info!("HTTP代理服务器监听: {}", http_listener.local_addr()?);
         
         let is_running = self.is_running.clone();
         tokio::spawn(async move {
@@ -2,29 +2,17 @@ use jni::JNIEnv;
 use jni::objects::{JClass, JString};
 use jni::sys::{jint, jstring};
 use std::sync::{Arc, Mutex};
-use std::thread;
 use tokio::runtime::Runtime;
 
 use crate::config::Config;
 use crate::forwarder::SmartForwarder;
 
 // 全局状态管理
-static mut RUNTIME: Option<Arc<Mutex<Option<Runtime>>>> = None;
-static mut FORWARDER: Option<Arc<Mutex<Option<SmartForwarder>>>> = None;
+type SharedForwarder = Arc<Mutex<Option<SmartForwarder>>>;
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+static FORWARDER: Mutex<Option<SharedForwarder>> = Mutex::new(None);
 static mut IS_RUNNING: bool = false;
 
-// 初始化全局状态
-fn init_globals() {
-    unsafe {
-        if RUNTIME.is_none() {
-            RUNTIME = Some(Arc::new(Mutex::new(None)));
-        }
-        if FORWARDER.is_none() {
-            FORWARDER = Some(Arc::new(Mutex::new(None)));
-        }
-    }
-}
-
 // 启动代理服务
 #[no_mangle]
 pub extern "system" fn Java_com_smartforward_SmartForwardNative_startProxy(
@@ -32,8 +20,6 @@ pub extern "system" fn Java_com_smartforward_SmartForwardNative_startProxy(
     _class: JClass,
     config_json: JString,
 ) -> jint {
-    init_globals();
-    
     // 获取配置字符串
     let config_str: String = match env.get_string(&config_json) {
         Ok(s) => s.into(),
@@ -77,23 +63,21 @@ pub extern "system" fn Java_com_smartforward_SmartForwardNative_startProxy(
         let forwarder_arc = Arc::new(Mutex::new(Some(forwarder)));
         let forwarder_clone = forwarder_arc.clone();
         
-        thread::spawn(move || {
-            rt.block_on(async {
-                if let Ok(mut f) = forwarder_clone.lock() {
-                    if let Some(forwarder) = f.take() {
-                        if let Err(e) = forwarder.run().await {
-                            log::error!("转发器运行失败: {}", e);
-                        }
-                    }
+        // 在运行时的工作线程上运行，运行时随 stopProxy 释放时一并停止
+        rt.spawn(async move {
+            let forwarder = forwarder_clone.lock().ok().and_then(|mut f| f.take());
+            if let Some(forwarder) = forwarder {
+                if let Err(e) = forwarder.run().await {
+                    log::error!("转发器运行失败: {}", e);
                 }
-            });
+            }
         });
         
         // 保存状态
-        if let Ok(mut runtime) = RUNTIME.as_ref().unwrap().lock() {
+        if let Ok(mut runtime) = RUNTIME.lock() {
             *runtime = Some(rt);
         }
-        if let Ok(mut forwarder) = FORWARDER.as_ref().unwrap().lock() {
+        if let Ok(mut forwarder) = FORWARDER.lock() {
             *forwarder = Some(forwarder_arc);
         }
         
@@ -116,10 +100,10 @@ pub extern "system" fn Java_com_smartforward_SmartForwardNative_stopProxy(
         }
         
         // 清理状态
-        if let Ok(mut runtime) = RUNTIME.as_ref().unwrap().lock() {
+        if let Ok(mut runtime) = RUNTIME.lock() {
             *runtime = None;
         }
-        if let Ok(mut forwarder) = FORWARDER.as_ref().unwrap().lock() {
+        if let Ok(mut forwarder) = FORWARDER.lock() {
             *forwarder = None;
         }
         
@@ -132,7 +116,7 @@ pub extern "system" fn Java_com_smartforward_SmartForwardNative_stopProxy(
 // 获取服务状态
 #[no_mangle]
 pub extern "system" fn Java_com_smartforward_SmartForwardNative_getStatus(
-    mut env: JNIEnv,
+    env: JNIEnv,
     _class: JClass,
 ) -> jstring {
     unsafe {
@@ -152,7 +136,7 @@ pub extern "system" fn Java_com_smartforward_SmartForwardNative_getStatus(
 // 获取日志
 #[no_mangle]
 pub extern "system" fn Java_com_smartforward_SmartForwardNative_getLogs(
-    mut env: JNIEnv,
+    env: JNIEnv,
     _class: JClass,
 ) -> jstring {
     // 这里可以实现日志收集逻辑
@@ -225,3 +209,113 @@ pub extern "system" fn Java_com_smartforward_SmartForwardNative_initLogger(
     log::info!("日志系统初始化完成");
     0
 }
+
+// 通过控制接口向独立运行的 smart-forward 服务进程发送一行命令，返回应答原文。
+// 目标运维状态保存在该进程的 CommonManager 中；startProxy 启动的进程内转发器没有目标状态，
+// 也不监听控制接口，不受这些调用影响。
+// 调用会阻塞JNI线程，连接、读写都设置超时
+fn send_control_command(control_addr: &str, command: &str) -> std::io::Result<String> {
+    use std::io::{Read, Write};
+    use std::net::ToSocketAddrs;
+    use std::time::Duration;
+
+    let timeout = Duration::from_secs(5);
+    let mut reply = String::new();
+
+    #[cfg(unix)]
+    if let Some(path) = control_addr.strip_prefix("unix:") {
+        let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(format!("{}\n", command).as_bytes())?;
+        stream.read_to_string(&mut reply)?;
+        return Ok(reply);
+    }
+
+    let mut last_error = None;
+    for addr in control_addr.to_socket_addrs()? {
+        match std::net::TcpStream::connect_timeout(&addr, timeout) {
+            Ok(mut stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                stream.write_all(format!("{}\n", command).as_bytes())?;
+                stream.read_to_string(&mut reply)?;
+                return Ok(reply);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "无效的控制接口地址")
+    }))
+}
+
+// 切换目标运维状态：state 为 enabled / drain / disabled
+#[no_mangle]
+pub extern "system" fn Java_com_smartforward_SmartForwardNative_setTargetState(
+    mut env: JNIEnv,
+    _class: JClass,
+    control_addr: JString,
+    target: JString,
+    state: JString,
+) -> jint {
+    let (control_addr, target, state): (String, String, String) = match (
+        env.get_string(&control_addr),
+        env.get_string(&target),
+        env.get_string(&state),
+    ) {
+        (Ok(a), Ok(t), Ok(s)) => (a.into(), t.into(), s.into()),
+        _ => return -1,
+    };
+    
+    let action = match state.as_str() {
+        "enabled" => "enable",
+        "drain" => "drain",
+        "disabled" => "disable",
+        _ => {
+            log::error!("无效的目标状态: {}", state);
+            return -2;
+        }
+    };
+    
+    match send_control_command(&control_addr, &format!("{} {}", action, target)) {
+        Ok(reply) if reply.starts_with("OK") => {
+            log::info!("目标 {} 已设为 {}", target, state);
+            0
+        }
+        Ok(reply) => {
+            log::error!("切换目标状态失败: {}", reply.trim());
+            -4
+        }
+        Err(e) => {
+            log::error!("无法连接控制接口 {}: {}", control_addr, e);
+            -3
+        }
+    }
+}
+
+// 获取各目标的运维状态和健康状态，每行一个目标：地址 状态 健康状况
+#[no_mangle]
+pub extern "system" fn Java_com_smartforward_SmartForwardNative_getTargetStates(
+    mut env: JNIEnv,
+    _class: JClass,
+    control_addr: JString,
+) -> jstring {
+    let control_addr: String = match env.get_string(&control_addr) {
+        Ok(s) => s.into(),
+        Err(_) => return std::ptr::null_mut(),
+    };
+    
+    let states = match send_control_command(&control_addr, "status") {
+        Ok(reply) => reply.strip_prefix("OK\n").unwrap_or_default().to_string(),
+        Err(e) => {
+            log::error!("无法连接控制接口 {}: {}", control_addr, e);
+            String::new()
+        }
+    };
+    
+    match env.new_string(states) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
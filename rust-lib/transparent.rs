@@ -0,0 +1,1057 @@
+// 透明代理 - 接收 iptables REDIRECT/TPROXY 转来的流量，按原始目的地址转发（仅Linux/Android）
+use crate::config::{DynamicUpdateConfig, ForwardRule, TransparentRoute};
+use crate::forwarder::{bind_all, Forwarder, TCPForwarder};
+use crate::listener::{
+    bind_tcp, bind_tcp_transparent, bind_udp_transparent, format_bind_results, BindResult,
+    ListenAddr,
+};
+use crate::utils::{
+    get_standard_stats, resolve_first_target, ConnectionStats, ConnectionTimeouts, TargetAddr,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparentMode {
+    Redirect, // iptables REDIRECT：通过 SO_ORIGINAL_DST 取回原始目的地址
+    Tproxy,   // iptables TPROXY：套接字本地地址即原始目的地址
+}
+
+impl TransparentMode {
+    fn from_config(mode: &str) -> Self {
+        match mode {
+            "tproxy" => TransparentMode::Tproxy,
+            _ => TransparentMode::Redirect,
+        }
+    }
+}
+
+/// 网段，例如 `10.0.0.0/8`，单个IP视为主机网段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+pub fn parse_cidr(s: &str) -> Result<Cidr> {
+    let (ip, prefix) = match s.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (s, None),
+    };
+    let network: IpAddr = ip
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("无效的网段: {}", s))?;
+    let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|p| *p <= max_prefix)
+            .ok_or_else(|| anyhow::anyhow!("无效的网段前缀: {}", s))?,
+        None => max_prefix,
+    };
+    Ok(Cidr { network, prefix })
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, canonical_ip(*ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = prefix as usize / 8;
+    let rest_bits = prefix % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+// 双栈套接字收到的IPv4流量以 ::ffff:a.b.c.d 表示，统一还原为IPv4
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(addr.ip()), addr.port())
+}
+
+/// 目的地址是否就是监听器自身。未经重定向、直接连到监听端口的流量会转发给自己，形成环路；
+/// TPROXY 模式下原始目的地址总是本地地址，只能按监听地址和端口判断
+fn is_own_listen_addr(listen: SocketAddr, dst: SocketAddr) -> bool {
+    let listen = canonical_addr(listen);
+    if dst.port() != listen.port() {
+        return false;
+    }
+    if !listen.ip().is_unspecified() {
+        return listen.ip() == dst.ip();
+    }
+    // 监听在任意地址时，目的IP属于本机即为直接连接；非本机地址不带 IP_TRANSPARENT 无法绑定
+    dst.ip().is_loopback() || std::net::UdpSocket::bind((dst.ip(), 0)).is_ok()
+}
+
+struct Route {
+    dst: Option<Cidr>,
+    ports: Vec<u16>,
+    targets: Vec<String>,
+}
+
+impl Route {
+    fn matches(&self, dst: &SocketAddr) -> bool {
+        self.dst.is_none_or(|cidr| cidr.contains(&dst.ip()))
+            && (self.ports.is_empty() || self.ports.contains(&dst.port()))
+    }
+}
+
+/// 按配置顺序匹配的路由表，首个匹配的路由生效
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn new(routes: &[TransparentRoute]) -> Result<Self> {
+        let routes = routes
+            .iter()
+            .map(|route| {
+                Ok(Route {
+                    dst: route.dst.as_deref().map(parse_cidr).transpose()?,
+                    ports: route.ports.clone().unwrap_or_default(),
+                    targets: route.targets.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { routes })
+    }
+
+    pub fn lookup(&self, dst: &SocketAddr) -> Option<&[String]> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(dst))
+            .map(|route| route.targets.as_slice())
+    }
+
+    // 路由命中时按顺序取第一个可解析的目标，未命中时直接连接原始目的地址
+    async fn select(&self, dst: SocketAddr) -> Result<Vec<TargetAddr>> {
+        match self.lookup(&dst) {
+            Some(targets) => resolve_first_target(targets).await,
+            None => Ok(vec![TargetAddr::Inet(dst)]),
+        }
+    }
+}
+
+// ================================
+// 原始目的地址获取
+// ================================
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_int_option(
+    fd: std::os::fd::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 取回TCP连接被重定向前的目的地址
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn original_dst(stream: &TcpStream, mode: TransparentMode) -> io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    if mode == TransparentMode::Tproxy {
+        return Ok(canonical_addr(local));
+    }
+
+    let socket = socket2::SockRef::from(stream);
+    let addr = match local {
+        SocketAddr::V6(v6) if v6.ip().to_ipv4_mapped().is_none() => socket.original_dst_ipv6()?,
+        _ => socket.original_dst()?,
+    };
+    addr.as_socket()
+        .map(canonical_addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "原始目的地址格式无效"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn original_dst(_stream: &TcpStream, _mode: TransparentMode) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "当前平台不支持透明代理",
+    ))
+}
+
+/// 开启 IP_RECVORIGDSTADDR，使UDP数据报携带原始目的地址
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn enable_recv_orig_dst(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => set_int_option(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1),
+        SocketAddr::V6(_) => {
+            set_int_option(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?;
+            // 双栈套接字上的IPv4数据报仍通过 SOL_IP 报告
+            let _ = set_int_option(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn enable_recv_orig_dst(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "当前平台不支持透明代理",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sockaddr_to_std(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// 接收UDP数据报，同时返回来源地址和原始目的地址
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn recv_with_orig_dst(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    use std::os::fd::AsRawFd;
+
+    let mut source: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    // u64 数组保证控制消息缓冲区按 cmsghdr 对齐
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut source as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let source = sockaddr_to_std(&source)
+        .map(canonical_addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "来源地址格式无效"))?;
+
+    let mut orig_dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_ORIGDSTADDR)
+                || (header.cmsg_level == libc::SOL_IPV6
+                    && header.cmsg_type == libc::IPV6_ORIGDSTADDR)
+            {
+                let data = libc::CMSG_DATA(cmsg);
+                let data_len = header.cmsg_len as usize - (data as usize - cmsg as usize);
+                let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+                std::ptr::copy_nonoverlapping(
+                    data,
+                    &mut storage as *mut _ as *mut u8,
+                    data_len.min(std::mem::size_of::<libc::sockaddr_storage>()),
+                );
+                orig_dst = sockaddr_to_std(&storage).map(canonical_addr);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((len as usize, source, orig_dst))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn recv_with_orig_dst(
+    _socket: &UdpSocket,
+    _buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "当前平台不支持透明代理",
+    ))
+}
+
+// ================================
+// 透明代理转发器
+// ================================
+#[derive(Default)]
+struct TransparentStats {
+    routed: AtomicU64,         // 命中路由的连接/会话
+    direct: AtomicU64,         // 直连原始目的地址的连接/会话
+    no_orig_dst: AtomicU64,    // 无法取得原始目的地址
+    route_failures: AtomicU64, // 路由目标均无法解析
+}
+
+// UDP会话：回程套接字绑定在原始目的地址上，客户端看到的应答来源与请求目的一致
+struct UdpSession {
+    upstream: Arc<UdpSocket>,
+    created_at: Instant,
+    last_seen: Instant,
+    reply_task: JoinHandle<()>,
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.reply_task.abort();
+    }
+}
+
+type UdpSessions = Arc<RwLock<HashMap<(SocketAddr, SocketAddr), UdpSession>>>;
+// 会话建立期间收到的数据报，建立后按顺序补发
+type PendingDatagrams = Arc<Mutex<HashMap<(SocketAddr, SocketAddr), Vec<Vec<u8>>>>>;
+
+// 单个会话建立期间最多缓存的数据报数
+const MAX_PENDING_DATAGRAMS: usize = 64;
+
+pub struct TransparentForwarder {
+    listen_addrs: Vec<ListenAddr>,
+    name: String,
+    mode: TransparentMode,
+    protocols: Vec<String>,
+    buffer_size: usize,
+    routes: Arc<RouteTable>,
+    stats: Arc<RwLock<ConnectionStats>>,
+    transparent_stats: Arc<TransparentStats>,
+    running: Arc<RwLock<bool>>,
+    sessions: UdpSessions,
+    bind_results: Vec<BindResult>,
+    timeouts: ConnectionTimeouts,
+}
+
+impl TransparentForwarder {
+    pub fn new(listen_addrs: &[ListenAddr], name: &str, rule: &ForwardRule) -> Result<Self> {
+        let config = rule.get_transparent_config();
+        Ok(Self {
+            listen_addrs: listen_addrs.to_vec(),
+            name: name.to_string(),
+            mode: TransparentMode::from_config(config.get_mode()),
+            protocols: rule.get_protocols(),
+            buffer_size: rule.get_effective_buffer_size(8192),
+            routes: Arc::new(RouteTable::new(&config.get_routes())?),
+            stats: Arc::new(RwLock::new(ConnectionStats::default())),
+            transparent_stats: Arc::new(TransparentStats::default()),
+            running: Arc::new(RwLock::new(false)),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            bind_results: Vec::new(),
+            timeouts: ConnectionTimeouts::from_config(
+                &rule.get_dynamic_update_config(&DynamicUpdateConfig::default()),
+            ),
+        })
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: ConnectionTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    fn start_tcp(&mut self) -> Result<()> {
+        let mode = self.mode;
+        let (listeners, results) =
+            bind_all(
+                "透明TCP",
+                &self.name,
+                &self.listen_addrs,
+                |addr| match addr {
+                    ListenAddr::Socket {
+                        addr,
+                        device,
+                        v6_only,
+                    } => match mode {
+                        TransparentMode::Redirect => bind_tcp(addr, device.as_deref(), *v6_only),
+                        TransparentMode::Tproxy => {
+                            bind_tcp_transparent(addr, device.as_deref(), *v6_only)
+                        }
+                    },
+                    ListenAddr::Unix(_) => anyhow::bail!("透明代理不支持Unix套接字监听"),
+                },
+            )?;
+        self.bind_results.extend(results);
+
+        for listener in listeners {
+            tokio::spawn(Self::serve_tcp(
+                listener,
+                mode,
+                self.name.clone(),
+                self.buffer_size,
+                self.timeouts,
+                self.routes.clone(),
+                self.stats.clone(),
+                self.transparent_stats.clone(),
+                self.running.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn serve_tcp(
+        listener: TcpListener,
+        mode: TransparentMode,
+        name: String,
+        buffer_size: usize,
+        timeouts: ConnectionTimeouts,
+        routes: Arc<RouteTable>,
+        stats: Arc<RwLock<ConnectionStats>>,
+        transparent_stats: Arc<TransparentStats>,
+        running: Arc<RwLock<bool>>,
+    ) {
+        let listen_addr = listener.local_addr().ok();
+        while *running.read().await {
+            let (stream, client_addr) = match listener.accept().await {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("透明TCP监听器 {} 接受连接失败: {}", name, e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let _ = stream.set_nodelay(true);
+
+            let orig_dst = match original_dst(&stream, mode) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    transparent_stats
+                        .no_orig_dst
+                        .fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "透明代理 {} 无法获取 {} 的原始目的地址: {}",
+                        name, client_addr, e
+                    );
+                    continue;
+                }
+            };
+            if listen_addr.is_some_and(|listen| is_own_listen_addr(listen, orig_dst)) {
+                transparent_stats
+                    .no_orig_dst
+                    .fetch_add(1, Ordering::Relaxed);
+                warn!("透明代理 {} 拒绝直接连接: {}", name, client_addr);
+                continue;
+            }
+
+            let routes = routes.clone();
+            let stats = stats.clone();
+            let transparent_stats = transparent_stats.clone();
+            let name = name.clone();
+            tokio::spawn(async move {
+                let targets =
+                    match Self::select_targets(&routes, orig_dst, &transparent_stats).await {
+                        Ok(targets) => targets,
+                        Err(e) => {
+                            warn!("透明代理 {} 目的地址 {}: {}", name, orig_dst, e);
+                            return;
+                        }
+                    };
+                debug!(
+                    "透明代理 {}: {} -> {} ({:?})",
+                    name, client_addr, orig_dst, targets
+                );
+                let _ = TCPForwarder::handle_connection(
+                    Box::new(stream),
+                    &targets,
+                    buffer_size,
+                    timeouts,
+                    stats,
+                    &name,
+                )
+                .await;
+            });
+        }
+    }
+
+    async fn select_targets(
+        routes: &RouteTable,
+        orig_dst: SocketAddr,
+        transparent_stats: &TransparentStats,
+    ) -> Result<Vec<TargetAddr>> {
+        let counter = if routes.lookup(&orig_dst).is_some() {
+            &transparent_stats.routed
+        } else {
+            &transparent_stats.direct
+        };
+        match routes.select(orig_dst).await {
+            Ok(targets) => {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(targets)
+            }
+            Err(e) => {
+                transparent_stats
+                    .route_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    fn start_udp(&mut self) -> Result<()> {
+        let (sockets, results) =
+            bind_all(
+                "透明UDP",
+                &self.name,
+                &self.listen_addrs,
+                |addr| match addr {
+                    ListenAddr::Socket {
+                        addr,
+                        device,
+                        v6_only,
+                    } => {
+                        let socket = bind_udp_transparent(addr, device.as_deref(), *v6_only)?;
+                        enable_recv_orig_dst(&socket)?;
+                        Ok(socket)
+                    }
+                    ListenAddr::Unix(_) => anyhow::bail!("透明代理不支持Unix套接字监听"),
+                },
+            )?;
+        self.bind_results.extend(results);
+
+        for socket in sockets {
+            tokio::spawn(Self::serve_udp(
+                socket,
+                self.name.clone(),
+                self.buffer_size,
+                self.routes.clone(),
+                self.stats.clone(),
+                self.transparent_stats.clone(),
+                self.running.clone(),
+                self.sessions.clone(),
+            ));
+        }
+
+        // 会话清理任务，检查间隔不超过空闲超时的一半
+        let sessions = self.sessions.clone();
+        let running = self.running.clone();
+        let stats = self.stats.clone();
+        let timeouts = self.timeouts;
+        tokio::spawn(async move {
+            let period =
+                (timeouts.udp_idle / 2).clamp(Duration::from_secs(1), Duration::from_secs(30));
+            let mut interval = tokio::time::interval(period);
+            while *running.read().await {
+                interval.tick().await;
+                let mut sessions_guard = sessions.write().await;
+                let mut stats_guard = stats.write().await;
+                sessions_guard.retain(|_, session| {
+                    match timeouts
+                        .udp_expiry(session.last_seen.elapsed(), session.created_at.elapsed())
+                    {
+                        Some(expiry) => {
+                            stats_guard.record_expiry(expiry);
+                            false
+                        }
+                        None => true,
+                    }
+                });
+            }
+            sessions.write().await.clear();
+        });
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn serve_udp(
+        socket: UdpSocket,
+        name: String,
+        buffer_size: usize,
+        routes: Arc<RouteTable>,
+        stats: Arc<RwLock<ConnectionStats>>,
+        transparent_stats: Arc<TransparentStats>,
+        running: Arc<RwLock<bool>>,
+        sessions: UdpSessions,
+    ) {
+        let mut buffer = vec![0u8; buffer_size];
+        let listen_addr = socket.local_addr().ok();
+        let pending: PendingDatagrams = Arc::new(Mutex::new(HashMap::new()));
+
+        while *running.read().await {
+            let result = socket
+                .async_io(tokio::io::Interest::READABLE, || {
+                    recv_with_orig_dst(&socket, &mut buffer)
+                })
+                .await;
+            let (len, client_addr, orig_dst) = match result {
+                Ok((len, client_addr, Some(orig_dst))) => (len, client_addr, orig_dst),
+                Ok((_, client_addr, None)) => {
+                    transparent_stats
+                        .no_orig_dst
+                        .fetch_add(1, Ordering::Relaxed);
+                    debug!("透明代理 {} 数据报缺少原始目的地址: {}", name, client_addr);
+                    continue;
+                }
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if listen_addr.is_some_and(|listen| is_own_listen_addr(listen, orig_dst)) {
+                transparent_stats
+                    .no_orig_dst
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "透明代理 {} 丢弃直接发往监听端口的数据报: {}",
+                    name, client_addr
+                );
+                continue;
+            }
+            stats.write().await.add_bytes_received(len as u64);
+
+            // 会话建立中的数据报先缓存；建立任务先登记会话再取走缓存，因此先查缓存再查会话
+            let key = (client_addr, orig_dst);
+            if let Some(queue) = pending.lock().unwrap().get_mut(&key) {
+                if queue.len() < MAX_PENDING_DATAGRAMS {
+                    queue.push(buffer[..len].to_vec());
+                }
+                continue;
+            }
+            let existing = sessions.write().await.get_mut(&key).map(|session| {
+                session.last_seen = Instant::now();
+                session.upstream.clone()
+            });
+            let Some(upstream) = existing else {
+                // 选路和上游套接字的建立可能较慢，放到单独任务中，不阻塞其他客户端
+                pending
+                    .lock()
+                    .unwrap()
+                    .insert(key, vec![buffer[..len].to_vec()]);
+                tokio::spawn(Self::open_udp_session(
+                    key,
+                    name.clone(),
+                    routes.clone(),
+                    stats.clone(),
+                    transparent_stats.clone(),
+                    sessions.clone(),
+                    pending.clone(),
+                ));
+                continue;
+            };
+
+            if upstream.send(&buffer[..len]).await.is_ok() {
+                stats.write().await.add_bytes_sent(len as u64);
+            }
+        }
+    }
+
+    async fn open_udp_session(
+        key: (SocketAddr, SocketAddr),
+        name: String,
+        routes: Arc<RouteTable>,
+        stats: Arc<RwLock<ConnectionStats>>,
+        transparent_stats: Arc<TransparentStats>,
+        sessions: UdpSessions,
+        pending: PendingDatagrams,
+    ) {
+        let (client_addr, orig_dst) = key;
+        let session =
+            match Self::new_udp_session(client_addr, orig_dst, &routes, &stats, &transparent_stats)
+                .await
+            {
+                Ok(session) => session,
+                Err(e) => {
+                    pending.lock().unwrap().remove(&key);
+                    warn!(
+                        "透明代理 {} 创建UDP会话失败 {} -> {}: {}",
+                        name, client_addr, orig_dst, e
+                    );
+                    return;
+                }
+            };
+        let upstream = session.upstream.clone();
+        sessions.write().await.insert(key, session);
+
+        let datagrams = pending.lock().unwrap().remove(&key).unwrap_or_default();
+        for datagram in datagrams {
+            if upstream.send(&datagram).await.is_ok() {
+                stats.write().await.add_bytes_sent(datagram.len() as u64);
+            }
+        }
+    }
+
+    async fn new_udp_session(
+        client_addr: SocketAddr,
+        orig_dst: SocketAddr,
+        routes: &RouteTable,
+        stats: &Arc<RwLock<ConnectionStats>>,
+        transparent_stats: &TransparentStats,
+    ) -> Result<UdpSession> {
+        let targets = Self::select_targets(routes, orig_dst, transparent_stats).await?;
+        let target = targets
+            .iter()
+            .find_map(|addr| match addr {
+                TargetAddr::Inet(addr) => Some(*addr),
+                TargetAddr::Unix(_) => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("UDP不支持Unix套接字目标"))?;
+
+        let bind_addr: SocketAddr = if target.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let upstream = Arc::new(UdpSocket::bind(bind_addr).await?);
+        upstream.connect(target).await?;
+
+        // 以原始目的地址作为来源回复客户端
+        let reply = bind_udp_transparent(&orig_dst, None, false)?;
+        stats.write().await.increment_connections();
+
+        let upstream_reader = upstream.clone();
+        let stats = stats.clone();
+        let reply_task = tokio::spawn(async move {
+            let mut buffer = vec![0u8; 65535];
+            while let Ok(len) = upstream_reader.recv(&mut buffer).await {
+                if reply.send_to(&buffer[..len], client_addr).await.is_ok() {
+                    stats.write().await.add_bytes_sent(len as u64);
+                }
+            }
+        });
+
+        Ok(UdpSession {
+            upstream,
+            created_at: Instant::now(),
+            last_seen: Instant::now(),
+            reply_task,
+        })
+    }
+}
+
+#[async_trait]
+impl Forwarder for TransparentForwarder {
+    async fn start(&mut self) -> Result<()> {
+        *self.running.write().await = true;
+
+        for protocol in self.protocols.clone() {
+            match protocol.as_str() {
+                "tcp" => self.start_tcp()?,
+                "udp" => self.start_udp()?,
+                _ => {}
+            }
+        }
+
+        info!(
+            "透明代理 {} 启动: 模式 {:?}，路由 {} 条",
+            self.name,
+            self.mode,
+            self.routes.routes.len()
+        );
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        *self.running.write().await = false;
+        self.sessions.write().await.clear();
+    }
+
+    fn is_running(&self) -> bool {
+        *self.running.blocking_read()
+    }
+
+    fn get_stats(&self) -> HashMap<String, String> {
+        let stats = self.stats.blocking_read();
+        let mut result = get_standard_stats(&stats);
+        let transparent_stats = &self.transparent_stats;
+        for (key, value) in [
+            ("routed", &transparent_stats.routed),
+            ("direct", &transparent_stats.direct),
+            ("no_orig_dst", &transparent_stats.no_orig_dst),
+            ("route_failures", &transparent_stats.route_failures),
+        ] {
+            result.insert(key.to_string(), value.load(Ordering::Relaxed).to_string());
+        }
+        result.insert(
+            "udp_sessions".to_string(),
+            self.sessions.blocking_read().len().to_string(),
+        );
+        result.insert(
+            "listeners".to_string(),
+            format_bind_results(&self.bind_results),
+        );
+        result
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_table_matching() {
+        let routes = RouteTable::new(&[
+            TransparentRoute {
+                dst: Some("10.0.0.0/8".to_string()),
+                ports: Some(vec![443]),
+                targets: vec!["192.168.1.2:8443".to_string()],
+            },
+            TransparentRoute {
+                dst: Some("fd00::/64".to_string()),
+                ports: None,
+                targets: vec!["[fd00::2]:80".to_string()],
+            },
+        ])
+        .unwrap();
+
+        assert!(routes.lookup(&"10.1.2.3:443".parse().unwrap()).is_some());
+        assert!(routes.lookup(&"10.1.2.3:80".parse().unwrap()).is_none());
+        assert!(routes.lookup(&"11.0.0.1:443".parse().unwrap()).is_none());
+        assert!(routes.lookup(&"[fd00::1234]:22".parse().unwrap()).is_some());
+        // 双栈套接字上的IPv4映射地址按IPv4匹配
+        assert!(routes
+            .lookup(&"[::ffff:10.0.0.1]:443".parse().unwrap())
+            .is_some());
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn test_recv_with_orig_dst() {
+        // 未经TPROXY的数据报，原始目的地址即本地地址
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        enable_recv_orig_dst(&socket).unwrap();
+        let local = socket.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", local).await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (len, source, orig_dst) = socket
+            .async_io(tokio::io::Interest::READABLE, || {
+                recv_with_orig_dst(&socket, &mut buffer)
+            })
+            .await
+            .unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_eq!(source, client.local_addr().unwrap());
+        assert_eq!(orig_dst, Some(local));
+    }
+
+    // TPROXY 模式下直接连到监听端口的连接被拒绝，不会转发给自己
+    #[tokio::test]
+    async fn test_tproxy_rejects_direct_connection() {
+        for bind_addr in ["127.0.0.1:0", "0.0.0.0:0"] {
+            let listener = TcpListener::bind(bind_addr).await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let transparent_stats = Arc::new(TransparentStats::default());
+            tokio::spawn(TransparentForwarder::serve_tcp(
+                listener,
+                TransparentMode::Tproxy,
+                "test".to_string(),
+                1024,
+                ConnectionTimeouts::from_config(&DynamicUpdateConfig::default()),
+                Arc::new(RouteTable::new(&[]).unwrap()),
+                Arc::new(RwLock::new(ConnectionStats::default())),
+                transparent_stats.clone(),
+                Arc::new(RwLock::new(true)),
+            ));
+
+            let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let mut buffer = [0u8; 1];
+            let read = tokio::time::timeout(
+                Duration::from_secs(3),
+                tokio::io::AsyncReadExt::read(&mut client, &mut buffer),
+            )
+            .await
+            .expect("直接连接未被关闭");
+            assert!(matches!(read, Ok(0) | Err(_)));
+            assert_eq!(transparent_stats.no_orig_dst.load(Ordering::Relaxed), 1);
+            assert_eq!(transparent_stats.direct.load(Ordering::Relaxed), 0);
+        }
+
+        // 经TPROXY转来、端口相同的远端目的地址不受影响
+        let listen: SocketAddr = "0.0.0.0:12345".parse().unwrap();
+        assert!(is_own_listen_addr(
+            listen,
+            "127.0.0.1:12345".parse().unwrap()
+        ));
+        assert!(!is_own_listen_addr(
+            listen,
+            "127.0.0.1:443".parse().unwrap()
+        ));
+        assert!(!is_own_listen_addr(
+            listen,
+            "203.0.113.1:12345".parse().unwrap()
+        ));
+    }
+
+    // 需要root权限和 iptables，在新建的网络命名空间中配置规则，不影响宿主机：
+    // sudo cargo test -- --ignored test_transparent_in_netns
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    #[ignore]
+    fn test_transparent_in_netns() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // 网络命名空间只作用于当前线程，之后在本线程上运行单线程运行时
+        assert_eq!(
+            unsafe { libc::unshare(libc::CLONE_NEWNET) },
+            0,
+            "创建网络命名空间失败: {}",
+            io::Error::last_os_error()
+        );
+        for command in [
+            "ip link set lo up",
+            "ip route add 198.51.100.0/24 dev lo",
+            "ip rule add fwmark 1 lookup 100",
+            "ip route add local 0.0.0.0/0 dev lo table 100",
+            // 198.51.100.1:80 经 REDIRECT 到 7001
+            "iptables -t nat -A OUTPUT -p tcp -d 198.51.100.1 --dport 80 -j REDIRECT --to-ports 7001",
+            // 198.51.100.2 打标记后回环进入 PREROUTING，经 TPROXY 到 7002
+            "iptables -t mangle -A OUTPUT -d 198.51.100.2 -j MARK --set-mark 1",
+            "iptables -t mangle -A PREROUTING -p tcp -d 198.51.100.2 -j TPROXY --on-ip 127.0.0.1 --on-port 7002 --tproxy-mark 1",
+            "iptables -t mangle -A PREROUTING -p udp -d 198.51.100.2 -j TPROXY --on-ip 127.0.0.1 --on-port 7002 --tproxy-mark 1",
+        ] {
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .status()
+                .unwrap();
+            assert!(status.success(), "命令执行失败: {}", command);
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // TCP 目标回显数据，UDP 目标回复 pong
+            let echo = TcpListener::bind("127.0.0.1:9000").await.unwrap();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = echo.accept().await {
+                    tokio::spawn(async move {
+                        let (mut reader, mut writer) = stream.split();
+                        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                    });
+                }
+            });
+            let udp_echo = UdpSocket::bind("127.0.0.1:9001").await.unwrap();
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 64];
+                while let Ok((_, peer)) = udp_echo.recv_from(&mut buffer).await {
+                    let _ = udp_echo.send_to(b"pong", peer).await;
+                }
+            });
+
+            let start = |mode: &str, port: u16| {
+                let rule: ForwardRule = serde_yaml::from_str(&format!(
+                    r#"
+name: {mode}
+listen_port: {port}
+transparent:
+  mode: {mode}
+  routes:
+    - {{dst: "198.51.100.0/24", ports: [53], targets: ["127.0.0.1:9001"]}}
+    - {{dst: "198.51.100.0/24", targets: ["127.0.0.1:9000"]}}
+"#
+                ))
+                .unwrap();
+                let listen = ListenAddr::Socket {
+                    addr: SocketAddr::from(([127, 0, 0, 1], port)),
+                    device: None,
+                    v6_only: false,
+                };
+                TransparentForwarder::new(&[listen], mode, &rule).unwrap()
+            };
+            let mut redirect = start("redirect", 7001);
+            redirect.start().await.unwrap();
+            let mut tproxy = start("tproxy", 7002);
+            tproxy.start().await.unwrap();
+
+            for dst in ["198.51.100.1:80", "198.51.100.2:443"] {
+                let mut client = TcpStream::connect(dst).await.unwrap();
+                client.write_all(b"hello").await.unwrap();
+                let mut buffer = [0u8; 5];
+                tokio::time::timeout(Duration::from_secs(3), client.read_exact(&mut buffer))
+                    .await
+                    .unwrap_or_else(|_| panic!("经 {} 转发的应答超时", dst))
+                    .unwrap();
+                assert_eq!(&buffer, b"hello");
+            }
+            assert_eq!(redirect.transparent_stats.routed.load(Ordering::Relaxed), 1);
+            assert_eq!(tproxy.transparent_stats.routed.load(Ordering::Relaxed), 1);
+
+            // UDP 应答以原始目的地址为来源
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for _ in 0..2 {
+                client.send_to(b"ping", "198.51.100.2:53").await.unwrap();
+                let mut buffer = [0u8; 16];
+                let (len, source) =
+                    tokio::time::timeout(Duration::from_secs(3), client.recv_from(&mut buffer))
+                        .await
+                        .expect("透明UDP应答超时")
+                        .unwrap();
+                assert_eq!(&buffer[..len], b"pong");
+                assert_eq!(source, "198.51.100.2:53".parse().unwrap());
+            }
+            assert_eq!(tproxy.sessions.read().await.len(), 1);
+        });
+    }
+}
@@ -0,0 +1,1034 @@
+// 反向隧道 - NAT后的客户端主动连接公网服务端注册规则，服务端开放公网端口并经客户端回连转发到本地目标
+//
+// 协议：控制连接上逐行传输JSON消息。服务端每接受一个公网连接，就通过控制连接下发 new_conn，
+// 客户端随即新建一条到服务端的工作连接（首行为 work 消息），之后该连接直接承载原始数据。
+use crate::common::CommonManager;
+use crate::config::{ForwardRule, TunnelClientConfig, TunnelServerConfig};
+use crate::forwarder::{bind_all, Forwarder};
+use crate::listener::{bind_tcp, parse_listen_addrs, ListenAddr};
+use crate::utils::{connect_addrs, hmac_sign, hmac_verify, resolve_target, BoxedStream};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+
+// 单条控制消息最大长度
+const MAX_MESSAGE_LEN: u64 = 64 * 1024;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// 超过该时间未收到对端消息视为连接已断开
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+// 握手、工作连接建立的超时时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 登录签名允许的时钟偏差（秒）
+const AUTH_MAX_SKEW: i64 = 300;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TunnelMessage {
+    Login {
+        client_id: String,
+        timestamp: i64,
+        nonce: String,
+        auth: String,
+        rules: Vec<RemoteRule>,
+    },
+    LoginResp {
+        error: Option<String>,
+        results: Vec<RegisterResult>,
+    },
+    NewConn {
+        id: u64,
+        rule: String,
+    },
+    Work {
+        id: u64,
+        auth: String,
+    },
+    Ping,
+    Pong,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RemoteRule {
+    name: String,
+    remote_port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RegisterResult {
+    name: String,
+    error: Option<String>,
+}
+
+fn login_auth(token: &str, client_id: &str, timestamp: i64, nonce: &str) -> String {
+    hmac_sign(token, &login_message(client_id, timestamp, nonce))
+}
+
+fn login_message(client_id: &str, timestamp: i64, nonce: &str) -> String {
+    format!("login:{}:{}:{}", client_id, timestamp, nonce)
+}
+
+fn work_auth(token: &str, id: u64) -> String {
+    hmac_sign(token, &format!("work:{}", id))
+}
+
+async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<TunnelMessage>> {
+    let mut line = String::new();
+    let n = (&mut *reader)
+        .take(MAX_MESSAGE_LEN)
+        .read_line(&mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        anyhow::bail!("隧道消息过长或不完整");
+    }
+    Ok(Some(serde_json::from_str(line.trim_end())?))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &TunnelMessage,
+) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// 单个注册规则的统计
+#[derive(Default)]
+struct RegistrationStats {
+    connections: AtomicU64,
+    active: AtomicU64,
+    failures: AtomicU64,
+    bytes_in: AtomicU64,  // 公网/服务端 -> 本地目标
+    bytes_out: AtomicU64, // 本地目标 -> 公网/服务端
+}
+
+impl RegistrationStats {
+    fn write_to(&self, prefix: &str, result: &mut HashMap<String, String>) {
+        for (key, value) in [
+            ("connections", &self.connections),
+            ("active", &self.active),
+            ("failures", &self.failures),
+            ("bytes_in", &self.bytes_in),
+            ("bytes_out", &self.bytes_out),
+        ] {
+            result.insert(
+                format!("{}.{}", prefix, key),
+                value.load(Ordering::Relaxed).to_string(),
+            );
+        }
+    }
+}
+
+// 双向转发一条连接，a 为入站方向（公网侧）
+async fn relay<A, B>(mut a: A, mut b: B, stats: &RegistrationStats)
+where
+    A: tokio::io::AsyncRead + AsyncWrite + Unpin,
+    B: tokio::io::AsyncRead + AsyncWrite + Unpin,
+{
+    stats.connections.fetch_add(1, Ordering::Relaxed);
+    stats.active.fetch_add(1, Ordering::Relaxed);
+    if let Ok((a_to_b, b_to_a)) = tokio::io::copy_bidirectional(&mut a, &mut b).await {
+        stats.bytes_in.fetch_add(a_to_b, Ordering::Relaxed);
+        stats.bytes_out.fetch_add(b_to_a, Ordering::Relaxed);
+    }
+    stats.active.fetch_sub(1, Ordering::Relaxed);
+}
+
+// ================================
+// 隧道服务端（公网实例）
+// ================================
+struct Registration {
+    session: u64,
+    client_id: String,
+    client_addr: SocketAddr,
+    remote_port: u16,
+    registered_at: chrono::DateTime<chrono::Local>,
+    stats: Arc<RegistrationStats>,
+    accept_tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // 客户端断开后立即关闭公网端口，已建立的连接继续完成
+        for task in &self.accept_tasks {
+            task.abort();
+        }
+    }
+}
+
+type PendingWork = Mutex<HashMap<u64, oneshot::Sender<BufReader<TcpStream>>>>;
+
+struct ServerState {
+    token: String,
+    public_addr: String,
+    allow_ports: Option<Vec<u16>>,
+    registrations: RwLock<HashMap<String, Registration>>,
+    pending: PendingWork,
+    next_id: AtomicU64,
+    nonces: Mutex<HashMap<String, Instant>>, // 已使用的登录随机数
+}
+
+impl ServerState {
+    // 防重放：登录随机数在时间窗口内只能使用一次
+    fn accept_nonce(&self, timestamp: i64, nonce: &str) -> bool {
+        if nonce.is_empty() {
+            return false;
+        }
+        let now = Instant::now();
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, until| *until > now);
+        let key = format!("{}:{}", timestamp, nonce);
+        if nonces.contains_key(&key) {
+            return false;
+        }
+        nonces.insert(key, now + Duration::from_secs(AUTH_MAX_SKEW as u64 * 2));
+        true
+    }
+}
+
+pub struct TunnelServer {
+    listen: String,
+    state: Arc<ServerState>,
+    running: Arc<RwLock<bool>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TunnelServer {
+    pub fn new(config: &TunnelServerConfig, public_addr: &str) -> Self {
+        Self {
+            listen: config.listen.clone(),
+            state: Arc::new(ServerState {
+                token: config.token.clone(),
+                public_addr: public_addr.to_string(),
+                allow_ports: config.allow_ports.clone(),
+                registrations: RwLock::new(HashMap::new()),
+                pending: Mutex::new(HashMap::new()),
+                // 连接编号以启动时间为起点，避免重启后与旧的工作连接混淆
+                next_id: AtomicU64::new(chrono::Utc::now().timestamp_micros() as u64),
+                nonces: Mutex::new(HashMap::new()),
+            }),
+            running: Arc::new(RwLock::new(false)),
+            task: None,
+        }
+    }
+
+    async fn serve(listener: TcpListener, state: Arc<ServerState>, running: Arc<RwLock<bool>>) {
+        while *running.read().await {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let _ = stream.set_nodelay(true);
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = Self::handle_incoming(stream, peer, state).await {
+                            warn!("隧道连接 {} 处理失败: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("隧道服务端接受连接失败: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    // 新连接的首条消息决定它是控制连接还是工作连接
+    async fn handle_incoming(
+        stream: TcpStream,
+        peer: SocketAddr,
+        state: Arc<ServerState>,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let first = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_message(&mut reader)).await {
+            Ok(result) => result?,
+            Err(_) => anyhow::bail!("握手超时"),
+        };
+
+        match first {
+            Some(TunnelMessage::Work { id, auth }) => {
+                if !hmac_verify(&state.token, &format!("work:{}", id), &auth) {
+                    anyhow::bail!("工作连接认证失败");
+                }
+                let sender = state.pending.lock().unwrap().remove(&id);
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send(reader);
+                        Ok(())
+                    }
+                    None => anyhow::bail!("工作连接 {} 已过期", id),
+                }
+            }
+            Some(TunnelMessage::Login {
+                client_id,
+                timestamp,
+                nonce,
+                auth,
+                rules,
+            }) => {
+                let skew = (chrono::Utc::now().timestamp() - timestamp).abs();
+                if skew > AUTH_MAX_SKEW
+                    || !hmac_verify(
+                        &state.token,
+                        &login_message(&client_id, timestamp, &nonce),
+                        &auth,
+                    )
+                    || !state.accept_nonce(timestamp, &nonce)
+                {
+                    let response = TunnelMessage::LoginResp {
+                        error: Some("认证失败".to_string()),
+                        results: Vec::new(),
+                    };
+                    let _ = write_message(reader.get_mut(), &response).await;
+                    anyhow::bail!("客户端 {} 认证失败", client_id);
+                }
+                Self::run_control_session(reader, peer, client_id, rules, state).await
+            }
+            Some(_) => anyhow::bail!("无效的首条消息"),
+            None => Ok(()),
+        }
+    }
+
+    async fn run_control_session(
+        reader: BufReader<TcpStream>,
+        peer: SocketAddr,
+        client_id: String,
+        rules: Vec<RemoteRule>,
+        state: Arc<ServerState>,
+    ) -> Result<()> {
+        let session = state.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = mpsc::channel::<TunnelMessage>(64);
+
+        let mut results = Vec::new();
+        for rule in &rules {
+            let error = Self::register(&state, session, &client_id, peer, rule, &tx)
+                .await
+                .err()
+                .map(|e| e.to_string());
+            results.push(RegisterResult {
+                name: rule.name.clone(),
+                error,
+            });
+        }
+        let registered = results.iter().filter(|r| r.error.is_none()).count();
+        info!(
+            "隧道客户端 {} ({}) 登录，注册成功 {}/{} 条规则",
+            client_id,
+            peer,
+            registered,
+            rules.len()
+        );
+
+        let (read_half, mut write_half) = tokio::io::split(reader);
+        let mut reader = BufReader::new(read_half);
+        write_message(
+            &mut write_half,
+            &TunnelMessage::LoginResp {
+                error: None,
+                results,
+            },
+        )
+        .await?;
+
+        let result: Result<()> = async {
+            loop {
+                tokio::select! {
+                    message = tokio::time::timeout(HEARTBEAT_TIMEOUT, read_message(&mut reader)) => {
+                        match message {
+                            Ok(Ok(Some(TunnelMessage::Ping))) => {
+                                write_message(&mut write_half, &TunnelMessage::Pong).await?;
+                            }
+                            Ok(Ok(Some(_))) => {}
+                            Ok(Ok(None)) => return Ok(()),
+                            Ok(Err(e)) => return Err(e),
+                            Err(_) => anyhow::bail!("心跳超时"),
+                        }
+                    }
+                    Some(message) = rx.recv() => {
+                        write_message(&mut write_half, &message).await?;
+                    }
+                }
+            }
+        }
+        .await;
+
+        // 清理该会话注册的全部规则
+        state
+            .registrations
+            .write()
+            .await
+            .retain(|_, registration| registration.session != session);
+        info!("隧道客户端 {} ({}) 断开", client_id, peer);
+        result
+    }
+
+    async fn register(
+        state: &Arc<ServerState>,
+        session: u64,
+        client_id: &str,
+        peer: SocketAddr,
+        rule: &RemoteRule,
+        tx: &mpsc::Sender<TunnelMessage>,
+    ) -> Result<()> {
+        if let Some(allow_ports) = &state.allow_ports {
+            if !allow_ports.contains(&rule.remote_port) {
+                anyhow::bail!("端口 {} 不在允许列表中", rule.remote_port);
+            }
+        }
+
+        let mut registrations = state.registrations.write().await;
+        if let Some(existing) = registrations.get(&rule.name) {
+            // 同一客户端重连时旧会话可能尚未超时，直接接管其注册
+            if existing.client_id != client_id {
+                anyhow::bail!("规则名已被客户端 {} 注册", existing.client_id);
+            }
+            registrations.remove(&rule.name);
+        }
+
+        let listen_addrs =
+            parse_listen_addrs(std::slice::from_ref(&state.public_addr), rule.remote_port)?;
+        let (listeners, _) = bind_all("隧道TCP", &rule.name, &listen_addrs, |addr| match addr {
+            ListenAddr::Socket {
+                addr,
+                device,
+                v6_only,
+            } => bind_tcp(addr, device.as_deref(), *v6_only),
+            ListenAddr::Unix(_) => anyhow::bail!("隧道不支持Unix套接字监听"),
+        })?;
+
+        let stats = Arc::new(RegistrationStats::default());
+        let accept_tasks = listeners
+            .into_iter()
+            .map(|listener| {
+                tokio::spawn(Self::serve_public(
+                    listener,
+                    rule.name.clone(),
+                    state.clone(),
+                    tx.clone(),
+                    stats.clone(),
+                ))
+            })
+            .collect();
+
+        registrations.insert(
+            rule.name.clone(),
+            Registration {
+                session,
+                client_id: client_id.to_string(),
+                client_addr: peer,
+                remote_port: rule.remote_port,
+                registered_at: chrono::Local::now(),
+                stats,
+                accept_tasks,
+            },
+        );
+        Ok(())
+    }
+
+    async fn serve_public(
+        listener: TcpListener,
+        rule: String,
+        state: Arc<ServerState>,
+        tx: mpsc::Sender<TunnelMessage>,
+        stats: Arc<RegistrationStats>,
+    ) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(result) => result,
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let _ = stream.set_nodelay(true);
+
+            let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+            let (work_tx, work_rx) = oneshot::channel();
+            state.pending.lock().unwrap().insert(id, work_tx);
+
+            if tx
+                .send(TunnelMessage::NewConn {
+                    id,
+                    rule: rule.clone(),
+                })
+                .await
+                .is_err()
+            {
+                state.pending.lock().unwrap().remove(&id);
+                break;
+            }
+
+            let state = state.clone();
+            let stats = stats.clone();
+            let rule = rule.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, work_rx).await {
+                    Ok(Ok(work)) => relay(stream, work, &stats).await,
+                    _ => {
+                        state.pending.lock().unwrap().remove(&id);
+                        stats.failures.fetch_add(1, Ordering::Relaxed);
+                        debug!("隧道规则 {} 等待客户端工作连接超时: {}", rule, peer);
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Forwarder for TunnelServer {
+    async fn start(&mut self) -> Result<()> {
+        let addr: SocketAddr = self.listen.parse()?;
+        let listener = bind_tcp(&addr, None, false)?;
+        *self.running.write().await = true;
+        self.task = Some(tokio::spawn(Self::serve(
+            listener,
+            self.state.clone(),
+            self.running.clone(),
+        )));
+        info!("隧道服务端启动: {}", self.listen);
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        *self.running.write().await = false;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.state.registrations.write().await.clear();
+    }
+
+    fn is_running(&self) -> bool {
+        *self.running.blocking_read()
+    }
+
+    fn get_stats(&self) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        result.insert("listen".to_string(), self.listen.clone());
+        let registrations = self.state.registrations.blocking_read();
+        result.insert("registrations".to_string(), registrations.len().to_string());
+        for (name, registration) in registrations.iter() {
+            let prefix = format!("reg.{}", name);
+            result.insert(
+                format!("{}.client", prefix),
+                format!("{}@{}", registration.client_id, registration.client_addr),
+            );
+            result.insert(
+                format!("{}.remote_port", prefix),
+                registration.remote_port.to_string(),
+            );
+            result.insert(
+                format!("{}.registered_at", prefix),
+                registration
+                    .registered_at
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+            );
+            registration.stats.write_to(&prefix, &mut result);
+        }
+        result
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+// ================================
+// 隧道客户端（NAT后实例）
+// ================================
+#[derive(Default)]
+struct ClientState {
+    connected: bool,
+    reconnects: u64,
+    last_error: Option<String>,
+}
+
+pub struct TunnelClient {
+    config: TunnelClientConfig,
+    rules: Vec<ForwardRule>,
+    common_manager: CommonManager,
+    stats: Arc<HashMap<String, Arc<RegistrationStats>>>,
+    state: Arc<RwLock<ClientState>>,
+    running: Arc<RwLock<bool>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TunnelClient {
+    pub fn new(
+        config: &TunnelClientConfig,
+        rules: &[ForwardRule],
+        common_manager: CommonManager,
+    ) -> Self {
+        let stats = rules
+            .iter()
+            .map(|rule| (rule.name.clone(), Arc::new(RegistrationStats::default())))
+            .collect();
+        Self {
+            config: config.clone(),
+            rules: rules.to_vec(),
+            common_manager,
+            stats: Arc::new(stats),
+            state: Arc::new(RwLock::new(ClientState::default())),
+            running: Arc::new(RwLock::new(false)),
+            task: None,
+        }
+    }
+
+    async fn dial(server_addr: &str) -> Result<BoxedStream> {
+        let addrs = resolve_target(server_addr).await?;
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, connect_addrs(&addrs)).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("连接隧道服务端超时"),
+        }
+    }
+
+    // 断线后按指数退避重连，登录成功后退避时间复位
+    async fn run(
+        config: TunnelClientConfig,
+        rules: Vec<RemoteRule>,
+        common_manager: CommonManager,
+        stats: Arc<HashMap<String, Arc<RegistrationStats>>>,
+        state: Arc<RwLock<ClientState>>,
+        running: Arc<RwLock<bool>>,
+    ) {
+        let max_backoff = Duration::from_secs(config.get_max_backoff().max(1));
+        let mut backoff = Duration::from_secs(1);
+
+        while *running.read().await {
+            let result = Self::run_session(&config, &rules, &common_manager, &stats, &state).await;
+            {
+                let mut state = state.write().await;
+                if state.connected {
+                    backoff = Duration::from_secs(1);
+                }
+                state.connected = false;
+                state.reconnects += 1;
+                if let Err(e) = &result {
+                    state.last_error = Some(e.to_string());
+                }
+            }
+            if !*running.read().await {
+                break;
+            }
+
+            match result {
+                Ok(()) => warn!("隧道会话断开，{}秒后重连", backoff.as_secs()),
+                Err(e) => warn!("隧道连接失败: {}，{}秒后重连", e, backoff.as_secs()),
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    async fn run_session(
+        config: &TunnelClientConfig,
+        rules: &[RemoteRule],
+        common_manager: &CommonManager,
+        stats: &Arc<HashMap<String, Arc<RegistrationStats>>>,
+        state: &Arc<RwLock<ClientState>>,
+    ) -> Result<()> {
+        let stream = Self::dial(&config.server_addr).await?;
+        let (read_half, mut write_half) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+
+        let client_id = config.get_client_id();
+        let timestamp = chrono::Utc::now().timestamp();
+        let nonce = hex::encode(rand::random::<[u8; 16]>());
+        write_message(
+            &mut write_half,
+            &TunnelMessage::Login {
+                auth: login_auth(&config.token, &client_id, timestamp, &nonce),
+                client_id,
+                timestamp,
+                nonce,
+                rules: rules.to_vec(),
+            },
+        )
+        .await?;
+
+        let response =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_message(&mut reader)).await {
+                Ok(result) => result?,
+                Err(_) => anyhow::bail!("等待登录响应超时"),
+            };
+        match response {
+            Some(TunnelMessage::LoginResp {
+                error: None,
+                results,
+            }) => {
+                for result in &results {
+                    match &result.error {
+                        None => info!("隧道规则 {} 注册成功", result.name),
+                        Some(e) => error!("隧道规则 {} 注册失败: {}", result.name, e),
+                    }
+                }
+            }
+            Some(TunnelMessage::LoginResp { error: Some(e), .. }) => {
+                anyhow::bail!("登录被拒绝: {}", e)
+            }
+            _ => anyhow::bail!("无效的登录响应"),
+        }
+        {
+            let mut state = state.write().await;
+            state.connected = true;
+            state.last_error = None;
+        }
+        info!("隧道已连接: {}", config.server_addr);
+
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.tick().await;
+        loop {
+            tokio::select! {
+                message = tokio::time::timeout(HEARTBEAT_TIMEOUT, read_message(&mut reader)) => {
+                    match message {
+                        Ok(Ok(Some(TunnelMessage::NewConn { id, rule }))) => {
+                            let Some(rule_stats) = stats.get(&rule).cloned() else {
+                                continue;
+                            };
+                            let server_addr = config.server_addr.clone();
+                            let token = config.token.clone();
+                            let common_manager = common_manager.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = Self::handle_new_conn(
+                                    &server_addr,
+                                    &token,
+                                    id,
+                                    &rule,
+                                    &common_manager,
+                                    &rule_stats,
+                                )
+                                .await
+                                {
+                                    rule_stats.failures.fetch_add(1, Ordering::Relaxed);
+                                    debug!("隧道规则 {} 转发失败: {}", rule, e);
+                                }
+                            });
+                        }
+                        Ok(Ok(Some(_))) => {}
+                        Ok(Ok(None)) => return Ok(()),
+                        Ok(Err(e)) => return Err(e),
+                        Err(_) => anyhow::bail!("心跳超时"),
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    write_message(&mut write_half, &TunnelMessage::Ping).await?;
+                }
+            }
+        }
+    }
+
+    async fn handle_new_conn(
+        server_addr: &str,
+        token: &str,
+        id: u64,
+        rule: &str,
+        common_manager: &CommonManager,
+        stats: &RegistrationStats,
+    ) -> Result<()> {
+        // 先建立工作连接：本地目标不可用时直接关闭，服务端的公网连接随之结束
+        let mut work = Self::dial(server_addr).await?;
+        write_message(
+            &mut work,
+            &TunnelMessage::Work {
+                id,
+                auth: work_auth(token, id),
+            },
+        )
+        .await?;
+
+        // 本地目标同样按规则的负载均衡策略选择，selection 在转发结束前保持
+        let selector = common_manager.selector(rule);
+        let selection = selector.select(None, None).await?;
+        let local =
+            match tokio::time::timeout(Duration::from_secs(5), connect_addrs(&selection.addrs))
+                .await
+            {
+                Ok(Ok(local)) => local,
+                Ok(Err(e)) => {
+                    selector.report(&selection.target, false);
+                    return Err(e);
+                }
+                Err(_) => {
+                    selector.report(&selection.target, false);
+                    anyhow::bail!("连接本地目标超时");
+                }
+            };
+        selector.report(&selection.target, true);
+        relay(work, local, stats).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Forwarder for TunnelClient {
+    async fn start(&mut self) -> Result<()> {
+        *self.running.write().await = true;
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| RemoteRule {
+                name: rule.name.clone(),
+                remote_port: rule.listen_port,
+            })
+            .collect();
+        self.task = Some(tokio::spawn(Self::run(
+            self.config.clone(),
+            rules,
+            self.common_manager.clone(),
+            self.stats.clone(),
+            self.state.clone(),
+            self.running.clone(),
+        )));
+        info!(
+            "隧道客户端启动: {} -> {} 条规则",
+            self.config.server_addr,
+            self.rules.len()
+        );
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        *self.running.write().await = false;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        *self.running.blocking_read()
+    }
+
+    fn get_stats(&self) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        let state = self.state.blocking_read();
+        result.insert("server_addr".to_string(), self.config.server_addr.clone());
+        result.insert("connected".to_string(), state.connected.to_string());
+        result.insert("reconnects".to_string(), state.reconnects.to_string());
+        if let Some(e) = &state.last_error {
+            result.insert("last_error".to_string(), e.clone());
+        }
+        for (name, stats) in self.stats.iter() {
+            stats.write_to(&format!("reg.{}", name), &mut result);
+        }
+        result
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_message_roundtrip_and_auth() {
+        let message = TunnelMessage::NewConn {
+            id: 42,
+            rule: "RDP".to_string(),
+        };
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &message).await.unwrap();
+        let mut reader = BufReader::new(buffer.as_slice());
+        assert!(matches!(
+            read_message(&mut reader).await.unwrap(),
+            Some(TunnelMessage::NewConn { id: 42, .. })
+        ));
+        assert!(read_message(&mut reader).await.unwrap().is_none());
+
+        let auth = login_auth("secret", "phone", 1000, "abcd");
+        assert!(hmac_verify("secret", "login:phone:1000:abcd", &auth));
+        assert!(!hmac_verify("other", "login:phone:1000:abcd", &auth));
+        assert!(!hmac_verify("secret", "login:phone:1000:abce", &auth));
+    }
+
+    fn start_server(listener: TcpListener) -> Arc<ServerState> {
+        let config = TunnelServerConfig {
+            listen: listener.local_addr().unwrap().to_string(),
+            token: "secret".to_string(),
+            allow_ports: None,
+        };
+        let state = TunnelServer::new(&config, "127.0.0.1").state;
+        tokio::spawn(TunnelServer::serve(
+            listener,
+            state.clone(),
+            Arc::new(RwLock::new(true)),
+        ));
+        state
+    }
+
+    async fn login(addr: SocketAddr, message: &TunnelMessage) -> TunnelMessage {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        write_message(stream.get_mut(), message).await.unwrap();
+        read_message(&mut stream).await.unwrap().unwrap()
+    }
+
+    // 同一登录消息重放时被拒绝
+    #[tokio::test]
+    async fn test_login_replay_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        start_server(listener);
+
+        let timestamp = chrono::Utc::now().timestamp();
+        let message = TunnelMessage::Login {
+            client_id: "phone".to_string(),
+            timestamp,
+            nonce: "0123abcd".to_string(),
+            auth: login_auth("secret", "phone", timestamp, "0123abcd"),
+            rules: Vec::new(),
+        };
+        assert!(matches!(
+            login(addr, &message).await,
+            TunnelMessage::LoginResp { error: None, .. }
+        ));
+        assert!(matches!(
+            login(addr, &message).await,
+            TunnelMessage::LoginResp { error: Some(_), .. }
+        ));
+    }
+
+    async fn wait_for<F: Fn() -> bool>(what: &str, condition: F) {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(tokio::time::Instant::now() < deadline, "等待{}超时", what);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // 服务端和客户端在回环地址上注册规则、经公网端口转发，控制连接断开后客户端重连
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_tunnel_end_to_end() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let server_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_listener.local_addr().unwrap();
+        let server = start_server(server_listener);
+
+        // 客户端经一个可切断的中继连接服务端，用来模拟控制连接断开
+        let relay_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay_listener.local_addr().unwrap();
+        let relays: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::new(Mutex::new(Vec::new()));
+        let relay_tasks = relays.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut inbound, _) = relay_listener.accept().await.unwrap();
+                let task = tokio::spawn(async move {
+                    let mut outbound = TcpStream::connect(server_addr).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+                relay_tasks.lock().unwrap().push(task);
+            }
+        });
+
+        let public_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: crate::config::Config = serde_yaml::from_str(&format!(
+            "logging: {{level: info, format: text}}\nnetwork: {{listen_addr: \"127.0.0.1\"}}\n\
+             rules:\n  - name: app\n    listen_port: {}\n    reverse: true\n    targets: [\"{}\"]\n",
+            public_port, echo_addr
+        ))
+        .unwrap();
+        let common_manager = CommonManager::new(config.clone());
+        common_manager.initialize().await.unwrap();
+        let client_config = TunnelClientConfig {
+            server_addr: relay_addr.to_string(),
+            token: "secret".to_string(),
+            client_id: Some("phone".to_string()),
+            max_backoff: Some(1),
+        };
+        let mut client = TunnelClient::new(&client_config, &config.rules, common_manager);
+        client.start().await.unwrap();
+
+        let registered = |state: &Arc<ServerState>| {
+            state
+                .registrations
+                .try_read()
+                .is_ok_and(|registrations| registrations.contains_key("app"))
+        };
+        wait_for("规则注册", || registered(&server)).await;
+
+        let mut public = TcpStream::connect(("127.0.0.1", public_port))
+            .await
+            .unwrap();
+        public.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), public.read_exact(&mut buf))
+            .await
+            .expect("隧道转发应答超时")
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        public.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        public.read_to_end(&mut rest).await.unwrap();
+
+        let stats = server.registrations.read().await["app"].stats.clone();
+        wait_for("注册统计", || stats.active.load(Ordering::Relaxed) == 0).await;
+        assert_eq!(stats.connections.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.bytes_in.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.bytes_out.load(Ordering::Relaxed), 5);
+        let client_stats = client.stats["app"].clone();
+        wait_for("客户端统计", || {
+            client_stats.active.load(Ordering::Relaxed) == 0
+        })
+        .await;
+        assert_eq!(client_stats.connections.load(Ordering::Relaxed), 1);
+        assert_eq!(client_stats.bytes_in.load(Ordering::Relaxed), 5);
+
+        // 第一条中继连接是控制连接，切断后服务端注销规则，客户端重连后重新注册
+        relays.lock().unwrap()[0].abort();
+        wait_for("规则注销", || !registered(&server)).await;
+        wait_for("客户端重连", || registered(&server)).await;
+        assert!(client.state.read().await.reconnects >= 1);
+
+        let mut public = TcpStream::connect(("127.0.0.1", public_port))
+            .await
+            .unwrap();
+        public.write_all(b"again").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), public.read_exact(&mut buf))
+            .await
+            .expect("重连后隧道转发应答超时")
+            .unwrap();
+        assert_eq!(&buf, b"again");
+        client.stop().await;
+    }
+}
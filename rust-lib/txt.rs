@@ -0,0 +1,258 @@
+// TXT目标记录 - 纯域名目标通过TXT记录发布实际转发地址
+//
+// 旧格式：每条记录一个 IP:PORT。新格式以 v=sf1 开头，字段以分号分隔：
+//   v=sf1; tcp=1.2.3.4:443,w=2; udp=1.2.3.4:3389; addr=5.6.7.8:443; exp=1767225600; sig=...
+// tcp/udp 端点只用于对应协议，addr 两种协议均可；w 为权重（默认1）；exp 为过期时间（Unix秒）。
+// sig 必须是最后一个字段，签名内容为 "域名;" 加上 sig 前的记录原文（去掉首尾空白），十六进制编码：
+//   hmac:    HMAC-SHA256(密钥, 内容)
+//   ed25519: Ed25519 私钥对内容的签名
+// 配置了 txt_key 时只接受签名有效且未过期的 v=sf1 记录，伪造或被劫持的TXT应答无法改写转发目标。
+use crate::utils::hmac_verify;
+use anyhow::Result;
+use log::debug;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::net::SocketAddr;
+
+const VERSION: &str = "v=sf1";
+
+// TXT记录的签名校验密钥
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxtKey {
+    Hmac(String),     // 共享密钥
+    Ed25519(Vec<u8>), // 32字节公钥
+}
+
+impl TxtKey {
+    // hmac:<密钥> 或 ed25519:<十六进制公钥>
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.split_once(':') {
+            Some(("hmac", secret)) if !secret.is_empty() => Ok(TxtKey::Hmac(secret.to_string())),
+            Some(("ed25519", public_key)) => {
+                let key = hex::decode(public_key.trim())
+                    .map_err(|e| anyhow::anyhow!("无效的Ed25519公钥: {}", e))?;
+                if key.len() != 32 {
+                    anyhow::bail!("Ed25519公钥应为32字节，实际为{}字节", key.len());
+                }
+                Ok(TxtKey::Ed25519(key))
+            }
+            _ => anyhow::bail!("txt_key 应为 hmac:<密钥> 或 ed25519:<十六进制公钥>"),
+        }
+    }
+
+    fn verify(&self, message: &str, signature: &str) -> bool {
+        match self {
+            TxtKey::Hmac(secret) => hmac_verify(secret, message, signature),
+            TxtKey::Ed25519(public_key) => hex::decode(signature).is_ok_and(|signature| {
+                UnparsedPublicKey::new(&ED25519, public_key)
+                    .verify(message.as_bytes(), &signature)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+// TXT记录发布的单个端点
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxtEndpoint {
+    pub addr: SocketAddr,
+    pub weight: Option<u16>,      // 旧格式没有权重，沿用目标配置的权重
+    pub protocol: Option<String>, // tcp / udp，None 表示两种协议均可
+    pub versioned: bool,          // 来自 v=sf1 记录
+}
+
+// 解析域名的全部TXT记录，跳过格式错误、签名无效或已过期的记录；
+// 同一地址出现多次时只保留第一次，协议提示不同时两种协议均可
+pub fn parse_records(
+    hostname: &str,
+    records: &[String],
+    key: Option<&TxtKey>,
+    now: u64,
+) -> Result<Vec<TxtEndpoint>> {
+    let hostname = hostname.trim_end_matches('.').to_lowercase();
+    let mut endpoints: Vec<TxtEndpoint> = Vec::new();
+    let mut last_error = None;
+    for record in records {
+        let record = record.trim_matches('"').trim();
+        match parse_record(&hostname, record, key, now) {
+            Ok(parsed) => {
+                for endpoint in parsed {
+                    match endpoints.iter_mut().find(|e| e.addr == endpoint.addr) {
+                        Some(existing) if existing.protocol != endpoint.protocol => {
+                            existing.protocol = None
+                        }
+                        Some(_) => {}
+                        None => endpoints.push(endpoint),
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("忽略 {} 的TXT记录 \"{}\": {}", hostname, record, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if endpoints.is_empty() {
+        match last_error {
+            Some(e) => anyhow::bail!("TXT记录中没有可用的目标 {}: {}", hostname, e),
+            None => anyhow::bail!("TXT记录中没有可用的目标: {}", hostname),
+        }
+    }
+    Ok(endpoints)
+}
+
+fn parse_record(
+    hostname: &str,
+    record: &str,
+    key: Option<&TxtKey>,
+    now: u64,
+) -> Result<Vec<TxtEndpoint>> {
+    if record.split(';').next().map(str::trim) != Some(VERSION) {
+        if key.is_some() {
+            anyhow::bail!("已配置 txt_key，不接受未签名的旧格式记录");
+        }
+        let addr = record
+            .parse::<SocketAddr>()
+            .map_err(|_| anyhow::anyhow!("不是有效的IP:PORT格式"))?;
+        return Ok(vec![TxtEndpoint {
+            addr,
+            weight: None,
+            protocol: None,
+            versioned: false,
+        }]);
+    }
+
+    let (body, signature) = match record.rsplit_once(';') {
+        Some((body, last)) => match last.trim().strip_prefix("sig=") {
+            Some(signature) => (body.trim(), Some(signature.trim())),
+            None => (record, None),
+        },
+        None => (record, None),
+    };
+    if let Some(key) = key {
+        let Some(signature) = signature else {
+            anyhow::bail!("缺少签名");
+        };
+        if !key.verify(&format!("{};{}", hostname, body), signature) {
+            anyhow::bail!("签名校验失败");
+        }
+    }
+
+    let mut endpoints = Vec::new();
+    for field in body.split(';').map(str::trim).skip(1) {
+        if field.is_empty() {
+            continue;
+        }
+        let Some((name, value)) = field.split_once('=') else {
+            anyhow::bail!("无效的字段: {}", field);
+        };
+        match name.trim() {
+            protocol @ ("tcp" | "udp" | "addr") => {
+                endpoints.push(parse_endpoint(protocol, value.trim())?)
+            }
+            "exp" => {
+                let expires: u64 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("无效的过期时间: {}", value))?;
+                if expires <= now {
+                    anyhow::bail!("记录已过期");
+                }
+            }
+            "sig" => anyhow::bail!("sig 必须是最后一个字段"),
+            // 未知字段留给后续扩展，忽略
+            _ => {}
+        }
+    }
+    if endpoints.is_empty() {
+        anyhow::bail!("记录中没有端点");
+    }
+    Ok(endpoints)
+}
+
+// 端点格式：IP:PORT[,w=权重]，未知属性忽略
+fn parse_endpoint(protocol: &str, value: &str) -> Result<TxtEndpoint> {
+    let mut parts = value.split(',').map(str::trim);
+    let addr = parts.next().unwrap_or_default();
+    let addr = addr
+        .parse::<SocketAddr>()
+        .map_err(|_| anyhow::anyhow!("无效的端点地址: {}", addr))?;
+    let mut weight = 1;
+    for attribute in parts {
+        if let Some(value) = attribute.strip_prefix("w=") {
+            weight = value
+                .parse()
+                .ok()
+                .filter(|w| *w > 0)
+                .ok_or_else(|| anyhow::anyhow!("无效的权重: {}", value))?;
+        }
+    }
+    Ok(TxtEndpoint {
+        addr,
+        weight: Some(weight),
+        protocol: (protocol != "addr").then(|| protocol.to_string()),
+        versioned: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hmac_sign;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_parse_signed_records() {
+        let body = "v=sf1; tcp=10.0.0.1:443,w=3; udp=10.0.0.1:3389; addr=10.0.0.2:443; exp=2000";
+        let hmac_key = TxtKey::parse("hmac:secret").unwrap();
+        let signature = hmac_sign("secret", &format!("drive.example.com;{}", body));
+        let signed = format!("{}; sig={}", body, signature);
+
+        let endpoints = parse_records(
+            "Drive.Example.com.",
+            &[signed.clone(), "10.0.0.9:443".to_string()],
+            Some(&hmac_key),
+            1000,
+        )
+        .unwrap();
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(endpoints[0].weight, Some(3));
+        assert_eq!(endpoints[0].protocol.as_deref(), Some("tcp"));
+        assert_eq!(endpoints[1].addr, "10.0.0.1:3389".parse().unwrap());
+        assert_eq!(endpoints[2].protocol, None);
+
+        // 篡改、换用其他域名、过期的记录均被拒绝
+        let tampered = signed.replace("10.0.0.2", "6.6.6.6");
+        for (hostname, record, now) in [
+            ("drive.example.com", tampered.as_str(), 1000),
+            ("other.example.com", signed.as_str(), 1000),
+            ("drive.example.com", signed.as_str(), 3000),
+            ("drive.example.com", body, 1000),
+        ] {
+            assert!(parse_records(hostname, &[record.to_string()], Some(&hmac_key), now).is_err());
+        }
+
+        // 未配置密钥时兼容旧格式
+        let endpoints =
+            parse_records("drive.example.com", &["10.0.0.9:443".to_string()], None, 0).unwrap();
+        assert!(!endpoints[0].versioned && endpoints[0].weight.is_none());
+
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let ed_key = TxtKey::parse(&format!(
+            "ed25519:{}",
+            hex::encode(pair.public_key().as_ref())
+        ))
+        .unwrap();
+        let signature = pair.sign(format!("drive.example.com;{}", body).as_bytes());
+        let signed = format!("{}; sig={}", body, hex::encode(signature.as_ref()));
+        assert_eq!(
+            parse_records("drive.example.com", &[signed], Some(&ed_key), 1000)
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(parse_records("drive.example.com", &[tampered], Some(&ed_key), 1000).is_err());
+        assert!(TxtKey::parse("ed25519:abcd").is_err());
+    }
+}
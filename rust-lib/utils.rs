@@ -1,16 +1,28 @@
+use crate::config::DynamicUpdateConfig;
+use crate::resolver;
 use anyhow::Result;
-use hickory_resolver::{
-    config::{ResolverConfig, ResolverOpts},
-    Resolver,
-};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hickory_resolver::config::{NameServerConfig, Protocol};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
 
 pub struct ConnectionStats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub connections: u32,
+    pub connect_attempts: u64, // 连接目标的尝试次数（含重试）
+    pub connect_failures: u64,
+    pub connect_retries: u64,
+    pub idle_timeouts: u64,        // 因空闲超时关闭的连接和UDP会话
+    pub lifetime_expirations: u64, // 因达到最长存活时间关闭的连接和UDP会话
+    pub no_healthy_target: u64,    // 没有健康目标时按 on_no_healthy_target 处理的连接
     pub start_time: Instant,
 }
 
@@ -20,6 +32,12 @@ impl Default for ConnectionStats {
             bytes_sent: 0,
             bytes_received: 0,
             connections: 0,
+            connect_attempts: 0,
+            connect_failures: 0,
+            connect_retries: 0,
+            idle_timeouts: 0,
+            lifetime_expirations: 0,
+            no_healthy_target: 0,
             start_time: Instant::now(),
         }
     }
@@ -38,32 +56,361 @@ impl ConnectionStats {
         self.connections += 1;
     }
 
+    pub fn record_connect_attempt(&mut self, success: bool, retry: bool) {
+        self.connect_attempts += 1;
+        if !success {
+            self.connect_failures += 1;
+        }
+        if retry {
+            self.connect_retries += 1;
+        }
+    }
+
+    pub fn record_expiry(&mut self, expiry: Expiry) {
+        match expiry {
+            Expiry::Idle => self.idle_timeouts += 1,
+            Expiry::Lifetime => self.lifetime_expirations += 1,
+        }
+    }
+
     pub fn get_uptime(&self) -> Duration {
         self.start_time.elapsed()
     }
 }
 
-pub async fn resolve_target(target: &str) -> Result<SocketAddr> {
+/// 连接或会话被超时关闭的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Idle,
+    Lifetime,
+}
+
+/// 空闲超时和最长存活时间，None 表示不限制
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTimeouts {
+    pub idle: Option<Duration>,     // TCP两个方向都没有数据的时间
+    pub udp_idle: Duration,         // UDP会话没有数据的时间
+    pub lifetime: Option<Duration>, // 从建立起算
+}
+
+impl ConnectionTimeouts {
+    pub fn from_config(config: &DynamicUpdateConfig) -> Self {
+        let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        Self {
+            idle: seconds(config.get_connection_timeout()),
+            udp_idle: Duration::from_secs(config.get_udp_timeout()),
+            lifetime: seconds(config.get_max_lifetime()),
+        }
+    }
+
+    /// TCP连接已空闲 idle_for、已存活 age 时的超时原因，未超时返回 None
+    pub fn tcp_expiry(&self, idle_for: Duration, age: Duration) -> Option<Expiry> {
+        self.expiry(self.idle, idle_for, age)
+    }
+
+    /// UDP会话的超时原因，未超时返回 None
+    pub fn udp_expiry(&self, idle_for: Duration, age: Duration) -> Option<Expiry> {
+        self.expiry(Some(self.udp_idle), idle_for, age)
+    }
+
+    fn expiry(&self, idle: Option<Duration>, idle_for: Duration, age: Duration) -> Option<Expiry> {
+        if self.lifetime.is_some_and(|lifetime| age >= lifetime) {
+            Some(Expiry::Lifetime)
+        } else if idle.is_some_and(|idle| idle_for >= idle) {
+            Some(Expiry::Idle)
+        } else {
+            None
+        }
+    }
+}
+
+/// Unix套接字地址前缀，例如 `unix:/var/run/docker.sock`
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// 解析后的目标地址：网络地址或Unix套接字路径
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Inet(addr) => write!(f, "{}", addr),
+            TargetAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// 提取 `unix:/path` 格式中的路径
+pub fn parse_unix_path(addr: &str) -> Option<PathBuf> {
+    addr.strip_prefix(UNIX_PREFIX)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+pub fn is_unix_addr(addr: &str) -> bool {
+    addr.starts_with(UNIX_PREFIX)
+}
+
+// 统一的双向流类型，TCP与Unix套接字共用同一套转发逻辑
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// 取得具体类型，用于对底层TCP连接设置套接字选项
+    fn as_any(&self) -> &dyn std::any::Any;
+}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncStream for T {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// 以RST立即关闭TCP连接（SO_LINGER=0）；Unix套接字和嗅探后包装过的流正常关闭
+pub fn reset_stream(stream: BoxedStream) {
+    // 经 &dyn 调用，避免匹配到 Box 自身的实现
+    let inner: &dyn AsyncStream = &*stream;
+    if let Some(tcp) = inner.as_any().downcast_ref::<TcpStream>() {
+        let _ = socket2::SockRef::from(tcp).set_linger(Some(Duration::ZERO));
+    }
+}
+
+// RFC 8305 建议的连接尝试间隔
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// 连接目标地址（TCP或Unix套接字）
+pub async fn connect_target(addr: &TargetAddr) -> Result<BoxedStream> {
+    match addr {
+        TargetAddr::Inet(addr) => tcp_stream(TcpStream::connect(addr).await?),
+        TargetAddr::Unix(path) => connect_unix(path).await,
+    }
+}
+
+/// 按顺序连接地址列表：网络地址使用Happy Eyeballs并发竞速，Unix套接字直接连接
+pub async fn connect_addrs(addrs: &[TargetAddr]) -> Result<BoxedStream> {
+    if let Some(TargetAddr::Unix(path)) = addrs.first() {
+        return connect_unix(path).await;
+    }
+
+    let inet_addrs: Vec<SocketAddr> = addrs
+        .iter()
+        .filter_map(|addr| match addr {
+            TargetAddr::Inet(addr) => Some(*addr),
+            TargetAddr::Unix(_) => None,
+        })
+        .collect();
+    tcp_stream(connect_happy_eyeballs(&inet_addrs).await?)
+}
+
+fn tcp_stream(stream: TcpStream) -> Result<BoxedStream> {
+    // 目标侧同样禁用Nagle算法
+    let _ = stream.set_nodelay(true);
+    Ok(Box::new(stream))
+}
+
+/// RFC 8305 Happy Eyeballs：按顺序每隔250ms发起一次新连接，
+/// 前一个尝试失败时立即发起下一个，最先成功的连接胜出
+pub async fn connect_happy_eyeballs(addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+    let mut pending = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    if let Some(addr) = pending.next() {
+        attempts.push(connect_attempt(addr));
+    }
+
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    last_error = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(connect_attempt(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(connect_attempt(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "没有可连接的地址")
+    }))
+}
+
+async fn connect_attempt(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> Result<BoxedStream> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &Path) -> Result<BoxedStream> {
+    anyhow::bail!("当前平台不支持Unix套接字: {}", path.display())
+}
+
+/// 解析目标为有序地址列表（按Happy Eyeballs顺序排列）
+pub async fn resolve_target(target: &str) -> Result<Vec<TargetAddr>> {
+    // 0. Unix套接字路径无需解析
+    if let Some(path) = parse_unix_path(target) {
+        return Ok(vec![TargetAddr::Unix(path)]);
+    }
+    // SRV/TXT目标按顺序展开为所有目标的地址
+    if is_expanded_addr(target) {
+        let targets = resolve_expanded(target).await?;
+        return Ok(targets.into_iter().flat_map(|t| t.addrs).collect());
+    }
+
+    let addrs = resolve_inet_target(target).await?;
+    Ok(interleave_address_families(addrs)
+        .into_iter()
+        .map(TargetAddr::Inet)
+        .collect())
+}
+
+/// SRV或TXT记录展开出的单个目标
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandedTarget {
+    pub address: String, // 主机名:端口或IP:端口，作为目标缓存的键
+    pub priority: u16,
+    pub weight: Option<u16>,      // 为空时沿用目标配置的权重
+    pub protocol: Option<String>, // 只用于 tcp 或 udp，为空时不限
+    pub addrs: Vec<TargetAddr>,
+}
+
+/// 判断是否为 srv:_service._proto.name 格式的目标
+pub fn is_srv_addr(target: &str) -> bool {
+    target.starts_with("srv:")
+}
+
+/// 判断是否为通过TXT记录发布地址的纯域名目标
+pub fn is_txt_addr(target: &str) -> bool {
+    !target.contains(':') && !is_unix_addr(target)
+}
+
+/// SRV和TXT目标按解析结果展开为多个目标
+pub fn is_expanded_addr(target: &str) -> bool {
+    is_srv_addr(target) || is_txt_addr(target)
+}
+
+pub async fn resolve_expanded(target: &str) -> Result<Vec<ExpandedTarget>> {
+    if is_srv_addr(target) {
+        resolve_srv(target).await
+    } else {
+        resolve_txt(target).await
+    }
+}
+
+/// 解析TXT目标：v=sf1 记录的每个端点展开为一个目标；
+/// 旧格式记录的地址合并为以域名命名的一个目标，行为与展开前一致
+pub async fn resolve_txt(target: &str) -> Result<Vec<ExpandedTarget>> {
+    let endpoints = resolver::resolver_for(target)
+        .await
+        .lookup_txt(target)
+        .await?;
+
+    let (versioned, legacy): (Vec<_>, Vec<_>) = endpoints.into_iter().partition(|e| e.versioned);
+    let mut targets: Vec<_> = versioned
+        .into_iter()
+        .map(|endpoint| ExpandedTarget {
+            address: endpoint.addr.to_string(),
+            priority: 0,
+            weight: endpoint.weight,
+            protocol: endpoint.protocol,
+            addrs: vec![TargetAddr::Inet(endpoint.addr)],
+        })
+        .collect();
+    if !legacy.is_empty() {
+        targets.push(ExpandedTarget {
+            address: target.to_string(),
+            priority: 0,
+            weight: None,
+            protocol: None,
+            addrs: interleave_address_families(legacy.into_iter().map(|e| e.addr).collect())
+                .into_iter()
+                .map(TargetAddr::Inet)
+                .collect(),
+        });
+    }
+    Ok(targets)
+}
+
+/// 解析SRV目标，返回按优先级升序、权重降序排列的各目标及其地址；
+/// 主机名无法解析的记录跳过，全部无法解析时报错
+pub async fn resolve_srv(target: &str) -> Result<Vec<ExpandedTarget>> {
+    let name = target.trim_start_matches("srv:");
+    let resolver = resolver::resolver_for(target).await;
+    let records = resolver.lookup_srv(name).await?;
+    let results = futures::future::join_all(
+        records
+            .iter()
+            .map(|record| resolver.lookup_ip(&record.target, record.port)),
+    )
+    .await;
+
+    let mut targets = Vec::new();
+    for (record, result) in records.into_iter().zip(results) {
+        let address = format!("{}:{}", record.target, record.port);
+        match result {
+            Ok(addrs) => targets.push(ExpandedTarget {
+                address,
+                priority: record.priority,
+                weight: Some(record.weight),
+                protocol: None,
+                addrs: interleave_address_families(addrs)
+                    .into_iter()
+                    .map(TargetAddr::Inet)
+                    .collect(),
+            }),
+            Err(e) => log::debug!("SRV目标 {} 解析失败: {}", address, e),
+        }
+    }
+    if targets.is_empty() {
+        anyhow::bail!("SRV记录 {} 没有可解析的目标", name);
+    }
+    targets.sort_by_key(|t| (t.priority, std::cmp::Reverse(t.weight)));
+    Ok(targets)
+}
+
+/// 按顺序解析目标列表，返回第一个可解析目标的地址
+pub async fn resolve_first_target(targets: &[String]) -> Result<Vec<TargetAddr>> {
+    for target in targets {
+        match resolve_target(target).await {
+            Ok(addrs) if !addrs.is_empty() => return Ok(addrs),
+            Ok(_) => {}
+            Err(e) => log::debug!("目标 {} 解析失败: {}", target, e),
+        }
+    }
+    anyhow::bail!("目标均无法解析: {}", targets.join(","))
+}
+
+async fn resolve_inet_target(target: &str) -> Result<Vec<SocketAddr>> {
     // 1. 尝试直接解析为SocketAddr (IP:PORT格式)
     if let Ok(addr) = target.parse::<SocketAddr>() {
-        return Ok(addr);
+        return Ok(vec![addr]);
     }
 
-    // 2. 解析 hostname:port 格式
+    // 2. 解析 hostname:port 格式（纯域名的TXT目标已在 resolve_target 中展开）
     let parts: Vec<&str> = target.split(':').collect();
     match parts.len() {
-        1 => {
-            // 纯域名 - 解析TXT记录获取IP:PORT
-            let hostname = parts[0];
-            resolve_txt_record_with_aliyun_dns(hostname).await
-        }
         2 => {
             // 域名:port 格式 - 解析A/AAAA记录，然后拼接端口
             let hostname = parts[0];
             let port: u16 = parts[1]
                 .parse()
                 .map_err(|e| anyhow::anyhow!("无效的端口号 {}: {}", parts[1], e))?;
-            resolve_domain_with_aliyun_dns(hostname, port).await
+            resolver::resolver_for(target)
+                .await
+                .lookup_ip(hostname, port)
+                .await
         }
         _ => {
             anyhow::bail!("无效的目标格式: {}", target);
@@ -71,124 +418,131 @@ pub async fn resolve_target(target: &str) -> Result<SocketAddr> {
     }
 }
 
-// 解析域名:PORT格式 - 解析A/AAAA记录，然后拼接端口
-async fn resolve_domain_with_aliyun_dns(hostname: &str, port: u16) -> Result<SocketAddr> {
-    // 使用tokio的spawn_blocking来运行同步DNS解析
-    let hostname = hostname.to_string();
-    let result = tokio::task::spawn_blocking(move || {
-        // 创建阿里云DNS解析器
-        let mut config = ResolverConfig::new();
-
-        // 添加阿里云DNS服务器
-        let aliyun_dns1: SocketAddr = "223.5.5.5:53".parse()?;
-        let aliyun_dns2: SocketAddr = "223.6.6.6:53".parse()?;
-
-        config.add_name_server(hickory_resolver::config::NameServerConfig::new(
-            aliyun_dns1,
-            hickory_resolver::config::Protocol::Udp,
-        ));
-        config.add_name_server(hickory_resolver::config::NameServerConfig::new(
-            aliyun_dns2,
-            hickory_resolver::config::Protocol::Udp,
-        ));
-
-        let mut opts = ResolverOpts::default();
-        opts.timeout = Duration::from_secs(5);
-        opts.attempts = 2;
-
-        let resolver = Resolver::new(config, opts)?;
-
-        // 使用阿里云DNS解析A和AAAA记录
-        match resolver.lookup_ip(&hostname) {
-            Ok(response) => {
-                // 优先选择IPv4地址
-                if let Some(addr) = response.iter().find(|addr| addr.is_ipv4()) {
-                    let socket_addr = SocketAddr::new(addr, port);
-                    Ok(socket_addr)
-                } else if let Some(addr) = response.iter().next() {
-                    let socket_addr = SocketAddr::new(addr, port);
-                    Ok(socket_addr)
-                } else {
-                    anyhow::bail!("没有找到可用的IP地址: {}", hostname)
-                }
-            }
-            Err(e) => {
-                anyhow::bail!("DNS解析失败 {}: {}", hostname, e)
-            }
+/// RFC 8305 地址排序：IPv6优先，之后IPv4/IPv6交替排列，同族内保持解析顺序
+pub fn interleave_address_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::new();
+
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
         }
-    })
-    .await?;
-
-    let socket_addr = result?;
-    Ok(socket_addr)
-}
-
-// 解析纯域名TXT记录 - 从TXT记录中获取IP:PORT
-async fn resolve_txt_record_with_aliyun_dns(hostname: &str) -> Result<SocketAddr> {
-    // 使用tokio的spawn_blocking来运行同步DNS解析
-    let hostname = hostname.to_string();
-    let result = tokio::task::spawn_blocking(move || {
-        // 创建阿里云DNS解析器
-        let mut config = ResolverConfig::new();
-
-        // 添加阿里云DNS服务器
-        let aliyun_dns1: SocketAddr = "223.5.5.5:53".parse()?;
-        let aliyun_dns2: SocketAddr = "223.6.6.6:53".parse()?;
-
-        config.add_name_server(hickory_resolver::config::NameServerConfig::new(
-            aliyun_dns1,
-            hickory_resolver::config::Protocol::Udp,
-        ));
-        config.add_name_server(hickory_resolver::config::NameServerConfig::new(
-            aliyun_dns2,
-            hickory_resolver::config::Protocol::Udp,
-        ));
-
-        let mut opts = ResolverOpts::default();
-        opts.timeout = Duration::from_secs(5);
-        opts.attempts = 2;
-
-        let resolver = Resolver::new(config, opts)?;
-
-        // 查询TXT记录
-        match resolver.txt_lookup(&hostname) {
-            Ok(txt_response) => {
-                for txt in txt_response.iter() {
-                    for txt_data in txt.iter() {
-                        let txt_string = String::from_utf8_lossy(txt_data);
-
-                        // 清理TXT记录内容（移除引号、空格等）
-                        let clean_txt = txt_string.trim_matches('"').trim();
-
-                        // 尝试解析TXT记录中的IP:PORT格式
-                        if let Ok(addr) = clean_txt.parse::<SocketAddr>() {
-                            return Ok(addr);
-                        }
-                    }
-                }
-                anyhow::bail!("TXT记录中没有找到有效的IP:PORT格式: {}", hostname)
-            }
-            Err(e) => {
-                anyhow::bail!("TXT记录查询失败 {}: {}", hostname, e)
+    }
+
+    ordered
+}
+
+/// DNS上游地址的组成部分：协议、主机、端口和TLS证书校验使用的域名
+pub struct UpstreamSpec {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+    pub tls_name: Option<String>,
+}
+
+/// 解析DNS上游写法（不做域名解析）
+///
+/// 支持格式：`1.1.1.1`、`udp://1.1.1.1:53`、`tcp://1.1.1.1`、
+/// `tls://dns.alidns.com`、`tls://1.1.1.1:853#cloudflare-dns.com`、
+/// `https://dns.alidns.com/dns-query`。`#` 后为TLS证书校验使用的域名。
+pub fn parse_upstream_spec(spec: &str) -> Result<UpstreamSpec> {
+    let (protocol, rest) = match spec.split_once("://") {
+        Some(("udp", rest)) => (Protocol::Udp, rest),
+        Some(("tcp", rest)) => (Protocol::Tcp, rest),
+        Some(("tls", rest)) => (Protocol::Tls, rest),
+        Some(("https", rest)) => (Protocol::Https, rest),
+        Some((scheme, _)) => anyhow::bail!("不支持的DNS上游协议 {}: {}", scheme, spec),
+        None => (Protocol::Udp, spec),
+    };
+    let default_port = match protocol {
+        Protocol::Tls => 853,
+        Protocol::Https => 443,
+        _ => 53,
+    };
+
+    let (rest, tls_name) = match rest.split_once('#') {
+        Some((rest, name)) => (rest, Some(name.to_string())),
+        None => (rest, None),
+    };
+    // DoH 只支持标准路径 /dns-query
+    let host_port = match rest.split_once('/') {
+        Some((host_port, path)) if protocol == Protocol::Https => {
+            if !path.is_empty() && path != "dns-query" {
+                anyhow::bail!("DoH仅支持 /dns-query 路径: {}", spec);
             }
+            host_port
         }
-    })
-    .await?;
+        Some(_) => anyhow::bail!("无效的DNS上游地址: {}", spec),
+        None => rest,
+    };
+
+    let (host, port, is_ip) = if let Ok(addr) = host_port.parse::<SocketAddr>() {
+        (addr.ip().to_string(), addr.port(), true)
+    } else if let Ok(ip) = host_port.parse::<IpAddr>() {
+        (ip.to_string(), default_port, true)
+    } else {
+        match host_port.rsplit_once(':') {
+            Some((host, port)) => (
+                host.to_string(),
+                port.parse::<u16>()
+                    .map_err(|e| anyhow::anyhow!("无效的端口号 {}: {}", port, e))?,
+                false,
+            ),
+            None => (host_port.to_string(), default_port, false),
+        }
+    };
+    if host.is_empty() {
+        anyhow::bail!("无效的DNS上游地址: {}", spec);
+    }
+
+    let tls_name = tls_name.or_else(|| (!is_ip).then(|| host.clone()));
+    if matches!(protocol, Protocol::Tls | Protocol::Https) && tls_name.is_none() {
+        anyhow::bail!("TLS/HTTPS上游需要指定证书域名，例如 tls://1.1.1.1#cloudflare-dns.com");
+    }
 
-    let socket_addr = result?;
-    Ok(socket_addr)
+    Ok(UpstreamSpec {
+        protocol,
+        host,
+        port,
+        tls_name,
+    })
 }
 
-pub async fn test_connection(target: &str) -> Result<Duration> {
-    let addr = resolve_target(target).await?;
-    let start = Instant::now();
+/// 解析DNS上游地址，返回可直接用于hickory的名称服务器列表
+///
+/// 上游写成域名时通过系统DNS解析出IP（每个IP一个名称服务器），
+/// 避免解析器依赖自身完成引导。
+pub async fn parse_dns_upstream(spec: &str) -> Result<Vec<NameServerConfig>> {
+    let upstream = parse_upstream_spec(spec)?;
+    let addrs: Vec<SocketAddr> = match upstream.host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, upstream.port)],
+        Err(_) => tokio::net::lookup_host((upstream.host.as_str(), upstream.port))
+            .await
+            .map_err(|e| anyhow::anyhow!("DNS上游 {} 解析失败: {}", upstream.host, e))?
+            .collect(),
+    };
+
+    Ok(addrs
+        .into_iter()
+        .map(|addr| {
+            let mut name_server = NameServerConfig::new(addr, upstream.protocol);
+            name_server.tls_dns_name = upstream.tls_name.clone();
+            name_server.trust_negative_responses = true;
+            name_server
+        })
+        .collect())
+}
 
-    // 统一使用5秒超时时间
-    match tokio::time::timeout(Duration::from_secs(5), tokio::net::TcpStream::connect(addr)).await {
-        Ok(Ok(_)) => Ok(start.elapsed()),
-        Ok(Err(e)) => Err(anyhow::anyhow!("连接失败 {}: {}", target, e)),
-        Err(_) => Err(anyhow::anyhow!("连接超时: {}", target)),
-    }
+/// 地址列表的展示格式
+pub fn format_addrs(addrs: &[TargetAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 // UDP连接测试函数
@@ -205,6 +559,11 @@ pub fn get_standard_stats(stats: &ConnectionStats) -> HashMap<String, String> {
         stats.bytes_received.to_string(),
     );
     result.insert("uptime".to_string(), format!("{:?}", stats.get_uptime()));
+    result.insert("idle_timeouts".to_string(), stats.idle_timeouts.to_string());
+    result.insert(
+        "lifetime_expirations".to_string(),
+        stats.lifetime_expirations.to_string(),
+    );
 
     // 增强的性能指标
     let uptime_secs = stats.get_uptime().as_secs() as f64;
@@ -233,3 +592,101 @@ pub fn get_stats_with_target(
     result.insert("target_addr".to_string(), target_addr.to_string());
     result
 }
+
+/// HMAC-SHA256 签名，返回十六进制字符串
+pub fn hmac_sign(key: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC接受任意长度的密钥");
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// 校验十六进制HMAC-SHA256签名（常量时间比较）
+pub fn hmac_verify(key: &str, message: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC接受任意长度的密钥");
+    mac.update(message.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_interleave_address_families() {
+        let addrs: Vec<SocketAddr> = vec![
+            "1.1.1.1:80".parse().unwrap(),
+            "2.2.2.2:80".parse().unwrap(),
+            "3.3.3.3:80".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+        ];
+        let ordered: Vec<String> = interleave_address_families(addrs)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            ordered,
+            vec!["[2001:db8::1]:80", "1.1.1.1:80", "2.2.2.2:80", "3.3.3.3:80"]
+        );
+    }
+
+    #[test]
+    fn test_connection_timeouts() {
+        let timeouts = ConnectionTimeouts::from_config(&DynamicUpdateConfig {
+            connection_timeout: Some(0),
+            max_lifetime: Some(600),
+            ..Default::default()
+        });
+        let secs = Duration::from_secs;
+
+        // connection_timeout 为0时TCP不做空闲超时，UDP使用默认60秒
+        assert_eq!(timeouts.tcp_expiry(secs(3600), secs(10)), None);
+        assert_eq!(timeouts.udp_expiry(secs(59), secs(59)), None);
+        assert_eq!(timeouts.udp_expiry(secs(60), secs(60)), Some(Expiry::Idle));
+        // 最长存活时间优先于空闲超时
+        assert_eq!(
+            timeouts.tcp_expiry(secs(0), secs(600)),
+            Some(Expiry::Lifetime)
+        );
+        assert_eq!(
+            timeouts.udp_expiry(secs(600), secs(600)),
+            Some(Expiry::Lifetime)
+        );
+    }
+
+    #[test]
+    fn test_parse_upstream_spec() {
+        let upstream = parse_upstream_spec("tls://1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(upstream.protocol, Protocol::Tls);
+        assert_eq!((upstream.host.as_str(), upstream.port), ("1.1.1.1", 853));
+        assert_eq!(upstream.tls_name.as_deref(), Some("cloudflare-dns.com"));
+
+        // 域名上游默认用主机名校验证书
+        let upstream = parse_upstream_spec("https://dns.alidns.com/dns-query").unwrap();
+        assert_eq!(upstream.port, 443);
+        assert_eq!(upstream.tls_name.as_deref(), Some("dns.alidns.com"));
+        assert_eq!(parse_upstream_spec("tcp://[::1]:5353").unwrap().port, 5353);
+
+        assert!(parse_upstream_spec("tls://1.1.1.1").is_err());
+        assert!(parse_upstream_spec("https://dns.google/resolve").is_err());
+        assert!(parse_upstream_spec("quic://dns.adguard.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_skips_dead_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        // 先占用再释放一个端口，得到一个必然拒绝连接的地址
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let stream = connect_happy_eyeballs(&[dead, live]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+    }
+}